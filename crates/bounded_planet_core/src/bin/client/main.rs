@@ -1,4 +1,4 @@
-use std::{fs, net::ToSocketAddrs, path::PathBuf, sync::Arc};
+use std::{net::ToSocketAddrs, path::PathBuf, sync::Arc};
 use structopt::StructOpt;
 use url::Url;
 use tracing::{Level, info};
@@ -8,11 +8,17 @@ use bevy::{
         mouse::{MouseButtonInput, MouseScrollUnit, MouseWheel},
     },
     prelude::*,
-    render::mesh::{Mesh, VertexAttribute}
 };
 use bounded_planet::{
     camera::*,
-    networking::{events::*, packets::*, systems::*}
+    land::streaming::TerrainStreaming,
+    networking::{
+        client::connection::{ClientTransport, ServerTrust},
+        crypto::{ClientIdentity, KnownHosts, load_certificates},
+        events::*,
+        packets::*,
+        systems::*
+    }
 };
 
 
@@ -37,13 +43,34 @@ struct Opt {
     #[structopt(long="url", default_value="quic://localhost:4433")]
     url: Url,
 
-    /// TLS certificate in PEM format
-    #[structopt(parse(from_os_str), short="c", long="cert", default_value="./certs/cert.pem")]
-    cert: PathBuf,
+    /// Certificates (in PEM or DER format) trusted to sign the server's certificate
+    #[structopt(parse(from_os_str), short="c", long="cert")]
+    cert: Vec<PathBuf>,
+
+    /// File of server certificate fingerprints. Certificates not signed by a trusted certificate are pinned here the
+    /// first time they are seen, and must match on later connections.
+    #[structopt(parse(from_os_str), long="known_hosts", default_value="./known_hosts.txt")]
+    known_hosts: PathBuf,
+
+    /// Certificate (in PEM or DER format) to present to servers which only admit known clients
+    #[structopt(parse(from_os_str), long="client_cert")]
+    client_cert: Option<PathBuf>,
+
+    /// Private key (in PEM or DER format) of the client certificate
+    #[structopt(parse(from_os_str), long="client_key")]
+    client_key: Option<PathBuf>,
 
     /// Accept any TLS certificate from the server even if it is invalid
     #[structopt(short="a", long="accept_any")]
-    accept_any_cert: bool
+    accept_any_cert: bool,
+
+    /// Username to authenticate with
+    #[structopt(short="u", long="username", required=true)]
+    username: String,
+
+    /// Password to authenticate with
+    #[structopt(short="p", long="password", required=true)]
+    password: String,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -73,16 +100,29 @@ async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
     // Create a Bevy app
     let mut app = App::build();
 
-    let cert = get_cert(&options.cert)?;
+    let trust = get_trust(&options)?;
+    let identity = get_identity(&options)?;
     app.add_plugin(bounded_planet::networking::client::plugin::Network {
-        addr: remote,
-        url,
-        cert,
-        accept_any_cert: options.accept_any_cert
+        transport: ClientTransport::Quic {
+            addr: remote,
+            url,
+            trust,
+            identity,
+        },
+        queue_config: Default::default(),
+        codec: Default::default(),
+        health_config: Default::default(),
+        reconnect: Default::default(),
     });
+    app.add_plugin(bounded_planet::networking::client::replication::Replication);
+    app.add_plugin(bounded_planet::networking::client::interpolation::Interpolation::default());
 
-    app.init_resource::<PingResponderState>();
-    app.add_system(respond_to_pings.system());
+    app.add_resource(AuthOnSessionState {
+        event_reader: Default::default(),
+        username: options.username,
+        password: options.password,
+    });
+    app.add_system(auth_on_session.system());
 
     app.init_resource::<NetEventLoggerState>();
     app.add_system(log_net_events.system());
@@ -98,11 +138,7 @@ async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
     app.add_system_to_stage(CAM_CACHE_UPDATE, use_or_update_action_cache.system());
     app.add_system(play_every_sound_on_mb1.system());
 
-    app.init_resource::<TileReceivedState>();
-    app.add_system(handle_tile_received.system());
-
-    app.init_resource::<RequestTileOnConnectedState>();
-    app.add_system(request_tile_on_connected.system());
+    app.add_plugin(TerrainStreaming::default());
 
     // Run it forever
     app.run();
@@ -110,103 +146,66 @@ async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// Fetch certificates to use
-fn get_cert(cert_path: &PathBuf) -> Result<quinn::Certificate, Box<dyn std::error::Error>> {
-    info!("Loading Cert: {:?}", cert_path);
-    Ok(quinn::Certificate::from_der(&fs::read(cert_path)?)?)
-}
-
-#[derive(Default)]
-pub struct PingResponderState {
-    pub event_reader: EventReader<ReceiveEvent>,
-}
-
-fn respond_to_pings(
-    mut state: ResMut<PingResponderState>,
-    receiver: ResMut<Events<ReceiveEvent>>,
-    mut sender: ResMut<Events<SendEvent>>,
-) {
-    for evt in state.event_reader.iter(&receiver) {
-        if let ReceiveEvent::ReceivedPacket { ref connection, data } = evt {
-            if let Packet::Ping(Ping { timestamp }) = **data {
-                sender.send(SendEvent::SendPacket {
-                    connection: *connection,
-                    stream: StreamType::PingPong,
-                    data: Arc::new(Packet::Pong(Pong { timestamp }))
-                });
-                info!("Received Ping, sending pong. {:?}", connection);
-            }
-        }
+/// Fetch certificates to trust
+fn get_trust(options: &Opt) -> Result<ServerTrust, Box<dyn std::error::Error>> {
+    let mut anchors = Vec::new();
+    for cert_path in &options.cert {
+        info!("Loading Cert: {:?}", cert_path);
+        anchors.extend(load_certificates(cert_path)?);
     }
-}
 
-#[derive(Default)]
-pub struct TileReceivedState {
-    pub event_reader: EventReader<ReceiveEvent>,
+    info!("Loading Known Hosts: {:?}", options.known_hosts);
+    let known_hosts = KnownHosts::load(&options.known_hosts)?;
+
+    Ok(ServerTrust {
+        anchors,
+        known_hosts: Some(Arc::new(known_hosts)),
+        accept_any: options.accept_any_cert,
+    })
 }
 
-/// When a tile is received from the server, we load it into the scene
-fn handle_tile_received(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut state: ResMut<TileReceivedState>,
-    receiver: ResMut<Events<ReceiveEvent>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut textures: ResMut<Assets<Texture>>,
-    mut materials: ResMut<Assets<StandardMaterial>>
-) {
-    for evt in state.event_reader.iter(&receiver) {
-        if let ReceiveEvent::ReceivedPacket { connection: ref _connection, data } = evt {
-            if let Packet::WorldTileData(WorldTileData { mesh_data }) = (**data).clone() {
-                info!("Loading tile received from server.");
-                let land_texture_top_handle = asset_server
-                    .load_sync(&mut textures, "content/textures/CoveWorldTop.png")
-                    .expect("Failed to load CoveWorldTop.png");
-                commands.spawn(PbrComponents {
-                    mesh: meshes.add(Mesh {
-                        primitive_topology: bevy::render::pipeline::PrimitiveTopology::TriangleList,
-                        attributes: vec![
-                            VertexAttribute::position(mesh_data.vertices),
-                            VertexAttribute::normal(mesh_data.normals),
-                            VertexAttribute::uv(mesh_data.uvs),
-                        ],
-                        indices: Some(mesh_data.indices),
-                    }),
-                    material: materials.add(StandardMaterial {
-                        albedo_texture: Some(land_texture_top_handle),
-                        shaded: true,
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                });
-                info!("Finished loading tile.");
-            }
+/// Fetch the certificate to present to the server, if one was given
+fn get_identity(options: &Opt) -> Result<Option<ClientIdentity>, Box<dyn std::error::Error>> {
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("Loading Client Cert: {:?}", cert_path);
+            Ok(Some(ClientIdentity::load(cert_path, key_path)?))
         }
+        (None, None) => Ok(None),
+        _ => Err("Both `--client_cert` and `--client_key` are required to present a client certificate".into()),
     }
 }
 
-#[derive(Default)]
-struct RequestTileOnConnectedState {
+struct AuthOnSessionState {
     pub event_reader: EventReader<ReceiveEvent>,
+    pub username: String,
+    pub password: String,
 }
 
-/// When the client connects to the server, request a tile
-fn request_tile_on_connected(
-    mut state: ResMut<RequestTileOnConnectedState>,
+/// When the server starts a new session, send credentials. Resumed sessions are already authenticated.
+fn auth_on_session(
+    mut state: ResMut<AuthOnSessionState>,
     mut sender: ResMut<Events<SendEvent>>,
     receiver: ResMut<Events<ReceiveEvent>>
 ) {
+    let state: &mut AuthOnSessionState = &mut state;
     for evt in state.event_reader.iter(&receiver) {
-        if let ReceiveEvent::Connected(connection, _) = evt {
-            info!("Requesting tile because connected to server...");
+        if let ReceiveEvent::ReceivedPacket { connection, data, .. } = evt {
+            match **data {
+                Packet::Session(Session::Accepted { resumed: false, .. }) => {}
+                Packet::Session(Session::Accepted { resumed: true, .. }) => {
+                    info!("Resumed previous session");
+                    continue;
+                }
+                _ => continue,
+            }
+
+            info!("Authenticating as {:?}...", state.username);
             sender.send(SendEvent::SendPacket {
                 connection: *connection,
-                stream: StreamType::WorldTileData,
-                data: Arc::new(Packet::WorldTileDataRequest(WorldTileDataRequest {
-                    //todo(#46): Respect request coordinates (x, y lod)
-                    x: 0,
-                    y: 0,
-                    lod: 0
+                data: Arc::new(Packet::AuthRequest(AuthRequest {
+                    username: state.username.clone(),
+                    password: state.password.clone(),
                 }))
             });
         }
@@ -1,11 +1,11 @@
-use std::{convert::TryFrom, fs, path::PathBuf, io::Write};
-use bounded_planet::land::{texture_to_mesh_data};
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::PathBuf};
+use bounded_planet::land::generate_world;
 use bounded_planet::land::heightmap::{HeightmapData, SamplingError};
-use bounded_planet::land::mesh::MAX_INDEX_COUNT;
+use bounded_planet::land::world::TILE_EXTENSION;
 use structopt::StructOpt;
 use thiserror::Error;
 use image::GrayImage;
-use tracing::{Level, error};
+use tracing::{Level, error, info};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "server")]
@@ -13,6 +13,14 @@ struct Opt {
     /// Path to heightmaps folder or file
     #[structopt(long = "path", required=true)]
     path: PathBuf,
+
+    /// Number of quads along each side of a tile
+    #[structopt(long = "tile_size", default_value = "128")]
+    tile_size: u16,
+
+    /// Number of levels of detail to generate each tile at
+    #[structopt(long = "lods", default_value = "4")]
+    lods: u8,
 }
 
 #[derive(Debug, Error)]
@@ -29,7 +37,7 @@ enum Errors {
 #[derive(Debug)]
 pub struct InputImageTooLargeError {
     path: Option<PathBuf>,
-    oversize: usize
+    oversize: u32
 }
 
 pub struct ImageHeightmap<'a> {
@@ -39,11 +47,13 @@ pub struct ImageHeightmap<'a> {
 impl<'a> ImageHeightmap<'a> {
     pub fn new(texture: &GrayImage) -> Result<ImageHeightmap, InputImageTooLargeError>
     {
-        let size = usize::try_from(texture.width() * texture.height()).expect("word size is less than 32 bit");
-        if size > MAX_INDEX_COUNT {
+        // The outermost pixels are only used for normals, so the heightmap is two pixels smaller than the image
+        let max = u32::from(u16::MAX) + 2;
+        let largest = texture.width().max(texture.height());
+        if largest > max {
             return Err(InputImageTooLargeError {
                 path: None,
-                oversize: size-MAX_INDEX_COUNT
+                oversize: largest-max
             })
         }
         Ok(ImageHeightmap {
@@ -67,7 +77,6 @@ impl<'a> HeightmapData for ImageHeightmap<'a>
     }
 }
 
-const EXTENSION: &str = "bpmesh";
 
 fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(
@@ -86,16 +95,16 @@ async fn run(options: Opt) -> anyhow::Result<()> {
     let metadata = fs::metadata(&options.path)?;
     if metadata.is_file() {
         let mut output_path = options.path.clone();
-        output_path.set_extension(EXTENSION);
-        generate_mesh(&options.path, &output_path)?;
+        output_path.set_extension("");
+        generate_mesh(&options.path, &output_path, options.tile_size, options.lods)?;
     } else if metadata.is_dir() {
         for entry in fs::read_dir(&options.path)? {
             let entry = entry?;
             let path = entry.path();
             if path.is_file() {
                 let mut output_path = path.clone();
-                output_path.set_extension(EXTENSION);
-                if let Err(e) = generate_mesh(&path, &output_path) {
+                output_path.set_extension("");
+                if let Err(e) = generate_mesh(&path, &output_path, options.tile_size, options.lods) {
                     error!("{}", e);
                 }
             }
@@ -107,9 +116,10 @@ async fn run(options: Opt) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn generate_mesh(file_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()> {
+/// Cut a heightmap into tiles at every level of detail, and write them into a world directory at `out_path`
+fn generate_mesh(file_path: &PathBuf, out_path: &PathBuf, tile_size: u16, lods: u8) -> anyhow::Result<()> {
     if let Some(ext) = file_path.extension() {
-        if ext == EXTENSION {
+        if ext == TILE_EXTENSION {
             return Ok(());
         }
     }
@@ -118,13 +128,15 @@ fn generate_mesh(file_path: &PathBuf, out_path: &PathBuf) -> anyhow::Result<()>
         e.path=Some(file_path.clone());
         Errors::InputImageTooLarge(e)
     })?;
-    let mesh = texture_to_mesh_data(&heightmap);
 
-    let mut encoder = flate2::write::ZlibEncoder::new(
-        std::fs::File::create(out_path)?,
-        flate2::Compression::new(5)
-    );
-    encoder.write_all(&rmp_serde::to_vec(&mesh)?)?;
+    let mut metadata = BTreeMap::new();
+    if let Some(name) = file_path.file_name() {
+        metadata.insert("source".to_owned(), name.to_string_lossy().into_owned());
+    }
+    metadata.insert("generator".to_owned(), format!("gen_world {}", env!("CARGO_PKG_VERSION")));
+
+    let manifest = generate_world(&heightmap, out_path, tile_size, lods, &metadata)?;
+    info!("Generated {}x{} tiles at {} levels of detail from {:?} into {:?}", manifest.tiles_x, manifest.tiles_y, manifest.lods, file_path, out_path);
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,17 +1,26 @@
-use std::{fs, net::SocketAddr, path::PathBuf, sync::Arc, time::SystemTime};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use std::time::Duration;
 use bevy::app::ScheduleRunnerPlugin;
 use bevy::prelude::*;
 use structopt::StructOpt;
-use tracing::{Level, info};
+use tracing::{Level, info, warn};
 use bounded_planet::{
-    land::systems::{WorldTileDataState, handle_world_tile_data_requests, setup_world_mesh_data},
+    land::{
+        store::{WorldStore, WorldStoreConfig},
+        systems::{WorldTileDataState, handle_world_tile_data_requests},
+    },
     networking::{
-        components::Connection,
+        capture::Capture as CapturePlugin,
+        crypto::{ClientAuth, SelfSigned, fingerprint, load_certificate_chain, load_private_key},
+        stats::{StatsLogConfig, log_network_stats_system},
         systems::{NetEventLoggerState, log_net_events},
-        events::{ReceiveEvent, SendEvent},
-        packets::{Packet, Ping, Pong, StreamType},
-        server::plugin::Network as NetworkPlugin
+        server::{
+            auth::{Auth as AuthPlugin, HashedFileCredentialStore},
+            plugin::{Network as NetworkPlugin, ServerTransport},
+            replication::Replication as ReplicationPlugin,
+            session::Sessions as SessionsPlugin,
+            shutdown::{Shutdown as ShutdownPlugin, ShutdownHandle}
+        }
     }
 };
 
@@ -22,13 +31,70 @@ struct Opt {
     #[structopt(long = "listen", default_value = "[::1]:4433")]
     addr: SocketAddr,
 
-    /// TLS private key in PEM format
-    #[structopt(parse(from_os_str), short = "k", long = "key", required=true)]
-    key: PathBuf,
+    /// TLS private key in PEM or DER format. If neither key nor certificate is given a self-signed certificate is
+    /// generated for this run.
+    #[structopt(parse(from_os_str), short = "k", long = "key", requires = "cert")]
+    key: Option<PathBuf>,
     
-    /// TLS certificate in PEM format
-    #[structopt(parse(from_os_str), short = "c", long = "cert", required=true)]
-    cert: PathBuf,
+    /// TLS certificate in PEM or DER format
+    #[structopt(parse(from_os_str), short = "c", long = "cert", requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// Generate a self-signed key and certificate, write them to the `key` and `cert` paths, and exit
+    #[structopt(long = "generate_cert", requires = "key")]
+    generate_cert: bool,
+
+    /// Host names to include in generated certificates
+    #[structopt(long = "hostname", default_value = "localhost")]
+    hostnames: Vec<String>,
+
+    /// Only admit clients presenting a certificate signed by a CA in this PEM or DER file
+    #[structopt(parse(from_os_str), long = "client_ca")]
+    client_ca: Vec<PathBuf>,
+
+    /// Only admit clients presenting a certificate whose fingerprint is listed in this file, one per line
+    #[structopt(parse(from_os_str), long = "allowed_clients")]
+    allowed_clients: Option<PathBuf>,
+
+    /// File containing the salted password hashes of users allowed to connect
+    #[structopt(parse(from_os_str), short = "u", long = "users", default_value = "./users.txt")]
+    users: PathBuf,
+
+    /// Add a user to the users file (in the form `username:password`) and exit
+    #[structopt(long = "add_user")]
+    add_user: Option<String>,
+
+    /// Number of failed authentication attempts before a connection is closed
+    #[structopt(long = "max_auth_attempts", default_value = "3")]
+    max_auth_attempts: u32,
+
+    /// Number of seconds a connection may stay open without authenticating
+    #[structopt(long = "auth_timeout", default_value = "30")]
+    auth_timeout: u64,
+
+    /// Number of seconds a disconnected client has to reconnect and resume its session
+    #[structopt(long = "session_grace", default_value = "60")]
+    session_grace: u64,
+
+    /// Record every network event to this capture file
+    #[structopt(parse(from_os_str), long = "capture")]
+    capture: Option<PathBuf>,
+
+    /// Number of seconds between dumps of the network traffic totals to the log
+    #[structopt(long = "stats_interval", default_value = "60")]
+    stats_interval: u64,
+
+    /// Number of seconds to wait for connections to close when shutting down
+    #[structopt(long = "shutdown_timeout", default_value = "5")]
+    shutdown_timeout: u64,
+
+    /// World directory written by `gen_world`
+    #[structopt(parse(from_os_str), long = "world", default_value = "content/worlds/CoveWorldtest")]
+    world: PathBuf,
+
+    /// Number of megabytes of world tiles to keep in memory
+    #[structopt(long = "world_memory", default_value = "256")]
+    world_memory: usize,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -40,9 +106,50 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .expect("Failed to configure logging");
 
     let opt = Opt::from_args();
+
+    if let Some(user) = &opt.add_user {
+        return add_user(&opt.users, user);
+    }
+
+    if opt.generate_cert {
+        return generate_cert(&opt);
+    }
+
     run(opt)
 }
 
+/// Generate a self-signed key and certificate and write them to the key and cert paths
+fn generate_cert(options: &Opt) -> Result<(), Box<dyn std::error::Error>> {
+    let (key_path, cert_path) = match (&options.key, &options.cert) {
+        (Some(key), Some(cert)) => (key, cert),
+        _ => return Err("Both `--key` and `--cert` are required to generate a certificate".into()),
+    };
+
+    let generated = SelfSigned::generate(options.hostnames.clone())?;
+    generated.save(key_path, cert_path)?;
+
+    info!("Generated certificate for {:?} (fingerprint {})", options.hostnames, fingerprint(&generated.cert));
+
+    Ok(())
+}
+
+/// Add a `username:password` pair to the users file
+fn add_user(users_path: &PathBuf, user: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut parts = user.splitn(2, ':');
+    let (username, password) = match (parts.next(), parts.next()) {
+        (Some(username), Some(password)) => (username, password),
+        _ => return Err("Expected user in the form `username:password`".into()),
+    };
+
+    let mut store = HashedFileCredentialStore::load(users_path)?;
+    store.add_user(username, password)?;
+    store.save(users_path)?;
+
+    info!("Added user {:?} to {:?}", username, users_path);
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
     // Create a Bevy app
@@ -52,19 +159,55 @@ async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
         1.0 / 10.0,
     )));
 
-    let (key, cert) = get_certs(&options.key, &options.cert)?;
+    let (key, cert) = get_certs(&options)?;
     app.add_plugin(NetworkPlugin {
-        certificate: cert,
-        private_key: key,
-        addr: options.addr,
+        transport: ServerTransport::Quic {
+            certificate: cert,
+            private_key: key,
+            addr: options.addr,
+            client_auth: get_client_auth(&options)?,
+        },
+        queue_config: Default::default(),
+        codec: Default::default(),
+        rate_limit: Default::default(),
+        health_config: Default::default(),
     });
 
-    app.add_startup_system(setup_world_mesh_data.system());
+    if let Some(path) = &options.capture {
+        app.add_plugin(CapturePlugin { path: path.clone() });
+    }
 
-    app.add_system(send_pings.system());
+    info!("Loading Users: {:?}", options.users);
+    app.add_plugin(AuthPlugin {
+        store: Arc::new(HashedFileCredentialStore::load(&options.users)?),
+        max_attempts: options.max_auth_attempts,
+        timeout: Duration::from_secs(options.auth_timeout),
+    });
 
-    app.init_resource::<PongLoggerState>();
-    app.add_system(log_pongs.system());
+    app.add_plugin(SessionsPlugin {
+        grace_period: Duration::from_secs(options.session_grace),
+    });
+
+    app.add_plugin(ReplicationPlugin);
+
+    // Close all connections cleanly on ctrl-c
+    let shutdown = ShutdownHandle::default();
+    let handle = shutdown.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Received ctrl-c");
+            handle.request();
+        }
+    });
+    app.add_plugin(ShutdownPlugin {
+        timeout: Duration::from_secs(options.shutdown_timeout),
+        handle: shutdown,
+    });
+
+    app.add_resource(WorldStore::new(&WorldStoreConfig {
+        dir: options.world.clone(),
+        memory_budget: options.world_memory * 1024 * 1024,
+    }));
 
     app.init_resource::<WorldTileDataState>();
     app.add_system(handle_world_tile_data_requests.system());
@@ -72,67 +215,58 @@ async fn run(options: Opt) -> Result<(), Box<dyn std::error::Error>> {
     app.init_resource::<NetEventLoggerState>();
     app.add_system(log_net_events.system());
 
-    // Run it forever
+    app.add_resource(StatsLogConfig {
+        interval: Duration::from_secs(options.stats_interval),
+    });
+    app.add_system(log_network_stats_system.system());
+
+    // Run it until shutdown
     app.run();
 
     Ok(())
 }
 
-/// Fetch certificates to use
-fn get_certs(
-    key_path: &PathBuf,
-    cert_path: &PathBuf
-) -> Result<(quinn::PrivateKey, quinn::CertificateChain), Box<dyn std::error::Error>> {
-    info!("Loading Key: {:?}", key_path);
-    let key = fs::read(key_path)?;
-    let key = if key_path.extension().map_or(false, |x| x == "der") {
-        quinn::PrivateKey::from_der(&key)?
-    } else {
-        quinn::PrivateKey::from_pem(&key)?
-    };
+/// Fetch certificates to use, or generate a self-signed certificate if none were given
+fn get_certs(options: &Opt) -> Result<(quinn::PrivateKey, quinn::CertificateChain), Box<dyn std::error::Error>> {
+    match (&options.key, &options.cert) {
+        (Some(key_path), Some(cert_path)) => {
+            info!("Loading Key: {:?}", key_path);
+            let key = load_private_key(key_path)?;
 
-    info!("Loading Cert: {:?}", cert_path);
-    let cert_chain = fs::read(cert_path)?;
-    let cert_chain = if cert_path.extension().map_or(false, |x| x == "der") {
-        quinn::CertificateChain::from_certs(quinn::Certificate::from_der(&cert_chain))
-    } else {
-        quinn::CertificateChain::from_pem(&cert_chain)?
-    };
+            info!("Loading Cert: {:?}", cert_path);
+            let cert_chain = load_certificate_chain(cert_path)?;
 
-    Ok((
-        key,
-        cert_chain,
-    ))
-}
+            Ok((key, cert_chain))
+        }
 
-fn send_pings(mut sender: ResMut<Events<SendEvent>>, conn: &Connection) {
-    sender.send(SendEvent::SendPacket {
-        connection: conn.id,
-        stream: StreamType::PingPong,
-        data: Arc::new(
-            Packet::Ping(Ping::default())
-        ),
-    });
-}
+        _ => {
+            let generated = SelfSigned::generate(options.hostnames.clone())?;
+            warn!(
+                "No certificate given, generated a self-signed certificate for {:?} (fingerprint {})",
+                options.hostnames,
+                fingerprint(&generated.cert)
+            );
 
-#[derive(Default)]
-pub struct PongLoggerState {
-    pub event_reader: EventReader<ReceiveEvent>,
+            Ok((generated.private_key()?, generated.certificate_chain()?))
+        }
+    }
 }
 
-fn log_pongs(mut state: ResMut<PongLoggerState>, receiver: ResMut<Events<ReceiveEvent>>) {
-    for evt in state.event_reader.iter(&receiver) {
-        if let ReceiveEvent::ReceivedPacket { data, .. } = evt {
-            if let Packet::Pong(Pong { timestamp }) = **data {
-                let time_sent = SystemTime::UNIX_EPOCH.checked_add(
-                    Duration::from_millis(timestamp as u64)
-                ).expect("Overflowed SystemTime");
-
-                let time_now = SystemTime::now();
-                let latency = time_now.duration_since(time_sent);
+/// Load the CAs and fingerprints of clients allowed to connect, if the server only admits known clients
+fn get_client_auth(options: &Opt) -> Result<Option<ClientAuth>, Box<dyn std::error::Error>> {
+    if options.client_ca.is_empty() && options.allowed_clients.is_none() {
+        return Ok(None);
+    }
 
-                info!("Received Pong. Latency {:?}", latency);
-            }
-        }
+    let mut client_auth = ClientAuth::default();
+    for path in &options.client_ca {
+        info!("Loading Client CA: {:?}", path);
+        client_auth.load_authorities(path)?;
+    }
+    if let Some(path) = &options.allowed_clients {
+        info!("Loading Allowed Clients: {:?}", path);
+        client_auth.load_allowed(path)?;
     }
+
+    Ok(Some(client_auth))
 }
@@ -0,0 +1,145 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io::{self, Read, Write},
+};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::mesh::MeshData;
+
+/// Written at the start of every mesh file
+const MAGIC: &[u8; 6] = b"BPMESH";
+
+/// Version of the mesh file format. Must be incremented whenever `MeshFileHeader` or `MeshData` changes.
+pub const MESH_FILE_VERSION: u32 = 1;
+
+/// Compression level of the mesh in a mesh file
+const COMPRESSION_LEVEL: u32 = 5;
+
+#[derive(Error, Debug)]
+pub enum MeshFileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Not a mesh file")]
+    NotAMeshFile,
+
+    #[error("Mesh file version {0} is not supported, expected version {expected}", expected = MESH_FILE_VERSION)]
+    UnsupportedVersion(u32),
+
+    #[error("Mesh file is corrupt, checksum is {actual:08x} but expected {expected:08x}")]
+    ChecksumMismatch {
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("Failed to encode header: {0}")]
+    EncodeHeader(bincode::Error),
+
+    #[error("Failed to decode header: {0}")]
+    DecodeHeader(bincode::Error),
+
+    #[error("Failed to encode mesh: {0}")]
+    EncodeMesh(rmp_serde::encode::Error),
+
+    #[error("Failed to decode mesh: {0}")]
+    DecodeMesh(rmp_serde::decode::Error),
+}
+
+/// Describes the mesh in a mesh file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshFileHeader {
+    /// Coordinates and level of detail of the tile
+    pub x: u32,
+    pub y: u32,
+    pub lod: u8,
+
+    /// Size of the heightmap the tile was cut from, in samples
+    pub heightmap_width: u32,
+    pub heightmap_height: u32,
+
+    /// Free-form information about the file, e.g. the heightmap it was generated from
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A `.bpmesh` file, holding the mesh of one world tile.
+///
+/// Files start with a magic number and the format version (4 bytes, network order), followed by a CRC32 checksum of
+/// the rest of the file. Then comes the length of the header (4 bytes, network order), the bincode encoded header,
+/// and the zlib compressed mesh, encoded with rmp-serde.
+#[derive(Debug, Clone)]
+pub struct MeshFile {
+    pub header: MeshFileHeader,
+    pub mesh_data: MeshData,
+}
+
+/// The header is encoded with bincode, the same options are used to write and read it
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl MeshFile {
+    /// Write the file
+    pub fn write(&self, mut writer: impl Write) -> Result<(), MeshFileError> {
+        let header = bincode_options().serialize(&self.header).map_err(MeshFileError::EncodeHeader)?;
+        let mesh = rmp_serde::to_vec(&self.mesh_data).map_err(MeshFileError::EncodeMesh)?;
+
+        let mut body = Vec::with_capacity(4 + header.len() + mesh.len() / 2);
+        body.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        body.extend_from_slice(&header);
+        let mut encoder = flate2::write::ZlibEncoder::new(body, flate2::Compression::new(COMPRESSION_LEVEL));
+        encoder.write_all(&mesh)?;
+        let body = encoder.finish()?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&MESH_FILE_VERSION.to_be_bytes())?;
+        writer.write_all(&crc32fast::hash(&body).to_be_bytes())?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Read a file, checking that it was written in a supported version and is not corrupt
+    pub fn read(mut reader: impl Read) -> Result<Self, MeshFileError> {
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic).map_err(|_| MeshFileError::NotAMeshFile)?;
+        if &magic != MAGIC {
+            return Err(MeshFileError::NotAMeshFile);
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != MESH_FILE_VERSION {
+            return Err(MeshFileError::UnsupportedVersion(version));
+        }
+
+        let expected = read_u32(&mut reader)?;
+        let mut body = Vec::new();
+        reader.read_to_end(&mut body)?;
+        let actual = crc32fast::hash(&body);
+        if actual != expected {
+            return Err(MeshFileError::ChecksumMismatch { expected, actual });
+        }
+
+        let header_length = match body.get(..4) {
+            Some(length) => u32::from_be_bytes(length.try_into().expect("Slice is 4 bytes long")) as usize,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+        let header = body.get(4..4 + header_length).ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let header = bincode_options().deserialize(header).map_err(MeshFileError::DecodeHeader)?;
+
+        let mesh = flate2::read::ZlibDecoder::new(&body[4 + header_length..]);
+        let mesh_data = rmp_serde::from_read(mesh).map_err(MeshFileError::DecodeMesh)?;
+
+        Ok(MeshFile { header, mesh_data })
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
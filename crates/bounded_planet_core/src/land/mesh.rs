@@ -7,6 +7,13 @@ use super::heightmap::HeightmapData;
 
 pub const MAX_INDEX_COUNT: usize = u16::MAX as usize;
 
+/// Number of levels of detail a mesh can be decimated to. Each level halves the number of samples along each side.
+pub const MAX_LOD_COUNT: u8 = 8;
+
+/// Depth of the skirts hung from the edges of decimated meshes. This is the full height range of a heightmap, so a
+/// skirt always reaches below the edge of a neighbouring tile, whatever its level of detail.
+pub const SKIRT_DEPTH: f32 = 256.0 / 16.0;
+
 /// Iterator which generates a quad (two triangles) with the top left corner at a given idnex
 struct QuadPatchGenerator {
     idx: usize,
@@ -62,34 +69,14 @@ pub fn texture_to_mesh_data<T>(land_texture: &T) -> MeshData
     let width = i32::from(land_texture.size().0);
     let height = i32::from(land_texture.size().1);
 
-    // Define a helper to sample the underlying data
-    let sample = |x, z| {
-        land_texture.sample(x, z).expect("Failed to sample heightmap") / 16.0
-    };
-
     // Generate positions
     let positions = (0..height).cartesian_product(0..width)
-        .map(move |(z, x)| [x as f32, sample(x, z), z as f32])
+        .map(move |(z, x)| [x as f32, sample_height(land_texture, x, z), z as f32])
         .collect::<Vec<_>>();
 
     // Generate normals
     let normals = (0..height).cartesian_product(0..width)
-        .map(move |(z, x)| {
-            // Sample 4 terrain points around central point
-            let l = sample(x - 1, z);
-            let r = sample(x + 1, z);
-            let d = sample(x, z - 1);
-            let u = sample(x, z + 1);
-
-            // Calculate normal
-            let norm = Vec3::new(
-                l - r,
-                2f32,
-                d - u
-            ).normalize();
-            
-            [norm.x(), norm.y(), norm.z()]
-        })
+        .map(move |(z, x)| sample_normal(land_texture, x, z))
         .collect::<Vec<_>>();
 
     MeshData {
@@ -100,13 +87,118 @@ pub fn texture_to_mesh_data<T>(land_texture: &T) -> MeshData
     }
 }
 
+/// Sample the height of the land at a point
+fn sample_height<T: HeightmapData>(land_texture: &T, x: i32, z: i32) -> f32 {
+    land_texture.sample(x, z).expect("Failed to sample heightmap") / 16.0
+}
+
+/// Calculate the normal of the land at a point from the 4 terrain points around it
+fn sample_normal<T: HeightmapData>(land_texture: &T, x: i32, z: i32) -> [f32; 3] {
+    let l = sample_height(land_texture, x - 1, z);
+    let r = sample_height(land_texture, x + 1, z);
+    let d = sample_height(land_texture, x, z - 1);
+    let u = sample_height(land_texture, x, z + 1);
+
+    let norm = Vec3::new(
+        l - r,
+        2f32,
+        d - u
+    ).normalize();
+
+    [norm.x(), norm.y(), norm.z()]
+}
+
+/// Get the samples kept along an axis at a level of detail: every `2^lod`th sample, and always the last one so the
+/// edges of neighbouring tiles meet
+fn lod_samples(len: i32, lod: u8) -> Vec<i32> {
+    let mut samples = (0..len).step_by(1 << lod).collect::<Vec<_>>();
+    if samples.last() != Some(&(len - 1)) {
+        samples.push(len - 1);
+    }
+    samples
+}
+
+/// takes a grayscale texture handle and returns the mesh data at a level of detail. Level 0 has a vertex for every
+/// sample, and each level above halves the number of vertices along each side.
+///
+/// Skirts of depth `SKIRT_DEPTH` hang from every edge, hiding the cracks between neighbouring tiles at different
+/// levels of detail.
+pub fn texture_to_lod_mesh_data<T>(land_texture: &T, lod: u8) -> MeshData
+    where T: HeightmapData
+{
+    assert!(lod < MAX_LOD_COUNT, "Level of detail {} is too high", lod);
+
+    let width = i32::from(land_texture.size().0);
+    let height = i32::from(land_texture.size().1);
+    let xs = lod_samples(width, lod);
+    let zs = lod_samples(height, lod);
+    let columns = xs.len();
+
+    let grid = zs.iter().cartesian_product(xs.iter()).map(|(&z, &x)| (x, z)).collect::<Vec<_>>();
+    let mut vertices = grid.iter()
+        .map(|&(x, z)| [x as f32, sample_height(land_texture, x, z), z as f32])
+        .collect::<Vec<_>>();
+    let mut normals = grid.iter()
+        .map(|&(x, z)| sample_normal(land_texture, x, z))
+        .collect::<Vec<_>>();
+    let mut uvs = grid.iter()
+        .map(|&(x, z)| [x as f32 / (width - 1) as f32, z as f32 / (height - 1) as f32])
+        .collect::<Vec<_>>();
+
+    let mut indices = (0..zs.len() - 1).cartesian_product(0..columns - 1)
+        .flat_map(|(row, column)| {
+            let base = (row * columns + column) as u32;
+            let columns = columns as u32;
+            vec![base, base + columns, base + 1, base + columns, base + columns + 1, base + 1]
+        })
+        .collect::<Vec<_>>();
+
+    // Walk around the edge of the grid, hanging a skirt below each edge
+    let last_row = (zs.len() - 1) * columns;
+    let edges = vec![
+        (0..columns).collect::<Vec<_>>(),
+        (0..zs.len()).map(|row| row * columns + columns - 1).collect(),
+        (0..columns).map(|column| last_row + column).collect(),
+        (0..zs.len()).map(|row| row * columns).collect(),
+    ];
+    for edge in edges {
+        let first_skirt = vertices.len() as u32;
+        for &idx in &edge {
+            let [x, y, z] = vertices[idx];
+            vertices.push([x, y - SKIRT_DEPTH, z]);
+            normals.push(normals[idx]);
+            uvs.push(uvs[idx]);
+        }
+
+        // Skirts are seen from either side depending on which edge they hang from, so both faces are emitted
+        for (i, pair) in edge.windows(2).enumerate() {
+            let (a, b) = (pair[0] as u32, pair[1] as u32);
+            let (sa, sb) = (first_skirt + i as u32, first_skirt + i as u32 + 1);
+            indices.extend_from_slice(&[a, sa, b, b, sa, sb]);
+            indices.extend_from_slice(&[a, b, sa, b, sb, sa]);
+        }
+    }
+
+    MeshData {
+        vertices,
+        indices,
+        normals,
+        uvs,
+    }
+}
+
 /// takes a grayscale texture handle and returns a mesh with height based on the grayscale values
 pub fn texture_to_mesh<T>(land_texture: &T) -> Result<Mesh, Box<dyn std::error::Error>>
     where T: HeightmapData
 {
     let mesh_data = texture_to_mesh_data(land_texture);
 
-    let land_mesh = Mesh {
+    Ok(mesh_data_to_mesh(mesh_data))
+}
+
+/// Turn mesh data (e.g. a tile received from the server) into a mesh which can be rendered
+pub fn mesh_data_to_mesh(mesh_data: MeshData) -> Mesh {
+    Mesh {
         primitive_topology: bevy::render::pipeline::PrimitiveTopology::TriangleList,
         attributes: vec![
             VertexAttribute::position(mesh_data.vertices),
@@ -114,9 +206,7 @@ pub fn texture_to_mesh<T>(land_texture: &T) -> Result<Mesh, Box<dyn std::error::
             VertexAttribute::uv(mesh_data.uvs),
         ],
         indices: Some(mesh_data.indices),
-    };
-
-    Ok(land_mesh)
+    }
 }
 
 fn uvs(width: i32, height: i32) -> Vec<[f32; 2]> {
@@ -2,6 +2,17 @@ pub mod heightmap;
 pub use heightmap::TextureHeightmap;
 
 pub mod mesh;
-pub use mesh::{MeshData, texture_to_mesh, texture_to_mesh_data};
+pub use mesh::{MeshData, mesh_data_to_mesh, texture_to_mesh, texture_to_mesh_data};
+
+pub mod bpmesh;
+pub use bpmesh::{MeshFile, MeshFileError, MeshFileHeader};
+
+pub mod world;
+pub use world::{WorldManifest, generate_world};
+
+pub mod store;
+pub use store::{WorldStore, WorldStoreConfig};
 
 pub mod systems;
+
+pub mod streaming;
@@ -0,0 +1,215 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+use tracing::{error, info};
+
+use super::{
+    mesh::MeshData,
+    world::{WorldError, WorldManifest, load_tile},
+};
+
+/// Identifies a tile at a level of detail: `(x, y, lod)`
+pub type TileKey = (u32, u32, u8);
+
+/// Configures where the server loads the world from, and how much of it is kept in memory
+#[derive(Debug, Clone)]
+pub struct WorldStoreConfig {
+    /// World directory written by `gen_world`, containing a manifest and one file per tile
+    pub dir: PathBuf,
+
+    /// Approximate number of bytes of tiles kept in memory. When there are more, the tiles which were requested
+    /// least recently are dropped.
+    pub memory_budget: usize,
+}
+
+impl Default for WorldStoreConfig {
+    fn default() -> Self {
+        WorldStoreConfig {
+            dir: PathBuf::from("content/worlds/CoveWorldtest"),
+            memory_budget: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// The state of a tile in a `WorldStore`
+#[derive(Debug, Clone)]
+pub enum TileStatus {
+    /// The tile is in memory
+    Ready(Arc<MeshData>),
+
+    /// The tile is being loaded in the background, see `WorldStore::poll`
+    Loading,
+
+    /// The tile is not in the world
+    Missing,
+}
+
+/// A tile in memory
+struct StoredTile {
+    mesh_data: Arc<MeshData>,
+    memory: usize,
+
+    /// Value of `WorldStore::clock` when the tile was last requested
+    last_used: u64,
+}
+
+/// The tiles of the world on the server. Tiles are loaded from the world directory on demand, on a background task,
+/// and kept in memory until the memory budget runs out. Must be created inside the tokio runtime.
+pub struct WorldStore {
+    dir: PathBuf,
+    memory_budget: usize,
+    manifest: Option<WorldManifest>,
+
+    tiles: HashMap<TileKey, StoredTile>,
+    memory_used: usize,
+    clock: u64,
+
+    /// Tiles being loaded in the background
+    loading: HashSet<TileKey>,
+    loaded_sender: UnboundedSender<(TileKey, Result<MeshData, WorldError>)>,
+    loaded_receiver: UnboundedReceiver<(TileKey, Result<MeshData, WorldError>)>,
+
+    /// Runtime to load tiles on, systems may not run on a runtime thread
+    runtime: Handle,
+}
+
+impl WorldStore {
+    /// Create a store for a world directory. The manifest is loaded straight away, if it can not be loaded the error
+    /// is logged and every tile is missing.
+    pub fn new(config: &WorldStoreConfig) -> Self {
+        let manifest = match WorldManifest::load(&config.dir) {
+            Ok(manifest) => {
+                info!("Serving a world of {}x{} tiles from {:?}", manifest.tiles_x, manifest.tiles_y, config.dir);
+                Some(manifest)
+            }
+            Err(err) => {
+                error!("Failed to load world, no tiles will be served: {}", err);
+                None
+            }
+        };
+
+        let (loaded_sender, loaded_receiver) = unbounded_channel();
+        WorldStore {
+            dir: config.dir.clone(),
+            memory_budget: config.memory_budget,
+            manifest,
+            tiles: HashMap::new(),
+            memory_used: 0,
+            clock: 0,
+            loading: HashSet::new(),
+            loaded_sender,
+            loaded_receiver,
+            runtime: Handle::current(),
+        }
+    }
+
+    /// Get the manifest of the world, if it was loaded
+    pub fn manifest(&self) -> Option<&WorldManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Approximate number of bytes of tiles in memory
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    /// Number of tiles in memory
+    pub fn cached(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Get a tile. If it is not in memory it starts loading, and is returned from `poll` once it has loaded.
+    pub fn get(&mut self, key: TileKey) -> TileStatus {
+        let (x, y, lod) = key;
+        if !self.manifest.map_or(false, |manifest| manifest.contains(x, y, lod)) {
+            return TileStatus::Missing;
+        }
+
+        self.clock += 1;
+        if let Some(tile) = self.tiles.get_mut(&key) {
+            tile.last_used = self.clock;
+            return TileStatus::Ready(tile.mesh_data.clone());
+        }
+
+        if let (true, Some(manifest)) = (self.loading.insert(key), self.manifest) {
+            let path = WorldManifest::tile_path(&self.dir, x, y, lod);
+            let sender = self.loaded_sender.clone();
+            self.runtime.spawn(async move {
+                // The loading task may have panicked, the error is still sent so the tile is not stuck loading
+                let task_path = path.clone();
+                let result = match tokio::task::spawn_blocking(move || load_tile(&task_path, &manifest, x, y, lod)).await {
+                    Ok(result) => result.map(|tile| tile.mesh_data),
+                    Err(err) => Err(WorldError::LoadTask { path, err }),
+                };
+
+                // The store may have been dropped, in which case nobody wants the tile
+                let _ = sender.send((key, result));
+            });
+        }
+
+        TileStatus::Loading
+    }
+
+    /// Collect the tiles which finished loading in the background. Tiles which failed to load are returned with the
+    /// error, and will be loaded again if they are requested again.
+    pub fn poll(&mut self) -> Vec<(TileKey, Result<Arc<MeshData>, WorldError>)> {
+        let mut loaded = Vec::new();
+        while let Ok((key, result)) = self.loaded_receiver.try_recv() {
+            self.loading.remove(&key);
+
+            let result = result.map(|mesh_data| {
+                let mesh_data = Arc::new(mesh_data);
+                let memory = memory_size(&mesh_data);
+                self.clock += 1;
+                self.memory_used += memory;
+                self.tiles.insert(key, StoredTile {
+                    mesh_data: mesh_data.clone(),
+                    memory,
+                    last_used: self.clock,
+                });
+                mesh_data
+            });
+            loaded.push((key, result));
+        }
+
+        self.evict();
+        loaded
+    }
+
+    /// Drop the least recently used tiles until the tiles in memory fit in the budget. Tiles still being sent to
+    /// clients are kept alive by their `Arc` until they have been sent.
+    fn evict(&mut self) {
+        if self.memory_used <= self.memory_budget {
+            return;
+        }
+
+        let mut by_age: Vec<_> = self.tiles.iter().map(|(key, tile)| (tile.last_used, *key)).collect();
+        by_age.sort();
+
+        for (_, key) in by_age {
+            if self.memory_used <= self.memory_budget {
+                break;
+            }
+            if let Some(tile) = self.tiles.remove(&key) {
+                self.memory_used -= tile.memory;
+            }
+        }
+    }
+}
+
+/// Approximate number of bytes of memory used by a mesh
+fn memory_size(mesh_data: &MeshData) -> usize {
+    size_of::<MeshData>()
+        + mesh_data.vertices.len() * size_of::<[f32; 3]>()
+        + mesh_data.indices.len() * size_of::<u32>()
+        + mesh_data.normals.len() * size_of::<[f32; 3]>()
+        + mesh_data.uvs.len() * size_of::<[f32; 2]>()
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tracing::{error, info, warn};
+
+use crate::{
+    camera::{CameraBPConfig, UniversalGeometry},
+    networking::{
+        events::{ReceiveEvent, SendEvent},
+        id::ConnectionId,
+        packets::{AuthResponse, Packet, RpcError, Session, WorldInfoRequest, WorldTileDataRequest},
+        rpc::{Requests, ResponseEvent, ResponseHandle},
+    },
+};
+use super::{mesh::mesh_data_to_mesh, world::WorldManifest};
+
+/// Identifies a tile at a level of detail: `(x, y, lod)`
+type TileKey = (u32, u32, u8);
+
+/// Configures which tiles are streamed in around the camera
+#[derive(Debug, Clone)]
+pub struct TerrainStreamingConfig {
+    /// Tiles within this distance of the point the camera looks at are shown, multiplied by the height of the camera
+    /// above the plane, so more terrain is shown as the camera zooms out
+    pub view_distance_scale: f32,
+
+    /// Smallest and largest distance from the point the camera looks at to show tiles within
+    pub min_view_distance: f32,
+    pub max_view_distance: f32,
+
+    /// Tiles within this distance of the camera are shown at full detail. Each time the distance doubles beyond
+    /// this, tiles are shown at the next level of detail.
+    pub lod_distance: f32,
+
+    /// Maximum number of tile requests in flight at once
+    pub max_requests: usize,
+
+    /// How long to wait for the server to send a requested tile
+    pub request_timeout: Duration,
+
+    /// Delay before requesting the world manifest again after a request failed, doubled after every failure up to
+    /// `max_manifest_retry_delay`
+    pub manifest_retry_delay: Duration,
+    pub max_manifest_retry_delay: Duration,
+
+    /// Number of tile meshes kept (including the ones being shown), so tiles which come back into view do not have
+    /// to be requested again
+    pub cache_size: usize,
+
+    /// Texture drawn over the whole world
+    pub texture: String,
+}
+
+impl Default for TerrainStreamingConfig {
+    fn default() -> Self {
+        TerrainStreamingConfig {
+            view_distance_scale: 4.0,
+            min_view_distance: 64.0,
+            max_view_distance: 2048.0,
+            lod_distance: 96.0,
+
+            // The server allows bursts of 16 tile requests
+            max_requests: 8,
+            request_timeout: Duration::from_secs(30),
+            manifest_retry_delay: Duration::from_secs(1),
+            max_manifest_retry_delay: Duration::from_secs(30),
+            cache_size: 128,
+            texture: "content/textures/CoveWorldTop.png".to_owned(),
+        }
+    }
+}
+
+/// Add this plugin (with the client `Network` plugin) to stream terrain tiles from the server around the camera with
+/// a `CameraBPConfig`. The world is laid out along the x and z axes of the plane, with its corner at the plane origin.
+/// The config may be changed at runtime through the `TerrainStreamingConfig` resource.
+#[derive(Default)]
+pub struct TerrainStreaming {
+    /// The geometry that the camera moves over, see `CameraBPPlugin`
+    pub geo: UniversalGeometry,
+    pub config: TerrainStreamingConfig,
+}
+
+impl Plugin for TerrainStreaming {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(self.config.clone());
+        app.add_resource(TerrainStreamingState {
+            geo: self.geo.normalize(),
+            ..Default::default()
+        });
+
+        app.add_system(track_terrain_server.system());
+        app.add_system(receive_terrain.system());
+        app.add_system(stream_terrain.system());
+    }
+}
+
+/// This component is attached to the entity showing each terrain tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerrainTile {
+    pub x: u32,
+    pub y: u32,
+    pub lod: u8,
+}
+
+/// A tile mesh which has been received from the server
+struct CachedTile {
+    mesh: Handle<Mesh>,
+
+    /// Frame the tile was last wanted in, the least recently used tiles are dropped first
+    last_used: u64,
+}
+
+/// Internal state of the terrain streaming systems
+#[derive(Default)]
+pub struct TerrainStreamingState {
+    geo: UniversalGeometry,
+    event_reader: EventReader<ReceiveEvent>,
+    response_reader: EventReader<ResponseEvent>,
+
+    /// Connection to the server tiles are requested from, once it has authenticated
+    connection: Option<ConnectionId>,
+
+    manifest: Option<WorldManifest>,
+    manifest_request: Option<ResponseHandle<WorldInfoRequest>>,
+
+    /// Number of manifest requests which have failed in a row, and when to request it again
+    manifest_failures: u32,
+    manifest_retry_at: Option<Instant>,
+
+    /// Tiles which have been requested but not received yet
+    pending: HashMap<TileKey, ResponseHandle<WorldTileDataRequest>>,
+
+    /// Tiles the server does not have, which are not requested again
+    missing: HashSet<TileKey>,
+
+    cache: HashMap<TileKey, CachedTile>,
+    frame: u64,
+
+    /// The entity showing each tile, by `(x, y)`
+    spawned: HashMap<(u32, u32), (Entity, TerrainTile)>,
+
+    material: Option<Handle<StandardMaterial>>,
+}
+
+impl TerrainStreamingState {
+    /// Number of tiles being shown
+    pub fn shown(&self) -> usize {
+        self.spawned.len()
+    }
+
+    /// Number of tile meshes in the cache
+    pub fn cached(&self) -> usize {
+        self.cache.len()
+    }
+}
+
+/// Work out which tiles should be shown, and at which level of detail, for a camera at `camera` looking at `focus`.
+/// Both are relative to the corner of the world, with the world laid out along the x and z axes and heights along y.
+/// Tiles are returned closest to the camera first.
+pub fn visible_tiles(manifest: &WorldManifest, config: &TerrainStreamingConfig, camera: Vec3, focus: Vec3) -> Vec<TerrainTile> {
+    let height = camera.y().max(0.0);
+    let radius = (height * config.view_distance_scale)
+        .max(config.min_view_distance)
+        .min(config.max_view_distance);
+
+    let size = f32::from(manifest.tile_size);
+    let (last_x, last_z) = ((manifest.width - 1) as f32, (manifest.height - 1) as f32);
+
+    // Range of tiles which may be within the radius, the distance is checked for each tile below
+    let range = |centre: f32, tiles: u32| {
+        let first = ((centre - radius) / size).floor().max(0.0) as u32;
+        let last = ((centre + radius) / size).floor().max(0.0) as u32;
+        first..last.saturating_add(1).min(tiles)
+    };
+
+    // Horizontal distance from a point to the area covered by a tile
+    let distance = |x: u32, y: u32, point: Vec3| {
+        let (min_x, min_z) = (x as f32 * size, y as f32 * size);
+        let (max_x, max_z) = ((min_x + size).min(last_x), (min_z + size).min(last_z));
+        let dx = (min_x - point.x()).max(point.x() - max_x).max(0.0);
+        let dz = (min_z - point.z()).max(point.z() - max_z).max(0.0);
+        (dx * dx + dz * dz).sqrt()
+    };
+
+    let mut tiles = Vec::new();
+    for y in range(focus.z(), manifest.tiles_y) {
+        for x in range(focus.x(), manifest.tiles_x) {
+            if distance(x, y, focus) > radius {
+                continue;
+            }
+
+            let from_camera = distance(x, y, camera).hypot(height);
+            let lod = if from_camera <= config.lod_distance {
+                0
+            } else {
+                (from_camera / config.lod_distance).log2().ceil() as u8
+            };
+
+            tiles.push((from_camera, TerrainTile { x, y, lod: lod.min(manifest.lods - 1) }));
+        }
+    }
+
+    tiles.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    tiles.into_iter().map(|(_, tile)| tile).collect()
+}
+
+/// Get the position of the camera, and the point on the plane it is looking at, relative to the plane origin
+fn camera_view(geo: &UniversalGeometry, transform: &GlobalTransform, max_distance: f32) -> (Vec3, Vec3) {
+    let UniversalGeometry::Plane { origin, normal } = *geo;
+
+    let camera = transform.translation() - origin;
+    let height = camera.dot(normal);
+    let forward = transform.rotation().mul_vec3(Vec3::new(0.0, 0.0, -1.0));
+
+    // Look straight down from the camera if it is looking away from the plane, or at the horizon
+    let towards = -forward.dot(normal);
+    let focus = if towards > std::f32::EPSILON && height / towards <= max_distance {
+        camera + forward * (height / towards)
+    } else {
+        camera - normal * height
+    };
+
+    (camera, focus)
+}
+
+/// Keep track of the connection to request tiles from, and request the world manifest once authenticated until it has
+/// been received
+fn track_terrain_server(
+    mut state: ResMut<TerrainStreamingState>,
+    config: Res<TerrainStreamingConfig>,
+    mut requests: ResMut<Requests>,
+    mut sender: ResMut<Events<SendEvent>>,
+    receiver: Res<Events<ReceiveEvent>>,
+) {
+    let state: &mut TerrainStreamingState = &mut state;
+    let previous = state.connection;
+    for evt in state.event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => match **data {
+                Packet::AuthResponse(AuthResponse::Ok) | Packet::Session(Session::Accepted { resumed: true, .. }) => {
+                    state.connection = Some(*connection);
+                }
+                _ => {}
+            },
+            ReceiveEvent::Disconnected { connection, .. } if state.connection == Some(*connection) => {
+                state.connection = None;
+            }
+            _ => {}
+        }
+    }
+
+    // The server may have changed, so tiles it did not have may be available now and the manifest is requested from
+    // the new connection straight away
+    if state.connection != previous {
+        state.missing.clear();
+        state.manifest_request = None;
+        state.manifest_failures = 0;
+        state.manifest_retry_at = None;
+    }
+
+    let connection = match state.connection {
+        Some(connection) if state.manifest.is_none() && state.manifest_request.is_none() => connection,
+        _ => return,
+    };
+    if state.manifest_retry_at.map_or(false, |retry_at| Instant::now() < retry_at) {
+        return;
+    }
+
+    info!("Requesting world info...");
+    state.manifest_retry_at = None;
+    state.manifest_request = Some(requests.send(&mut sender, connection, WorldInfoRequest, config.request_timeout));
+}
+
+/// Get the delay before requesting the manifest again after the given number of failed requests (starting from 1)
+fn manifest_retry_delay(config: &TerrainStreamingConfig, failures: u32) -> Duration {
+    let delay = config.manifest_retry_delay.as_secs_f64() * 2f64.powi(failures.saturating_sub(1).min(31) as i32);
+    Duration::from_secs_f64(delay.min(config.max_manifest_retry_delay.as_secs_f64()))
+}
+
+/// Store the world manifest and tiles received from the server
+fn receive_terrain(
+    mut state: ResMut<TerrainStreamingState>,
+    config: Res<TerrainStreamingConfig>,
+    responses: Res<Events<ResponseEvent>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    let state: &mut TerrainStreamingState = &mut state;
+    for evt in state.response_reader.iter(&responses) {
+        if let Some(result) = state.manifest_request.and_then(|handle| handle.get(evt)) {
+            state.manifest_request = None;
+            // The manifest comes from the network, so it is checked before anything relies on it
+            let error = match result.map(|info| (info.manifest, info.manifest.validate())) {
+                Ok((manifest, Ok(()))) => {
+                    info!("Streaming a world of {}x{} tiles", manifest.tiles_x, manifest.tiles_y);
+                    state.manifest = Some(manifest);
+                    continue;
+                }
+                Ok((_, Err(err))) => err.to_string(),
+                Err(err) => err.to_string(),
+            };
+
+            // Requested again by `track_terrain_server` after a delay
+            state.manifest_failures += 1;
+            let delay = manifest_retry_delay(&config, state.manifest_failures);
+            warn!("Failed to get world info, retrying in {:?}: {}", delay, error);
+            state.manifest_retry_at = Some(Instant::now() + delay);
+            continue;
+        }
+
+        let found = state.pending
+            .iter()
+            .find_map(|(key, handle)| handle.get(evt).map(|result| (*key, result)));
+        let (key, result) = match found {
+            Some(found) => found,
+            None => continue,
+        };
+        state.pending.remove(&key);
+
+        match result {
+            Ok(tile) => {
+                let mesh = meshes.add(mesh_data_to_mesh((*tile.mesh_data).clone()));
+                state.cache.insert(key, CachedTile { mesh, last_used: state.frame });
+            }
+            Err(RpcError::NotFound) => {
+                warn!("Server does not have tile {:?}", key);
+                state.missing.insert(key);
+            }
+
+            // Requested again if the tile is still wanted
+            Err(err) => warn!("Failed to request tile {:?}: {}", key, err),
+        }
+    }
+}
+
+/// Show the tiles around the camera, requesting the ones which have not been received yet, and despawn the tiles
+/// which are out of range
+#[allow(clippy::too_many_arguments)]
+fn stream_terrain(
+    mut commands: Commands,
+    mut state: ResMut<TerrainStreamingState>,
+    config: Res<TerrainStreamingConfig>,
+    asset_server: Res<AssetServer>,
+    mut requests: ResMut<Requests>,
+    mut sender: ResMut<Events<SendEvent>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut textures: ResMut<Assets<Texture>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut cameras: Query<(&CameraBPConfig, &GlobalTransform)>,
+) {
+    let state: &mut TerrainStreamingState = &mut state;
+    let manifest = match state.manifest {
+        Some(manifest) => manifest,
+        None => return,
+    };
+    let (camera, focus) = match cameras.iter().iter().next() {
+        Some((_, transform)) => camera_view(&state.geo, transform, config.max_view_distance),
+        None => return,
+    };
+
+    state.frame += 1;
+    let wanted = visible_tiles(&manifest, &config, camera, focus);
+
+    // Despawn tiles which are out of range, their meshes stay in the cache
+    let in_range: HashSet<_> = wanted.iter().map(|tile| (tile.x, tile.y)).collect();
+    let out_of_range: Vec<_> = state.spawned.keys().filter(|tile| !in_range.contains(tile)).copied().collect();
+    for tile in out_of_range {
+        if let Some((entity, _)) = state.spawned.remove(&tile) {
+            commands.despawn(entity);
+        }
+    }
+
+    let UniversalGeometry::Plane { origin, .. } = state.geo;
+    for tile in wanted {
+        let key = (tile.x, tile.y, tile.lod);
+        let frame = state.frame;
+
+        let mesh = match state.cache.get_mut(&key) {
+            Some(cached) => {
+                cached.last_used = frame;
+                cached.mesh
+            }
+            None => {
+                // Keep showing the tile at its old level of detail until the new one arrives
+                if let Some((_, shown)) = state.spawned.get(&(tile.x, tile.y)) {
+                    if let Some(cached) = state.cache.get_mut(&(shown.x, shown.y, shown.lod)) {
+                        cached.last_used = frame;
+                    }
+                }
+
+                let can_request = state.pending.len() < config.max_requests
+                    && !state.pending.contains_key(&key)
+                    && !state.missing.contains(&key);
+                if let (Some(connection), true) = (state.connection, can_request) {
+                    let handle = requests.send(&mut sender, connection, WorldTileDataRequest {
+                        x: tile.x,
+                        y: tile.y,
+                        lod: tile.lod,
+                    }, config.request_timeout);
+                    state.pending.insert(key, handle);
+                }
+                continue;
+            }
+        };
+
+        match state.spawned.get_mut(&(tile.x, tile.y)) {
+            Some((_, shown)) if *shown == tile => {}
+            Some((entity, shown)) => {
+                // Swap the level of detail
+                commands.insert(*entity, (mesh, tile));
+                *shown = tile;
+            }
+            None => {
+                let material = *state.material.get_or_insert_with(|| {
+                    // The terrain is still shown, untextured, if the texture can not be loaded
+                    let texture = match asset_server.load_sync(&mut textures, &config.texture) {
+                        Ok(texture) => Some(texture),
+                        Err(err) => {
+                            error!("Failed to load terrain texture {:?}: {}", config.texture, err);
+                            None
+                        }
+                    };
+                    materials.add(StandardMaterial {
+                        albedo_texture: texture,
+                        shaded: true,
+                        ..Default::default()
+                    })
+                });
+
+                commands
+                    .spawn(PbrComponents {
+                        mesh,
+                        material,
+                        transform: Transform::from_translation(origin),
+                        ..Default::default()
+                    })
+                    .with(tile);
+                let entity = commands.current_entity().expect("Terrain tile was not spawned");
+                state.spawned.insert((tile.x, tile.y), (entity, tile));
+            }
+        }
+    }
+
+    // Drop the least recently used meshes, tiles being shown were used this frame so are dropped last
+    if state.cache.len() > config.cache_size {
+        let mut by_age: Vec<_> = state.cache.iter().map(|(key, cached)| (cached.last_used, *key)).collect();
+        by_age.sort();
+
+        let excess = state.cache.len() - config.cache_size;
+        for (last_used, key) in by_age.into_iter().take(excess) {
+            if last_used == state.frame {
+                break;
+            }
+            if let Some(cached) = state.cache.remove(&key) {
+                meshes.remove(&cached.mesh);
+            }
+        }
+    }
+}
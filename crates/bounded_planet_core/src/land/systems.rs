@@ -1,51 +1,80 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+
 use bevy::prelude::*;
 use crate::networking::{
     events::{ReceiveEvent, SendEvent},
-    packets::{Packet, WorldTileDataRequest, WorldTileData}
+    packets::{RpcError, WorldInfo, WorldInfoRequest, WorldTileDataRequest, WorldTileData},
+    rpc::{DeferredRequest, IncomingRequest}
+};
+use tracing::{error, warn};
+use super::{
+    store::{TileKey, TileStatus, WorldStore},
+    world::WorldError,
 };
-use super::MeshData;
-
-/// Loads the world mesh and stores it
-pub fn setup_world_mesh_data(mut state: ResMut<WorldTileDataState>) {
-    //todo(#47):
-    // - Load world data on demand, instead of ahead of time like this
-    // - Use the asset server to load content?
-    // - Store a cache in `WorldTileDataState` (or load through asset server and unload)?
-
-    let mesh_data = rmp_serde::from_read::<_, MeshData>(
-        flate2::read::ZlibDecoder::new(
-            std::fs::File::open("content/worlds/CoveWorldtest.bpmesh").expect("Failed to load 'content/worlds/CoveWorldtest.bpmesh'")
-        )
-    ).expect("Failed to deserialized 'content/worlds/CoveWorldtest.bpmesh'");
-    state.mesh_data = Some(mesh_data);
-}
 
 #[derive(Default)]
 pub struct WorldTileDataState {
     pub event_reader: EventReader<ReceiveEvent>,
-    pub mesh_data: Option<MeshData>
+
+    /// Requests for tiles which are being loaded
+    pub waiting: HashMap<TileKey, Vec<DeferredRequest<WorldTileDataRequest>>>,
 }
 
-/// Handle a request from the client for a world tile
+/// Handle a request from the client for the world manifest or a world tile
 pub fn handle_world_tile_data_requests(
     mut state: ResMut<WorldTileDataState>,
+    mut store: ResMut<WorldStore>,
     mut sender: ResMut<Events<SendEvent>>,
     receiver: ResMut<Events<ReceiveEvent>>)
 {
+    let state: &mut WorldTileDataState = &mut state;
+
+    // Answer the requests for tiles which have finished loading
+    for (key, result) in store.poll() {
+        let waiting = state.waiting.remove(&key).unwrap_or_default();
+        if let Err(err) = &result {
+            error!("Failed to load tile {:?}: {}", key, err);
+        }
+
+        for request in waiting {
+            let request = request.incoming();
+            match &result {
+                Ok(mesh_data) => request.respond(&mut sender, WorldTileData {
+                    mesh_data: mesh_data.clone()
+                }),
+                Err(err) => request.fail(&mut sender, load_error(err)),
+            }
+        }
+    }
+
     for evt in state.event_reader.iter(&receiver) {
-        if let ReceiveEvent::ReceivedPacket { data, connection, .. } = evt {
-            //todo(#46): Respect request coordinates (x, y lod)
-            if let Packet::WorldTileDataRequest(WorldTileDataRequest { x: _x, y: _y, lod: _lod }) = **data {
-                sender.send(
-                    SendEvent::TransferPacket {
-                        connection: *connection,
-                        data: Arc::new(Packet::WorldTileData(WorldTileData {
-                            mesh_data: state.mesh_data.as_ref().expect("Failed to get mesh_data from WorldTileDataState").clone()
-                        }))
-                    }
-                );
+        if let Some(request) = IncomingRequest::<WorldInfoRequest>::from_event(evt) {
+            match store.manifest() {
+                Some(manifest) => request.respond(&mut sender, WorldInfo { manifest: *manifest }),
+                None => request.fail(&mut sender, RpcError::NotFound),
+            }
+        }
+
+        if let Some(request) = IncomingRequest::<WorldTileDataRequest>::from_event(evt) {
+            let WorldTileDataRequest { x, y, lod } = *request.request;
+            match store.get((x, y, lod)) {
+                TileStatus::Ready(mesh_data) => request.respond(&mut sender, WorldTileData {
+                    mesh_data
+                }),
+                TileStatus::Loading => state.waiting.entry((x, y, lod)).or_default().push(request.defer()),
+                TileStatus::Missing => {
+                    warn!("Connection {:?} requested tile ({}, {}) at level of detail {}, which is not in the world", request.connection, x, y, lod);
+                    request.fail(&mut sender, RpcError::NotFound);
+                }
             }
         }
     }
 }
+
+/// Get the error to send to a client when a tile fails to load. The details are only logged on the server.
+fn load_error(err: &WorldError) -> RpcError {
+    match err {
+        WorldError::Io { err, .. } if err.kind() == std::io::ErrorKind::NotFound => RpcError::NotFound,
+        _ => RpcError::Failed("Failed to load tile".to_owned()),
+    }
+}
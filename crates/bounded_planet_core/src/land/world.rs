@@ -0,0 +1,343 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{
+    bpmesh::{MeshFile, MeshFileError, MeshFileHeader},
+    heightmap::{HeightmapData, SamplingError},
+    mesh::{MAX_INDEX_COUNT, MAX_LOD_COUNT, MeshData, texture_to_lod_mesh_data},
+};
+
+/// Number of quads along each side of a tile, when `gen_world` is not given another size
+pub const DEFAULT_TILE_SIZE: u16 = 128;
+
+/// Number of levels of detail each tile is generated at, when `gen_world` is not given another count
+pub const DEFAULT_LOD_COUNT: u8 = 4;
+
+/// Largest number of quads along each side of a tile. Tiles have one more sample than quads along each side, and
+/// every vertex of a tile, including its skirts, must be addressable by a `u16` index (see `MAX_INDEX_COUNT`).
+pub const MAX_TILE_SIZE: u16 = 252;
+
+/// Name of the manifest file in a world directory
+pub const MANIFEST_FILE: &str = "world.manifest";
+
+/// Extension of tile files in a world directory
+pub const TILE_EXTENSION: &str = "bpmesh";
+
+#[derive(Debug, Error)]
+pub enum WorldError {
+    #[error("Failed to access {path:?}: {err}")]
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+
+    #[error("Failed to encode {path:?}: {err}")]
+    Encode {
+        path: PathBuf,
+        err: rmp_serde::encode::Error,
+    },
+
+    #[error("Failed to decode {path:?}: {err}")]
+    Decode {
+        path: PathBuf,
+        err: rmp_serde::decode::Error,
+    },
+
+    #[error("Failed to read or write tile {path:?}: {err}")]
+    MeshFile {
+        path: PathBuf,
+        err: MeshFileError,
+    },
+
+    #[error("Tile {path:?} does not belong in this world, expected {expected:?} but found {found:?}")]
+    TileMismatch {
+        path: PathBuf,
+        expected: Box<MeshFileHeader>,
+        found: Box<MeshFileHeader>,
+    },
+
+    #[error("Tile size {0} is not between 1 and {max}", max = MAX_TILE_SIZE)]
+    InvalidTileSize(u16),
+
+    #[error("Level of detail count {0} is not between 1 and {max}", max = MAX_LOD_COUNT)]
+    InvalidLodCount(u8),
+
+    #[error("Heightmap of {width}x{height} samples is too small to make a tile")]
+    HeightmapTooSmall {
+        width: u32,
+        height: u32,
+    },
+
+    #[error("Tile ({x}, {y}) at level of detail {lod} is outside the world")]
+    NoSuchTile {
+        x: u32,
+        y: u32,
+        lod: u8,
+    },
+
+    #[error("Manifest of {width}x{height} samples does not have {tiles_x}x{tiles_y} tiles")]
+    InconsistentManifest {
+        width: u32,
+        height: u32,
+        tiles_x: u32,
+        tiles_y: u32,
+    },
+
+    #[error("Task loading tile {path:?} failed: {err}")]
+    LoadTask {
+        path: PathBuf,
+        err: tokio::task::JoinError,
+    },
+}
+
+/// Describes how a world was cut into tiles, stored in the world directory next to the tile files
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct WorldManifest {
+    /// Size of the whole heightmap, in samples
+    pub width: u32,
+    pub height: u32,
+
+    /// Number of quads along each side of a tile. Neighbouring tiles share the samples along their common border,
+    /// so tiles are `tile_size + 1` samples wide (except at the far edges of the world, where they may be smaller).
+    pub tile_size: u16,
+
+    /// Number of tiles along each axis
+    pub tiles_x: u32,
+    pub tiles_y: u32,
+
+    /// Number of levels of detail each tile was generated at
+    pub lods: u8,
+}
+
+impl WorldManifest {
+    /// Describe a world cut from a heightmap of the given size
+    pub fn new(width: u32, height: u32, tile_size: u16, lods: u8) -> Result<Self, WorldError> {
+        if tile_size == 0 || tile_size > MAX_TILE_SIZE {
+            return Err(WorldError::InvalidTileSize(tile_size));
+        }
+        if lods == 0 || lods > MAX_LOD_COUNT {
+            return Err(WorldError::InvalidLodCount(lods));
+        }
+        if width < 2 || height < 2 {
+            return Err(WorldError::HeightmapTooSmall { width, height });
+        }
+
+        let tiles = |samples: u32| (samples - 1 + u32::from(tile_size) - 1) / u32::from(tile_size);
+        Ok(WorldManifest {
+            width,
+            height,
+            tile_size,
+            tiles_x: tiles(width),
+            tiles_y: tiles(height),
+            lods,
+        })
+    }
+
+    /// Check that a manifest (e.g. one which was received from the network) describes a valid world, in the same way
+    /// as `new`
+    pub fn validate(&self) -> Result<(), WorldError> {
+        let expected = Self::new(self.width, self.height, self.tile_size, self.lods)?;
+        if expected != *self {
+            return Err(WorldError::InconsistentManifest {
+                width: self.width,
+                height: self.height,
+                tiles_x: self.tiles_x,
+                tiles_y: self.tiles_y,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check if a tile is in the world
+    pub fn contains(&self, x: u32, y: u32, lod: u8) -> bool {
+        x < self.tiles_x && y < self.tiles_y && lod < self.lods
+    }
+
+    /// Get the first sample of a tile, and the number of samples it covers, along each axis
+    pub fn tile_bounds(&self, x: u32, y: u32) -> Result<((u32, u32), (u32, u32)), WorldError> {
+        if !self.contains(x, y, 0) {
+            return Err(WorldError::NoSuchTile { x, y, lod: 0 });
+        }
+
+        let size = u32::from(self.tile_size);
+        let first = (x * size, y * size);
+        let samples = ((size + 1).min(self.width - first.0), (size + 1).min(self.height - first.1));
+        Ok((first, samples))
+    }
+
+    /// Load the manifest of a world directory
+    pub fn load(dir: &Path) -> Result<Self, WorldError> {
+        let path = dir.join(MANIFEST_FILE);
+        let file = BufReader::new(File::open(&path).map_err(|err| WorldError::Io { path: path.clone(), err })?);
+        let manifest: Self = rmp_serde::from_read(file).map_err(|err| WorldError::Decode { path, err })?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    /// Save the manifest into a world directory
+    pub fn save(&self, dir: &Path) -> Result<(), WorldError> {
+        let path = dir.join(MANIFEST_FILE);
+        let data = rmp_serde::to_vec(self).map_err(|err| WorldError::Encode { path: path.clone(), err })?;
+        fs::write(&path, data).map_err(|err| WorldError::Io { path, err })
+    }
+
+    /// Get the header a tile file of this world should have, without any metadata
+    pub fn tile_header(&self, x: u32, y: u32, lod: u8) -> MeshFileHeader {
+        MeshFileHeader {
+            x,
+            y,
+            lod,
+            heightmap_width: self.width,
+            heightmap_height: self.height,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Get the path of a tile file in a world directory
+    pub fn tile_path(dir: &Path, x: u32, y: u32, lod: u8) -> PathBuf {
+        dir.join(format!("{}_{}_{}.{}", x, y, lod, TILE_EXTENSION))
+    }
+}
+
+/// Load a tile file, and check that it holds the expected tile of the world
+pub fn load_tile(path: &Path, manifest: &WorldManifest, x: u32, y: u32, lod: u8) -> Result<MeshFile, WorldError> {
+    let file = BufReader::new(File::open(path).map_err(|err| WorldError::Io { path: path.to_owned(), err })?);
+    let tile = MeshFile::read(file).map_err(|err| WorldError::MeshFile { path: path.to_owned(), err })?;
+
+    // Metadata is free-form, so it is not checked
+    let expected = MeshFileHeader {
+        metadata: tile.header.metadata.clone(),
+        ..manifest.tile_header(x, y, lod)
+    };
+    if tile.header != expected {
+        return Err(WorldError::TileMismatch {
+            path: path.to_owned(),
+            expected: Box::new(expected),
+            found: Box::new(tile.header),
+        });
+    }
+
+    Ok(tile)
+}
+
+/// Save a tile file
+pub fn save_tile(path: &Path, tile: &MeshFile) -> Result<(), WorldError> {
+    let file = BufWriter::new(File::create(path).map_err(|err| WorldError::Io { path: path.to_owned(), err })?);
+    tile.write(file).map_err(|err| WorldError::MeshFile { path: path.to_owned(), err })
+}
+
+/// Load the manifest and every tile, at every level of detail, of a world directory
+pub fn load_world(dir: &Path) -> Result<(WorldManifest, HashMap<(u32, u32, u8), MeshData>), WorldError> {
+    let manifest = WorldManifest::load(dir)?;
+
+    let mut tiles = HashMap::new();
+    for y in 0..manifest.tiles_y {
+        for x in 0..manifest.tiles_x {
+            for lod in 0..manifest.lods {
+                let tile = load_tile(&WorldManifest::tile_path(dir, x, y, lod), &manifest, x, y, lod)?;
+                tiles.insert((x, y, lod), tile.mesh_data);
+            }
+        }
+    }
+
+    Ok((manifest, tiles))
+}
+
+/// A window onto part of a larger heightmap. Samples one either side of the window are read from the larger
+/// heightmap, so normals along the border match the neighbouring tile.
+pub struct TileHeightmap<'a, H> {
+    heightmap: &'a H,
+    offset: (i32, i32),
+    size: (u16, u16),
+}
+
+impl<'a, H: HeightmapData> TileHeightmap<'a, H> {
+    /// Get the window onto a tile of a world
+    pub fn new(heightmap: &'a H, manifest: &WorldManifest, x: u32, y: u32) -> Result<Self, WorldError> {
+        let (first, samples) = manifest.tile_bounds(x, y)?;
+
+        // Tiles are at most `MAX_TILE_SIZE + 1` samples wide, and the world is at most as large as the heightmap
+        Ok(TileHeightmap {
+            heightmap,
+            offset: (first.0 as i32, first.1 as i32),
+            size: (samples.0 as u16, samples.1 as u16),
+        })
+    }
+}
+
+impl<'a, H: HeightmapData> HeightmapData for TileHeightmap<'a, H> {
+    fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    fn sample(&self, x: i32, y: i32) -> Result<f32, SamplingError> {
+        if (x > i32::from(self.size.0)) || (y > i32::from(self.size.1)) || (y < -1) || (x < -1) {
+            return Err(SamplingError::ReadOutOfBounds())
+        }
+
+        self.heightmap.sample(self.offset.0 + x, self.offset.1 + y)
+    }
+}
+
+/// Generate the mesh of one tile of a world at a level of detail. Vertices are positioned relative to the corner of
+/// the whole world, and UVs span the whole world, so tiles line up without being moved.
+pub fn tile_mesh_data<H: HeightmapData>(heightmap: &H, manifest: &WorldManifest, x: u32, y: u32, lod: u8) -> Result<MeshData, WorldError> {
+    if !manifest.contains(x, y, lod) {
+        return Err(WorldError::NoSuchTile { x, y, lod });
+    }
+    let tile = TileHeightmap::new(heightmap, manifest, x, y)?;
+
+    let mut mesh_data = texture_to_lod_mesh_data(&tile, lod);
+    debug_assert!(mesh_data.vertices.len() <= MAX_INDEX_COUNT);
+    let (offset_x, offset_z) = (tile.offset.0 as f32, tile.offset.1 as f32);
+    let (span_x, span_z) = ((manifest.width - 1) as f32, (manifest.height - 1) as f32);
+
+    for (vertex, uv) in mesh_data.vertices.iter_mut().zip(mesh_data.uvs.iter_mut()) {
+        vertex[0] += offset_x;
+        vertex[2] += offset_z;
+        *uv = [vertex[0] / span_x, vertex[2] / span_z];
+    }
+
+    Ok(mesh_data)
+}
+
+/// Cut a heightmap into tiles, generate each tile at every level of detail, and write them with a manifest into a
+/// world directory. The directory is created if it does not exist. The metadata is stored in every tile file.
+pub fn generate_world<H: HeightmapData>(
+    heightmap: &H,
+    dir: &Path,
+    tile_size: u16,
+    lods: u8,
+    metadata: &BTreeMap<String, String>,
+) -> Result<WorldManifest, WorldError> {
+    let (width, height) = heightmap.size();
+    let manifest = WorldManifest::new(u32::from(width), u32::from(height), tile_size, lods)?;
+
+    fs::create_dir_all(dir).map_err(|err| WorldError::Io { path: dir.to_owned(), err })?;
+    for y in 0..manifest.tiles_y {
+        for x in 0..manifest.tiles_x {
+            for lod in 0..manifest.lods {
+                let tile = MeshFile {
+                    header: MeshFileHeader {
+                        metadata: metadata.clone(),
+                        ..manifest.tile_header(x, y, lod)
+                    },
+                    mesh_data: tile_mesh_data(heightmap, &manifest, x, y, lod)?,
+                };
+                save_tile(&WorldManifest::tile_path(dir, x, y, lod), &tile)?;
+            }
+        }
+    }
+
+    // The manifest is written last, so a world with a manifest is complete
+    manifest.save(dir)?;
+
+    Ok(manifest)
+}
@@ -0,0 +1,516 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::{
+    codec::CodecConfig,
+    components::PeerIdentity,
+    events::{DisconnectReason, ReceiveEvent, SendEvent, SendTarget},
+    handshake::{HandshakeRejection, PROTOCOL_VERSION},
+    id::ConnectionId,
+    packets::{AuthRequest, Packet, RpcPayload, Session, SessionToken, StreamType},
+    queue::{QueueConfig, SendQueue},
+    rate_limit::{Violation, ViolationPolicy},
+    systems::{SEND_NET_EVENT_STAGE, SessionEventListenerState},
+};
+
+/// Written at the start of every capture file
+const MAGIC: &[u8; 6] = b"BPCAP\0";
+
+/// Version of the capture file format. Must be incremented whenever `CaptureRecord` changes.
+pub const CAPTURE_VERSION: u32 = 2;
+
+/// Space allowed in a record for everything other than its packet
+const RECORD_OVERHEAD: usize = 64 * 1024;
+
+/// Recorded in place of passwords
+pub const REDACTED_PASSWORD: &str = "<redacted>";
+
+/// Recorded in place of session tokens
+pub const REDACTED_TOKEN: SessionToken = SessionToken([0; 32]);
+
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Not a capture file")]
+    NotACapture,
+
+    #[error("Capture file version {0} is not supported, expected version {}", CAPTURE_VERSION)]
+    UnsupportedVersion(u32),
+
+    #[error("Capture was recorded with protocol version {0}, expected version {}", PROTOCOL_VERSION)]
+    ProtocolMismatch(u32),
+
+    #[error("Failed to encode record: {0}")]
+    Encode(bincode::Error),
+
+    #[error("Failed to decode record: {0}")]
+    Decode(bincode::Error),
+
+    #[error("Record of {size} bytes is larger than the maximum of {max} bytes")]
+    RecordTooLarge {
+        size: usize,
+        max: usize,
+    },
+}
+
+/// An event in a capture. Events received from the network mirror `ReceiveEvent`, events sent to the network mirror
+/// `SendEvent`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CapturedEvent {
+    Connected,
+    PeerIdentified(PeerIdentity),
+    Received {
+        stream: StreamType,
+        packet: Arc<Packet>,
+    },
+    HandshakeCompleted {
+        capabilities: HashSet<String>,
+    },
+    HandshakeRejected(HandshakeRejection),
+    PeerSilent(Duration),
+    LimitExceeded {
+        violation: Violation,
+        policy: ViolationPolicy,
+    },
+    SessionResumed {
+        replaced: ConnectionId,
+    },
+    Disconnected {
+        reason: DisconnectReason,
+        by_peer: bool,
+    },
+    SocketClosed,
+
+    /// A `NetworkError`. Errors can't be replayed, so only their description is kept.
+    Error(String),
+
+    /// A packet sent with `SendEvent::SendPacket`
+    Send {
+        stream: StreamType,
+        packet: Arc<Packet>,
+    },
+
+    /// A packet sent with `SendEvent::SendDatagram`
+    SendDatagram {
+        stream: StreamType,
+        packet: Arc<Packet>,
+    },
+
+    /// A connection closed with `SendEvent::Disconnect`
+    Disconnect(DisconnectReason),
+
+    /// A packet sent to several connections with `SendEvent::Broadcast`
+    Broadcast {
+        target: SendTarget,
+        stream: StreamType,
+        packet: Arc<Packet>,
+    },
+}
+
+impl CapturedEvent {
+    /// Check if this event was sent to the network, rather than received from it
+    pub fn is_sent(&self) -> bool {
+        matches!(self, CapturedEvent::Send { .. } | CapturedEvent::SendDatagram { .. } | CapturedEvent::Disconnect(_) | CapturedEvent::Broadcast { .. })
+    }
+}
+
+/// A single event in a capture file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// Frame the event was published in, counted from the start of the capture
+    pub frame: u64,
+
+    /// Time from the start of the capture to the frame the event was published in
+    pub timestamp: Duration,
+
+    /// The connection the event is about, if any
+    pub connection: Option<ConnectionId>,
+
+    pub event: CapturedEvent,
+}
+
+impl CaptureRecord {
+    fn received(frame: u64, timestamp: Duration, event: &ReceiveEvent) -> Self {
+        let (connection, event) = match event {
+            ReceiveEvent::Connected(connection, _) => (Some(*connection), CapturedEvent::Connected),
+            ReceiveEvent::PeerIdentified { connection, identity } => (Some(*connection), CapturedEvent::PeerIdentified(identity.clone())),
+            ReceiveEvent::ReceivedPacket { connection, stream, data } => (Some(*connection), CapturedEvent::Received { stream: *stream, packet: redact(data) }),
+            ReceiveEvent::HandshakeCompleted { connection, capabilities } => (Some(*connection), CapturedEvent::HandshakeCompleted { capabilities: capabilities.clone() }),
+            ReceiveEvent::HandshakeRejected { connection, reason } => (Some(*connection), CapturedEvent::HandshakeRejected(reason.clone())),
+            ReceiveEvent::PeerSilent { connection, silent_for } => (Some(*connection), CapturedEvent::PeerSilent(*silent_for)),
+            ReceiveEvent::LimitExceeded { connection, violation, policy } => (Some(*connection), CapturedEvent::LimitExceeded { violation: *violation, policy: *policy }),
+            ReceiveEvent::SessionResumed { connection, replaced } => (Some(*connection), CapturedEvent::SessionResumed { replaced: *replaced }),
+            ReceiveEvent::Disconnected { connection, reason, by_peer } => (Some(*connection), CapturedEvent::Disconnected { reason: reason.clone(), by_peer: *by_peer }),
+            ReceiveEvent::SocketClosed => (None, CapturedEvent::SocketClosed),
+            ReceiveEvent::NetworkError(err) => (err.connection(), CapturedEvent::Error(err.to_string())),
+        };
+
+        CaptureRecord { frame, timestamp, connection, event }
+    }
+
+    fn sent(frame: u64, timestamp: Duration, event: &SendEvent) -> Self {
+        let event_data = match event {
+            SendEvent::SendPacket { data, .. } => CapturedEvent::Send { stream: data.stream_type(), packet: redact(data) },
+            SendEvent::SendDatagram { data, .. } => CapturedEvent::SendDatagram { stream: data.stream_type(), packet: redact(data) },
+            SendEvent::Disconnect { reason, .. } => CapturedEvent::Disconnect(reason.clone()),
+            SendEvent::Broadcast { target, data } => CapturedEvent::Broadcast { target: target.clone(), stream: data.stream_type(), packet: redact(data) },
+        };
+
+        CaptureRecord { frame, timestamp, connection: event.get_connection(), event: event_data }
+    }
+
+    /// Convert an event which came from the network back into a `ReceiveEvent`. Returns `None` for every other event.
+    /// `queues` holds the send queue created for each replayed connection.
+    fn replay(&self, queues: &mut HashMap<ConnectionId, SendQueue>) -> Option<ReceiveEvent> {
+        let connection = self.connection;
+        let event = match &self.event {
+            CapturedEvent::Connected => {
+                let connection = connection?;
+                let queue = queues.entry(connection).or_insert_with(|| SendQueue::new(QueueConfig::default()));
+                ReceiveEvent::Connected(connection, queue.clone())
+            }
+            CapturedEvent::PeerIdentified(identity) => ReceiveEvent::PeerIdentified { connection: connection?, identity: identity.clone() },
+            CapturedEvent::Received { stream, packet } => ReceiveEvent::ReceivedPacket { connection: connection?, stream: *stream, data: packet.clone() },
+            CapturedEvent::LimitExceeded { violation, policy } => ReceiveEvent::LimitExceeded { connection: connection?, violation: *violation, policy: *policy },
+            CapturedEvent::Disconnected { reason, by_peer } => {
+                let connection = connection?;
+                queues.remove(&connection);
+                ReceiveEvent::Disconnected { connection, reason: reason.clone(), by_peer: *by_peer }
+            }
+            CapturedEvent::SocketClosed => {
+                queues.clear();
+                ReceiveEvent::SocketClosed
+            }
+
+            // These events are published by systems in the app, which publish them again as the packets are replayed
+            CapturedEvent::HandshakeCompleted { .. }
+            | CapturedEvent::HandshakeRejected(_)
+            | CapturedEvent::PeerSilent(_)
+            | CapturedEvent::SessionResumed { .. } => return None,
+
+            CapturedEvent::Error(_)
+            | CapturedEvent::Send { .. }
+            | CapturedEvent::SendDatagram { .. }
+            | CapturedEvent::Disconnect(_)
+            | CapturedEvent::Broadcast { .. } => return None,
+        };
+
+        Some(event)
+    }
+}
+
+/// Replace the secrets in a packet (passwords and session tokens) with placeholders, so capture files can't be used
+/// to log in or take over a session. Packets without secrets are shared rather than copied.
+fn redact(packet: &Arc<Packet>) -> Arc<Packet> {
+    let redact_auth = |request: &AuthRequest| AuthRequest {
+        username: request.username.clone(),
+        password: REDACTED_PASSWORD.to_owned(),
+    };
+
+    let redacted = match &**packet {
+        Packet::AuthRequest(request) => Packet::AuthRequest(redact_auth(request)),
+        Packet::Request { id, request: RpcPayload::AuthRequest(request) } => Packet::Request {
+            id: *id,
+            request: RpcPayload::AuthRequest(redact_auth(request)),
+        },
+        Packet::Session(Session::Request { resume }) => Packet::Session(Session::Request {
+            resume: resume.map(|_| REDACTED_TOKEN),
+        }),
+        Packet::Session(Session::Accepted { resumed, .. }) => Packet::Session(Session::Accepted {
+            token: REDACTED_TOKEN,
+            resumed: *resumed,
+        }),
+        _ => return packet.clone(),
+    };
+
+    Arc::new(redacted)
+}
+
+/// Records are encoded with bincode, the same options are used to write and read them
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Writes records to a capture file
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    /// Create a capture file, replacing any existing file
+    pub fn create(path: &Path) -> Result<Self, CaptureError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&CAPTURE_VERSION.to_be_bytes())?;
+        writer.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+
+        Ok(CaptureWriter { writer })
+    }
+
+    /// Write a record, prefixed with its length (4 bytes, network order)
+    pub fn write(&mut self, record: &CaptureRecord) -> Result<(), CaptureError> {
+        let bytes = bincode_options().serialize(record).map_err(CaptureError::Encode)?;
+        self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), CaptureError> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Reads the records in a capture file, in the order they were written
+pub struct CaptureReader {
+    reader: BufReader<File>,
+}
+
+impl CaptureReader {
+    /// Open a capture file and check that it was written by a compatible build
+    pub fn open(path: &Path) -> Result<Self, CaptureError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 6];
+        reader.read_exact(&mut magic).map_err(|_| CaptureError::NotACapture)?;
+        if &magic != MAGIC {
+            return Err(CaptureError::NotACapture);
+        }
+
+        let version = read_u32(&mut reader)?;
+        if version != CAPTURE_VERSION {
+            return Err(CaptureError::UnsupportedVersion(version));
+        }
+
+        let protocol = read_u32(&mut reader)?;
+        if protocol != PROTOCOL_VERSION {
+            return Err(CaptureError::ProtocolMismatch(protocol));
+        }
+
+        Ok(CaptureReader { reader })
+    }
+
+    /// Read the next record, or `None` at the end of the file
+    pub fn read(&mut self) -> Result<Option<CaptureRecord>, CaptureError> {
+        let length = match read_u32(&mut self.reader) {
+            Ok(length) => length as usize,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        // Refuse to allocate space for records larger than any packet the network would have accepted
+        let max = CodecConfig::default().max_frame_size + RECORD_OVERHEAD;
+        if length > max {
+            return Err(CaptureError::RecordTooLarge { size: length, max });
+        }
+
+        let mut bytes = vec![0; length];
+        self.reader.read_exact(&mut bytes)?;
+        bincode_options().deserialize(&bytes).map(Some).map_err(CaptureError::Decode)
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<CaptureRecord, CaptureError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+/// Add this plugin after a network plugin to record every `ReceiveEvent` and `SendEvent` to a capture file. The file
+/// can be inspected with `CaptureReader`, or fed back into an app with `Replay`. Passwords and session tokens are
+/// replaced with placeholders, so replayed logins fail.
+#[derive(Debug)]
+pub struct Capture {
+    pub path: PathBuf,
+}
+
+impl Plugin for Capture {
+    fn build(&self, app: &mut AppBuilder) {
+        info!("Capturing network events to {:?}", self.path);
+        let writer = CaptureWriter::create(&self.path).expect("Failed to create capture file");
+
+        app.add_resource(CaptureState {
+            writer: Some(writer),
+            started: Instant::now(),
+            frame: 0,
+            receive_event_reader: Default::default(),
+            send_event_reader: Default::default(),
+        });
+        app.add_system_to_stage(SEND_NET_EVENT_STAGE, capture_system.system());
+    }
+}
+
+pub struct CaptureState {
+    /// `None` once writing has failed
+    writer: Option<CaptureWriter>,
+    started: Instant,
+    frame: u64,
+    receive_event_reader: EventReader<ReceiveEvent>,
+    send_event_reader: EventReader<SendEvent>,
+}
+
+/// Write the events published this frame to the capture file
+pub fn capture_system(
+    mut state: ResMut<CaptureState>,
+    receive_events: Res<Events<ReceiveEvent>>,
+    send_events: Res<Events<SendEvent>>,
+) {
+    let state: &mut CaptureState = &mut state;
+    let (frame, timestamp) = (state.frame, state.started.elapsed());
+    state.frame += 1;
+
+    let received = state.receive_event_reader.iter(&receive_events).map(|evt| CaptureRecord::received(frame, timestamp, evt));
+    let sent = state.send_event_reader.iter(&send_events).map(|evt| CaptureRecord::sent(frame, timestamp, evt));
+    let records = received.chain(sent).collect::<Vec<_>>();
+
+    let writer = match &mut state.writer {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    // Flush every frame, so the capture is complete up to the last frame if the app crashes
+    let result = records
+        .iter()
+        .try_for_each(|record| writer.write(record))
+        .and_then(|_| writer.flush());
+
+    if let Err(err) = result {
+        warn!("Failed to write capture, stopping capture: {}", err);
+        state.writer = None;
+    }
+}
+
+/// Feeds the events received in a capture back into an app as `ReceiveEvent`s, one captured frame per update. The app
+/// must have a network plugin, usually a server `Network` plugin with a loopback transport which no client connects
+/// to.
+///
+/// Only events which came from the network are replayed. Events published by systems (such as `HandshakeCompleted`)
+/// are published again by the app as it handles the replayed packets. Sent events and errors are not replayed.
+pub struct Replay {
+    records: Vec<CaptureRecord>,
+
+    /// Index of the next record to replay
+    next: usize,
+
+    /// Next frame to replay
+    frame: u64,
+
+    /// Send queues of the replayed connections. Nothing sends from them, they are emptied after every update.
+    queues: HashMap<ConnectionId, SendQueue>,
+
+    /// If set, `step` waits so that frames are replayed at the same pace they were captured
+    pub realtime: bool,
+    started: Option<Instant>,
+
+    send_event_reader: EventReader<SendEvent>,
+
+    /// Events the app sent while being replayed
+    pub sent: Vec<SendEvent>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<CaptureRecord>) -> Self {
+        Replay {
+            records,
+            next: 0,
+            frame: 0,
+            queues: Default::default(),
+            realtime: false,
+            started: None,
+            send_event_reader: Default::default(),
+            sent: Default::default(),
+        }
+    }
+
+    /// Read every record in a capture file
+    pub fn load(path: &Path) -> Result<Self, CaptureError> {
+        Ok(Self::new(CaptureReader::open(path)?.collect::<Result<_, _>>()?))
+    }
+
+    /// Check if every captured frame has been replayed
+    pub fn finished(&self) -> bool {
+        self.next >= self.records.len()
+    }
+
+    /// Feed the events received in the next captured frame into the app and run one update. Returns false once every
+    /// frame has been replayed.
+    pub fn step(&mut self, app: &mut App) -> bool {
+        if self.finished() {
+            return false;
+        }
+
+        if self.realtime {
+            self.wait_for_frame();
+        }
+
+        let event_sender = app
+            .resources
+            .get::<SessionEventListenerState>()
+            .expect("Network plugin was not added")
+            .event_sender
+            .clone();
+
+        // Events are passed through the same channel as the network tasks use, so they are handled exactly as they
+        // were when they were captured
+        while let Some(record) = self.records.get(self.next).filter(|record| record.frame <= self.frame) {
+            if let Some(event) = record.replay(&mut self.queues) {
+                let _ = event_sender.send(event);
+            }
+            self.next += 1;
+        }
+        self.frame += 1;
+
+        app.update();
+
+        let send_events = app.resources.get::<Events<SendEvent>>().expect("Network plugin was not added");
+        self.sent.extend(self.send_event_reader.iter(&send_events).cloned());
+        for queue in self.queues.values() {
+            queue.discard();
+        }
+
+        true
+    }
+
+    /// Replay every remaining frame
+    pub fn run(&mut self, app: &mut App) {
+        while self.step(app) {}
+    }
+
+    /// Sleep until the time the next frame was captured at, relative to the first replayed frame
+    fn wait_for_frame(&mut self) {
+        let timestamp = match self.records.get(self.next) {
+            Some(record) if record.frame <= self.frame => record.timestamp,
+            _ => return,
+        };
+
+        let first = self.records[0].timestamp;
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let due = started + timestamp.checked_sub(first).unwrap_or_default();
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
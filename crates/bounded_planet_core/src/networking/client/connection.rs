@@ -0,0 +1,353 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use quinn::{
+    ClientConfigBuilder,
+    crypto::rustls::TlsSession,
+};
+use thiserror::Error;
+use tokio::{runtime::Handle, sync::mpsc::UnboundedSender};
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::networking::{
+    codec::CodecConfig,
+    crypto::{ClientIdentity, KnownHosts, PinningVerifier, SkipServerVerification},
+    events::{DisconnectCode, NetworkError, ReceiveEvent, SendEvent},
+    handshake::ALPN_PROTOCOLS,
+    id::ConnectionId,
+    loopback::{LoopbackConnector, LoopbackError, run_loopback},
+    packets::{Packet, Session, SessionToken},
+    queue::QueueConfig,
+    rate_limit::RateLimitConfig,
+    systems::{Connecting, SessionEventListenerState},
+};
+
+/// How the client connects to the server
+#[derive(Debug, Clone)]
+pub enum ClientTransport {
+    /// Connect to a QUIC server
+    Quic {
+        addr: SocketAddr,
+        url: Url,
+        trust: ServerTrust,
+
+        /// Certificate to present to servers which require client certificates
+        identity: Option<ClientIdentity>,
+    },
+
+    /// Connect to a server in the same process, see `networking::loopback`
+    Loopback(LoopbackConnector),
+}
+
+/// Configures which server certificates the client accepts
+#[derive(Debug, Clone, Default)]
+pub struct ServerTrust {
+    /// Certificates (usually CAs) trusted to sign the server's certificate
+    pub anchors: Vec<quinn::Certificate>,
+
+    /// If set, a server certificate which isn't signed by an anchor is pinned here the first time it is seen, and
+    /// must match on every later connection
+    pub known_hosts: Option<Arc<KnownHosts>>,
+
+    /// Accept any certificate, even if it is invalid. Insecure!
+    pub accept_any: bool,
+}
+
+/// Configures how the client reconnects to the server when the connection is lost
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectConfig {
+    /// Delay before the first attempt to reconnect
+    pub initial_delay: Duration,
+
+    /// The delay is multiplied by this after every failed attempt
+    pub multiplier: f64,
+
+    /// Maximum delay between attempts
+    pub max_delay: Duration,
+
+    /// Number of failed attempts after which the client gives up, or `None` to keep trying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Get the delay before the given attempt (starting from 1)
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+/// State of the connection to the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionStatus {
+    /// Waiting for a connection attempt to complete
+    Connecting {
+        attempt: u32,
+    },
+
+    /// Connected to the server
+    Connected(ConnectionId),
+
+    /// Waiting before the next connection attempt
+    WaitingToReconnect {
+        attempt: u32,
+        retry_at: Instant,
+    },
+
+    /// Too many connection attempts failed, the client will not try again
+    GaveUp,
+
+    /// The connection was closed deliberately (by either end) for a reason which reconnecting will not fix
+    Closed(DisconnectCode),
+
+    /// The server's certificate did not match the pinned fingerprint, the client will not try again
+    Untrusted,
+}
+
+/// Resource holding the state of the connection to the server, and everything needed to dial it again
+pub struct ServerConnection {
+    status: ConnectionStatus,
+
+    /// The token of the current session, used to resume it after reconnecting
+    session_token: Option<SessionToken>,
+
+    transport: ClientTransport,
+    queue_config: QueueConfig,
+    codec: Arc<CodecConfig>,
+    reconnect: ReconnectConfig,
+
+    /// Runtime to run connection tasks on, systems may not run on a runtime thread
+    runtime: Handle,
+}
+
+impl ServerConnection {
+    pub(crate) fn new(transport: ClientTransport, queue_config: QueueConfig, codec: CodecConfig, reconnect: ReconnectConfig) -> Self {
+        ServerConnection {
+            status: ConnectionStatus::Connecting { attempt: 0 },
+            session_token: None,
+            transport,
+            queue_config,
+            codec: Arc::new(codec),
+            reconnect,
+            runtime: Handle::current(),
+        }
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// Start a connection attempt
+    pub(crate) fn dial(&mut self, attempt: u32, event_sender: UnboundedSender<ReceiveEvent>) {
+        let (runtime, queue_config, codec) = (&self.runtime, self.queue_config, self.codec.clone());
+
+        // The server is trusted not to flood the client
+        let limits = Arc::new(RateLimitConfig::unlimited());
+        let started = match &self.transport {
+            ClientTransport::Quic { addr, url, trust, identity } => {
+                info!("Connecting to {} (attempt {})", addr, attempt);
+
+                // Sockets must be created inside the runtime
+                runtime
+                    .enter(|| create_endpoint(addr, url, trust, identity.as_ref(), event_sender.clone()))
+                    .map(|connecting| {
+                        runtime.spawn(Connecting::new(connecting, event_sender, queue_config, codec, limits).run());
+                    })
+            }
+
+            ClientTransport::Loopback(connector) => {
+                info!("Connecting to loopback server (attempt {})", attempt);
+
+                connector
+                    .connect()
+                    .map(|end| {
+                        runtime.spawn(run_loopback(end, event_sender, queue_config, codec, limits));
+                    })
+                    .map_err(CreateEndpointError::from)
+            }
+        };
+
+        match started {
+            Ok(()) => {
+                self.status = ConnectionStatus::Connecting { attempt };
+            }
+            Err(err) => {
+                error!("Failed to create an endpoint: {}", err);
+                self.schedule_reconnect(attempt + 1);
+            }
+        }
+    }
+
+    /// Wait before the given attempt, or give up if there have been too many attempts
+    fn schedule_reconnect(&mut self, attempt: u32) {
+        if self.reconnect.max_attempts.map_or(false, |max| attempt > max) {
+            error!("Failed to connect after {} attempts, giving up", attempt - 1);
+            self.status = ConnectionStatus::GaveUp;
+            return;
+        }
+
+        let delay = self.reconnect.delay(attempt);
+        info!("Reconnecting in {:?}", delay);
+        self.status = ConnectionStatus::WaitingToReconnect {
+            attempt,
+            retry_at: Instant::now() + delay,
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct ServerConnectionState {
+    pub event_reader: EventReader<ReceiveEvent>,
+}
+
+/// Track the connection to the server, resume the session after reconnecting and reconnect when the connection is lost
+pub fn server_connection_system(
+    mut state: ResMut<ServerConnectionState>,
+    mut connection: ResMut<ServerConnection>,
+    session: Res<SessionEventListenerState>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut sender: ResMut<Events<SendEvent>>,
+) {
+    for evt in state.event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::Connected(id, _) => {
+                connection.status = ConnectionStatus::Connected(*id);
+            }
+
+            // Start a new session, or resume the previous one
+            ReceiveEvent::HandshakeCompleted { connection: id, .. } => {
+                sender.send(SendEvent::SendPacket {
+                    connection: *id,
+                    data: Arc::new(Packet::Session(Session::Request { resume: connection.session_token })),
+                });
+            }
+
+            ReceiveEvent::ReceivedPacket { data, .. } => {
+                if let Packet::Session(Session::Accepted { token, resumed }) = **data {
+                    info!("Session accepted (resumed: {})", resumed);
+                    connection.session_token = Some(token);
+                }
+            }
+
+            // Reconnect unless the connection was closed on purpose
+            ReceiveEvent::Disconnected { reason, by_peer, .. } => {
+                match (reason.code, *by_peer) {
+                    (DisconnectCode::ProtocolMismatch, _) | (DisconnectCode::AuthenticationFailed, _) | (DisconnectCode::Normal, false) => {
+                        info!("Connection to server closed: {}", reason);
+                        connection.status = ConnectionStatus::Closed(reason.code);
+                    }
+                    _ => {
+                        warn!("Lost connection to server: {}", reason);
+                        connection.schedule_reconnect(1);
+                    }
+                }
+            }
+
+            ReceiveEvent::SocketClosed => {
+                warn!("Lost connection to server");
+                connection.schedule_reconnect(1);
+            }
+
+            // Trying again would only connect to the same (possibly malicious) server
+            ReceiveEvent::NetworkError(NetworkError::CertificateMismatch { host, .. }) => {
+                error!("Refusing to connect to {}, its certificate does not match the pinned fingerprint", host);
+                connection.status = ConnectionStatus::Untrusted;
+            }
+
+            // Connection errors before the connection opened mean the attempt failed
+            ReceiveEvent::NetworkError(NetworkError::ConnectionError(_)) => {
+                if let ConnectionStatus::Connecting { attempt } = connection.status {
+                    connection.schedule_reconnect(attempt + 1);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    if let ConnectionStatus::WaitingToReconnect { attempt, retry_at } = connection.status {
+        if Instant::now() >= retry_at {
+            connection.dial(attempt, session.event_sender.clone());
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+enum CreateEndpointError {
+    #[error(transparent)]
+    EndpointError(#[from] quinn::EndpointError),
+
+    #[error(transparent)]
+    ConnectError(#[from] quinn::ConnectError),
+
+    #[error(transparent)]
+    LoopbackError(#[from] LoopbackError),
+
+    #[error(transparent)]
+    CertificateError(#[from] webpki::Error),
+
+    #[error(transparent)]
+    TLSError(#[from] rustls::TLSError),
+}
+
+fn create_endpoint(
+    addr: &SocketAddr,
+    url: &Url,
+    trust: &ServerTrust,
+    identity: Option<&ClientIdentity>,
+    event_sender: UnboundedSender<ReceiveEvent>,
+) -> Result<quinn::generic::Connecting<TlsSession>, CreateEndpointError>
+{
+    let host = url.host_str().expect("Failed to get host_str from url");
+
+    let mut client_config = ClientConfigBuilder::default();
+    client_config.protocols(ALPN_PROTOCOLS);
+    for anchor in &trust.anchors {
+        client_config.add_certificate_authority(anchor.clone())?;
+    }
+
+    let mut client_config = client_config.build();
+    let tls_cfg: &mut rustls::ClientConfig = Arc::get_mut(&mut client_config.crypto)
+        .expect("Failed to get mutable reference to crypto configuration");
+
+    if let Some(identity) = identity {
+        tls_cfg.set_single_client_cert(identity.certificates.clone(), identity.private_key.clone())?;
+    }
+
+    // this is only available when compiled with "dangerous_configuration" feature
+    if trust.accept_any {
+        tls_cfg
+            .dangerous()
+            .set_certificate_verifier(SkipServerVerification::new());
+    } else {
+        let pinned_as = format!("{}:{}", host, addr.port());
+        tls_cfg
+            .dangerous()
+            .set_certificate_verifier(PinningVerifier::new(trust.known_hosts.clone(), pinned_as, event_sender));
+    }
+
+    let mut endpoint = quinn::Endpoint::builder();
+
+    endpoint.default_client_config(client_config);
+
+    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().expect("Failed to parse bind address"))?;
+    let connecting = endpoint.connect(addr, host)?;
+
+    Ok(connecting)
+}
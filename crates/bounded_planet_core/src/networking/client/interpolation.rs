@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use tracing::warn;
+
+use crate::networking::{
+    events::ReceiveEvent,
+    health::ConnectionHealth,
+    id::{ConnectionId, NetworkEntityId},
+    packets::{ComponentData, Packet, Ping, Replication as ReplicationPacket, timestamp_now},
+    replication::{NetworkTransform, Replicate},
+    systems::NetworkConnections,
+};
+
+/// How much of each new clock sample is blended into the estimated clock offset
+const CLOCK_SMOOTHING: f64 = 0.1;
+
+/// Configures how replicated transforms are interpolated
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationConfig {
+    /// How far behind the server entities are rendered. Must be longer than the interval between server updates
+    /// (plus some jitter), otherwise entities will frequently be extrapolated.
+    pub delay: Duration,
+
+    /// How far past the latest snapshot an entity may be extrapolated when packets are late
+    pub max_extrapolation: Duration,
+
+    /// Maximum number of snapshots buffered per entity
+    pub max_snapshots: usize,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        InterpolationConfig {
+            // The server sends updates at 10Hz, so this leaves room for one late update
+            delay: Duration::from_millis(200),
+            max_extrapolation: Duration::from_millis(250),
+            max_snapshots: 32,
+        }
+    }
+}
+
+/// Add this plugin (after the client `Replication` plugin) to smoothly interpolate the `Transform` of replicated
+/// entities between the snapshots received from the server. The config may be changed at runtime through the
+/// `InterpolationConfig` resource.
+#[derive(Default)]
+pub struct Interpolation {
+    pub config: InterpolationConfig,
+}
+
+impl Plugin for Interpolation {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(self.config);
+        app.init_resource::<ServerClock>();
+        app.init_resource::<InterpolationState>();
+
+        app.add_system(update_server_clock.system());
+        app.add_system(buffer_snapshots.system());
+        app.add_system(interpolate_transforms.system());
+    }
+}
+
+/// This component is attached to replicated entities whose `Transform` is interpolated
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Interpolated;
+
+/// Estimate of the server clock, based on the timestamps in the `Ping` packets sent by the server
+#[derive(Debug, Clone, Default)]
+pub struct ServerClock {
+    /// Estimated difference (in milliseconds) between the server clock and the local clock
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    /// Get the estimated current server time in milliseconds (see `timestamp_now`), if the clock has been synchronised
+    pub fn now(&self) -> Option<f64> {
+        self.offset.map(|offset| timestamp_now() as f64 + offset)
+    }
+
+    /// Get the estimated difference (in milliseconds) between the server clock and the local clock, if the clock has
+    /// been synchronised
+    pub fn offset(&self) -> Option<f64> {
+        self.offset
+    }
+
+    /// Blend a new sample of the server time into the estimate. The server time was sent about half of the round
+    /// trip time ago, so that is added to it when the round trip time is known.
+    pub fn add_sample(&mut self, server_time: u64, rtt: Option<Duration>) {
+        let latency = rtt.map_or(0.0, |rtt| rtt.as_secs_f64() * 1000.0 / 2.0);
+        let sample = server_time as f64 + latency - timestamp_now() as f64;
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (sample - offset) * CLOCK_SMOOTHING,
+            None => sample,
+        });
+    }
+}
+
+/// The state of an entity at a point in server time
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub server_time: u64,
+    pub transform: NetworkTransform,
+}
+
+/// Internal state of the interpolation systems
+#[derive(Default)]
+pub struct InterpolationState {
+    clock_event_reader: EventReader<ReceiveEvent>,
+    snapshot_event_reader: EventReader<ReceiveEvent>,
+
+    /// Snapshots of every replicated entity, oldest first
+    snapshots: HashMap<NetworkEntityId, VecDeque<Snapshot>>,
+
+    /// Time of the latest replication packet received. Updates are only sent for entities which have changed, so an
+    /// entity whose latest snapshot is older than this has not moved since.
+    latest_server_time: u64,
+}
+
+/// Update the estimated server clock from the timestamps of pings sent by the server
+fn update_server_clock(
+    mut state: ResMut<InterpolationState>,
+    mut clock: ResMut<ServerClock>,
+    connections: Res<NetworkConnections>,
+    receiver: Res<Events<ReceiveEvent>>,
+    query: Query<&ConnectionHealth>,
+) {
+    // Smoothed round trip time to the server, `None` until it has been measured
+    let rtt = |connection: &ConnectionId| {
+        let entity = connections.connections.get(connection)?;
+        query.get::<ConnectionHealth>(*entity).ok()?.rtt
+    };
+
+    for evt in state.clock_event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => match **data {
+                Packet::Ping(Ping { timestamp }) => clock.add_sample(timestamp, rtt(connection)),
+
+                // Use replication timestamps until the first ping arrives
+                Packet::Replication(ReplicationPacket::Spawn { server_time, .. })
+                | Packet::Replication(ReplicationPacket::Update { server_time, .. }) if clock.offset.is_none() => {
+                    clock.add_sample(server_time, rtt(connection))
+                }
+
+                _ => {}
+            },
+
+            // A new connection may be to a server with a different clock
+            ReceiveEvent::Disconnected { .. } => clock.offset = None,
+
+            _ => {}
+        }
+    }
+}
+
+/// Store the transform snapshots received from the server
+fn buffer_snapshots(
+    mut state: ResMut<InterpolationState>,
+    config: Res<InterpolationConfig>,
+    receiver: Res<Events<ReceiveEvent>>,
+) {
+    // Break up `state` in a way that Rust is happy with
+    let state: &mut InterpolationState = &mut state;
+    let InterpolationState { snapshot_event_reader, snapshots, latest_server_time, .. } = state;
+
+    for evt in snapshot_event_reader.iter(&receiver) {
+        let packet = match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } => match &**data {
+                Packet::Replication(packet) => packet,
+                _ => continue,
+            },
+
+            ReceiveEvent::Disconnected { .. } => {
+                snapshots.clear();
+                *latest_server_time = 0;
+                continue;
+            }
+
+            _ => continue,
+        };
+
+        let (entity, components, server_time) = match packet {
+            ReplicationPacket::Spawn { entity, components, server_time } => (entity, components, server_time),
+            ReplicationPacket::Update { entity, changed, server_time, .. } => (entity, changed, server_time),
+            ReplicationPacket::Despawn { entity } => {
+                snapshots.remove(entity);
+                continue;
+            }
+        };
+
+        *latest_server_time = (*latest_server_time).max(*server_time);
+
+        let transform = match decode_transform(components) {
+            Some(transform) => transform,
+            None => continue,
+        };
+
+        // Insert the snapshot in time order, updates are ordered so this is almost always at the back
+        let buffer = snapshots.entry(*entity).or_default();
+        let index = buffer.iter().rposition(|s| s.server_time <= *server_time).map_or(0, |i| i + 1);
+        buffer.insert(index, Snapshot { server_time: *server_time, transform });
+
+        while buffer.len() > config.max_snapshots {
+            buffer.pop_front();
+        }
+    }
+}
+
+/// Find and decode the `NetworkTransform` in a list of replicated components
+fn decode_transform(components: &[ComponentData]) -> Option<NetworkTransform> {
+    let component = components.iter().find(|c| c.kind == NetworkTransform::KIND)?;
+
+    match rmp_serde::from_read_ref(&component.data) {
+        Ok(transform) => Some(transform),
+        Err(err) => {
+            warn!("Failed to decode transform snapshot: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Set the `Transform` of every replicated entity to its interpolated state at the current render time
+fn interpolate_transforms(
+    mut commands: Commands,
+    mut state: ResMut<InterpolationState>,
+    config: Res<InterpolationConfig>,
+    clock: Res<ServerClock>,
+    mut query: Query<(Entity, &NetworkEntityId, &mut Transform, Option<&Interpolated>)>,
+) {
+    // Nothing can be interpolated until the server clock is known
+    let render_time = match clock.now() {
+        Some(now) => now - config.delay.as_secs_f64() * 1000.0,
+        None => return,
+    };
+    let max_extrapolation = config.max_extrapolation.as_secs_f64() * 1000.0;
+    let latest_server_time = state.latest_server_time;
+
+    for (entity, id, mut transform, interpolated) in &mut query.iter() {
+        let buffer = match state.snapshots.get_mut(id) {
+            Some(buffer) if !buffer.is_empty() => buffer,
+            _ => continue,
+        };
+
+        // Discard snapshots which are too old to ever be used again, keeping one before the render time
+        while buffer.len() > 2 && buffer[1].server_time as f64 <= render_time {
+            buffer.pop_front();
+        }
+
+        // Only extrapolate entities which may have moved since their latest snapshot
+        let last = buffer[buffer.len() - 1].server_time;
+        let max_extrapolation = if last < latest_server_time { 0.0 } else { max_extrapolation };
+
+        *transform = sample(buffer, render_time, max_extrapolation);
+
+        if interpolated.is_none() {
+            commands.insert_one(entity, Interpolated);
+        }
+    }
+}
+
+/// Get the transform at the given time (in milliseconds of server time) from a non-empty, time ordered buffer of
+/// snapshots, extrapolating up to `max_extrapolation` milliseconds past the latest snapshot
+pub fn sample(buffer: &VecDeque<Snapshot>, time: f64, max_extrapolation: f64) -> Transform {
+    let first = &buffer[0];
+    if buffer.len() == 1 || time <= first.server_time as f64 {
+        return Transform::from(&first.transform);
+    }
+
+    // Find the pair of snapshots either side of the render time. When the render time is past the latest snapshot
+    // (because packets are late) the last two snapshots are used to extrapolate, for a limited time. Once a newer
+    // snapshot arrives (or extrapolation runs out) the entity is reconciled with the latest state sent by the server.
+    let next = buffer.iter().position(|s| s.server_time as f64 >= time).unwrap_or(buffer.len() - 1).max(1);
+    let from = &buffer[next - 1];
+    let to = &buffer[next];
+
+    if time > to.server_time as f64 + max_extrapolation {
+        return Transform::from(&to.transform);
+    }
+
+    let span = (to.server_time - from.server_time) as f64;
+    if span <= 0.0 {
+        return Transform::from(&to.transform);
+    }
+    let t = ((time - from.server_time as f64) / span) as f32;
+
+    lerp(&from.transform, &to.transform, t)
+}
+
+/// Interpolate (or extrapolate, when `t` is greater than 1) between two transforms
+fn lerp(from: &NetworkTransform, to: &NetworkTransform, t: f32) -> Transform {
+    let translation = Vec3::from(from.translation).lerp(Vec3::from(to.translation), t);
+    let scale = Vec3::from(from.scale).lerp(Vec3::from(to.scale), t);
+
+    // Rotations are not extrapolated, an extrapolated rotation can easily overshoot into something nonsensical
+    let from_rotation: Quat = Vec4::from(from.rotation).into();
+    let to_rotation: Quat = Vec4::from(to.rotation).into();
+    let rotation = from_rotation.lerp(to_rotation, t.min(1.0));
+
+    Transform::new(Mat4::from_scale_rotation_translation(scale, rotation, translation))
+}
@@ -1 +1,4 @@
 pub mod plugin;
+pub mod connection;
+pub mod replication;
+pub mod interpolation;
@@ -1,36 +1,69 @@
-use std::{net::SocketAddr, sync::Arc};
-
-use thiserror::Error;
 use bevy::prelude::{AppBuilder, Plugin, IntoQuerySystem};
-use quinn::{
-    ClientConfigBuilder,
-    crypto::rustls::TlsSession,
-};
 use tokio::sync::mpsc::unbounded_channel;
-use url::Url;
 
 use crate::networking::{
-    crypto::SkipServerVerification,
+    codec::CodecConfig,
     events::{
         ReceiveEvent,
         SendEvent
     },
+    groups::{
+        NetworkGroups,
+        update_network_groups_system
+    },
+    handshake::{
+        HandshakeState,
+        handshake_system
+    },
+    health::{
+        HealthConfig,
+        HealthState,
+        connection_health_system
+    },
+    queue::QueueConfig,
+    rpc::{
+        Requests,
+        ResponseEvent,
+        rpc_system
+    },
+    stats::{
+        NetworkStats,
+        update_connection_stats_system
+    },
     systems::{
-        Connecting,
         NetworkConnections,
         SessionEventListenerState,
         receive_net_events_system,
         send_net_events_system,
+        update_queue_depths_system,
         SEND_NET_EVENT_STAGE,
         RECEIVE_NET_EVENT_STAGE
     }
 };
 
+use super::connection::{
+    ClientTransport,
+    ReconnectConfig,
+    ServerConnection,
+    ServerConnectionState,
+    server_connection_system
+};
+
 pub struct Network {
-    pub addr: SocketAddr,
-    pub url: Url,
-    pub cert: quinn::Certificate,
-    pub accept_any_cert : bool,
+    /// How to connect to the server
+    pub transport: ClientTransport,
+
+    /// Sizes and overflow policy of the send queues to the server
+    pub queue_config: QueueConfig,
+
+    /// How packets are encoded, and the limits on packets received from the server
+    pub codec: CodecConfig,
+
+    /// How the health of each connection is monitored
+    pub health_config: HealthConfig,
+
+    /// How the client reconnects when the connection to the server is lost
+    pub reconnect: ReconnectConfig,
 }
 
 impl Plugin for Network {
@@ -42,6 +75,10 @@ impl Plugin for Network {
             event_receiver: recv,
             stream_senders: Default::default(),
             send_event_reader: Default::default(),
+            require_auth: false,
+            unauthenticated: Default::default(),
+            resumable: Default::default(),
+            aliases: Default::default(),
         });
 
         app.init_resource::<NetworkConnections>();
@@ -50,60 +87,42 @@ impl Plugin for Network {
         app.add_event::<ReceiveEvent>();
         app.add_event::<SendEvent>();
 
-        // Start a task that waits for the connection to finish opening
-        tokio::spawn(
-            Connecting::new(
-                create_endpoint(&self.addr, &self.url, &self.cert, self.accept_any_cert).expect("Failed to create an endpoint"),
-                send
-            ).run()
-        );
+        // Start connecting to the server. If the connection is lost (or fails to open) it is dialed again.
+        let mut connection = ServerConnection::new(self.transport.clone(), self.queue_config, self.codec.clone(), self.reconnect);
+        connection.dial(1, send);
+        app.add_resource(connection);
+        app.init_resource::<ServerConnectionState>();
+        app.add_system(server_connection_system.system());
 
         // Add a system that consumes all network events from an MPSC and publishes them as ECS events
         app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, receive_net_events_system.system());
 
-        // Add a system that consumes ECS events and forwards them to MPSCs which will eventually be sent over the network
-        app.add_system_to_stage(SEND_NET_EVENT_STAGE, send_net_events_system.system());
-    }
-}
+        // Add a system that collects the connections in each group, just before broadcasts to groups are sent
+        app.init_resource::<NetworkGroups>();
+        app.add_system_to_stage(SEND_NET_EVENT_STAGE, update_network_groups_system.system());
 
-#[derive(Error, Debug)]
-enum CreateEndpointError {
-    #[error(transparent)]
-    EndpointError(#[from] quinn::EndpointError),
+        // Add a system that consumes ECS events and forwards them to send queues which will eventually be sent over the network
+        app.add_system_to_stage(SEND_NET_EVENT_STAGE, send_net_events_system.system());
 
-    #[error(transparent)]
-    ConnectError(#[from] quinn::ConnectError)
-}
+        // Add a system that publishes the depth of the send queues to the ECS
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, update_queue_depths_system.system());
 
-fn create_endpoint(
-    addr: &SocketAddr,
-    url: &Url,
-    server_cert: &quinn::Certificate,
-    accept_any_cert: bool
-) -> Result<quinn::generic::Connecting<TlsSession>, CreateEndpointError>
-{
-    let mut client_config = ClientConfigBuilder::default();
-    client_config.protocols(&[b"hq-29"]);
-    
-    let mut client_config = client_config.build();
-    if accept_any_cert {
-        let tls_cfg: &mut rustls::ClientConfig = Arc::get_mut(&mut client_config.crypto)
-            .expect("Failed to get mutable reference to crypto configuration");
-        
-        // this is only available when compiled with "dangerous_configuration" feature
-        tls_cfg
-            .dangerous()
-            .set_certificate_verifier(SkipServerVerification::new());
-    } else {
-        client_config.add_certificate_authority(server_cert.clone()).expect("Adding cert failed");
-    }
+        // Add a system that publishes the traffic through each connection, and the totals, to the ECS
+        app.init_resource::<NetworkStats>();
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, update_connection_stats_system.system());
 
-    let mut endpoint = quinn::Endpoint::builder();
-    
-    endpoint.default_client_config(client_config);
+        // Add a system that exchanges protocol versions with every new connection
+        app.init_resource::<HandshakeState>();
+        app.add_system(handshake_system.system());
 
-    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().expect("Failed to parse bind address"))?;
-    let connecting = endpoint.connect(addr, &url.host_str().expect("Failed to get host_str from url"))?;
+        // Add a system that pings every connection and tracks its health
+        app.add_resource(self.health_config);
+        app.init_resource::<HealthState>();
+        app.add_system(connection_health_system.system());
 
-    Ok(connecting)
+        // Add a system that matches responses to the requests they answer, and times out unanswered requests
+        app.init_resource::<Requests>();
+        app.add_event::<ResponseEvent>();
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, rpc_system.system());
+    }
 }
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use tracing::{error, warn};
+
+use super::interpolation::Interpolated;
+use crate::networking::{
+    events::ReceiveEvent,
+    id::NetworkEntityId,
+    packets::{ComponentData, Packet, Replication as ReplicationPacket},
+    replication::{AppReplicationExt, NetworkTransform, Replicate},
+};
+
+/// Add this plugin (after the client `Network` plugin) to mirror entities replicated by the server into the ECS.
+/// Register replicated components with `app.replicate::<T>()`, `NetworkTransform` is always replicated.
+pub struct Replication;
+
+impl Plugin for Replication {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<ReplicationClientState>();
+
+        app.add_system(apply_replication.system());
+
+        // Give replicated entities a `Transform` which follows their `NetworkTransform`
+        app.add_system(insert_transforms.system());
+        app.add_system(apply_network_transforms.system());
+
+        app.replicate::<NetworkTransform>();
+    }
+}
+
+/// Functions to add and remove a single type of replicated component
+struct ComponentApplier {
+    insert: fn(&mut Commands, Entity, &[u8]) -> Result<(), rmp_serde::decode::Error>,
+    remove: fn(&mut Commands, Entity),
+}
+
+/// Internal state of the client replication systems
+#[derive(Default)]
+pub struct ReplicationClientState {
+    event_reader: EventReader<ReceiveEvent>,
+
+    /// Map from network IDs to the local entity mirroring them
+    entities: HashMap<NetworkEntityId, Entity>,
+
+    /// Map from component kinds to the functions which apply them
+    appliers: HashMap<&'static str, ComponentApplier>,
+}
+
+impl ReplicationClientState {
+    /// Start applying replicated components of type `T`
+    pub(crate) fn register<T: Replicate>(&mut self) {
+        self.appliers.insert(T::KIND, ComponentApplier {
+            insert: insert_component::<T>,
+            remove: remove_component::<T>,
+        });
+    }
+
+    /// Get the local entity mirroring a replicated entity
+    pub fn entity(&self, id: NetworkEntityId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+}
+
+/// Add or replace components on a local entity
+fn insert_components(
+    appliers: &HashMap<&'static str, ComponentApplier>,
+    commands: &mut Commands,
+    entity: Entity,
+    components: &[ComponentData],
+) {
+    for component in components {
+        match appliers.get(component.kind.as_str()) {
+            Some(applier) => {
+                if let Err(err) = (applier.insert)(commands, entity, &component.data) {
+                    error!("Failed to decode replicated component {}: {:?}", component.kind, err);
+                }
+            }
+            None => warn!("Received unknown replicated component: {}", component.kind),
+        }
+    }
+}
+
+fn insert_component<T: Replicate>(commands: &mut Commands, entity: Entity, data: &[u8]) -> Result<(), rmp_serde::decode::Error> {
+    let component: T = rmp_serde::from_read_ref(data)?;
+    commands.insert_one(entity, component);
+    Ok(())
+}
+
+fn remove_component<T: Replicate>(commands: &mut Commands, entity: Entity) {
+    commands.remove_one::<T>(entity);
+}
+
+/// Apply replication packets from the server to the local mirror of each replicated entity
+fn apply_replication(
+    mut commands: Commands,
+    mut state: ResMut<ReplicationClientState>,
+    receiver: Res<Events<ReceiveEvent>>,
+) {
+    // Break up `state` in a way that Rust is happy with
+    let state: &mut ReplicationClientState = &mut state;
+    let ReplicationClientState { event_reader, entities, appliers } = state;
+
+    for evt in event_reader.iter(&receiver) {
+        let packet = match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } => match &**data {
+                Packet::Replication(packet) => packet,
+                _ => continue,
+            },
+
+            // The server has gone away, so everything it replicated is gone
+            ReceiveEvent::Disconnected { .. } => {
+                for (_, e) in entities.drain() {
+                    commands.despawn(e);
+                }
+                continue;
+            }
+
+            _ => continue,
+        };
+
+        match packet {
+            ReplicationPacket::Spawn { entity, components, .. } => {
+                let e = match entities.get(entity) {
+                    Some(e) => *e,
+                    None => {
+                        commands.spawn((*entity,));
+                        let e = commands.current_entity().expect("`spawn` did not create an entity");
+                        entities.insert(*entity, e);
+                        e
+                    }
+                };
+                insert_components(appliers, &mut commands, e, components);
+            }
+
+            ReplicationPacket::Update { entity, changed, removed, .. } => {
+                let e = match entities.get(entity) {
+                    Some(e) => *e,
+                    None => {
+                        warn!("Received update for unknown replicated entity {:?}", entity);
+                        continue;
+                    }
+                };
+
+                insert_components(appliers, &mut commands, e, changed);
+                for kind in removed {
+                    if let Some(applier) = appliers.get(kind.as_str()) {
+                        (applier.remove)(&mut commands, e);
+                    }
+                }
+            }
+
+            ReplicationPacket::Despawn { entity } => {
+                if let Some(e) = entities.remove(entity) {
+                    commands.despawn(e);
+                }
+            }
+        }
+    }
+}
+
+/// Add a `Transform` to replicated entities which have a `NetworkTransform` but no `Transform`
+fn insert_transforms(
+    mut commands: Commands,
+    mut query: Query<Without<Transform, (Entity, &NetworkTransform)>>,
+) {
+    for (entity, network) in &mut query.iter() {
+        let transform = Transform::from(network);
+        commands.insert(entity, (GlobalTransform::new(*transform.value()), transform));
+    }
+}
+
+/// Copy the `NetworkTransform` of replicated entities into their `Transform`, unless the entity is interpolated
+fn apply_network_transforms(mut query: Query<Without<Interpolated, (&NetworkTransform, &mut Transform)>>) {
+    for (network, mut transform) in &mut query.iter() {
+        *transform = Transform::from(network);
+    }
+}
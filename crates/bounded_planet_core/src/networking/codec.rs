@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{Read, Write},
+};
+
+use bincode::Options;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+
+use super::{
+    packets::{Packet, StreamType},
+    serialization::{RecvError, SendError},
+};
+
+/// Set in the header byte of a frame if the payload is compressed
+const COMPRESSED: u8 = 0x80;
+
+/// Stream whose packets are sent without a header byte, see `CodecConfig::encode`
+const PLAIN_STREAM: StreamType = StreamType::Handshake;
+
+/// Serialization format used to encode packets. Every frame records the format it was encoded with, so peers may
+/// use different formats.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+#[repr(u8)]
+pub enum Format {
+    MessagePack = 0,
+
+    /// Bincode with variable length integers, smaller than MessagePack since field names and types are not encoded
+    Bincode = 1,
+}
+
+impl TryFrom<u8> for Format {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Format::MessagePack),
+            1 => Ok(Format::Bincode),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Compression applied to the packets sent on a stream
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum Compression {
+    None,
+
+    /// Zlib compression with the given level (0-9)
+    Zlib(u32),
+}
+
+/// Configures how packets are encoded into frames, and the limits on frames received from peers
+#[derive(Debug, Clone)]
+pub struct CodecConfig {
+    /// Format used to encode packets sent by this end
+    pub format: Format,
+
+    /// Maximum size (in bytes) of a frame, both before and after decompression. Larger frames are refused when
+    /// sending, and close the stream they arrive on when receiving.
+    pub max_frame_size: usize,
+
+    /// Compression used for packets sent to each stream. Streams which are not listed are not compressed.
+    pub compression: HashMap<StreamType, Compression>,
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        let mut compression = HashMap::new();
+
+        // World tiles are large and compress well
+        compression.insert(StreamType::WorldTileData, Compression::Zlib(6));
+
+        CodecConfig {
+            format: Format::MessagePack,
+            max_frame_size: 16 * 1024 * 1024,
+            compression,
+        }
+    }
+}
+
+impl CodecConfig {
+    /// Get the compression used for packets sent to a stream
+    pub fn compression(&self, stream: StreamType) -> Compression {
+        self.compression.get(&stream).copied().unwrap_or(Compression::None)
+    }
+
+    /// Encode a packet into a frame: a header byte identifying the format and compression, followed by the payload.
+    ///
+    /// Packets on the handshake stream are plain MessagePack with no header byte, however the codec is configured.
+    /// Handshakes are exchanged before either end knows which protocol version the other speaks, so they are encoded
+    /// in the way every protocol version has encoded them. That way a peer running any other version can still decode
+    /// the handshake and report the version mismatch.
+    pub fn encode(&self, packet: &Packet, stream: StreamType) -> Result<Vec<u8>, SendError> {
+        if stream == PLAIN_STREAM {
+            let frame = rmp_serde::to_vec(packet).map_err(SendError::EncodeError)?;
+            return self.check_size(frame);
+        }
+
+        let mut frame = vec![self.format as u8];
+        match self.format {
+            Format::MessagePack => rmp_serde::encode::write(&mut frame, packet).map_err(SendError::EncodeError)?,
+            Format::Bincode => bincode_options().serialize_into(&mut frame, packet).map_err(SendError::BincodeEncodeError)?,
+        }
+
+        if let Compression::Zlib(level) = self.compression(stream) {
+            let mut encoder = ZlibEncoder::new(vec![self.format as u8 | COMPRESSED], flate2::Compression::new(level));
+            encoder.write_all(&frame[1..]).map_err(SendError::CompressError)?;
+            frame = encoder.finish().map_err(SendError::CompressError)?;
+        }
+
+        self.check_size(frame)
+    }
+
+    /// Refuse frames which the receiver would refuse
+    fn check_size(&self, frame: Vec<u8>) -> Result<Vec<u8>, SendError> {
+        if frame.len() > self.max_frame_size {
+            return Err(SendError::FrameTooLarge {
+                size: frame.len(),
+                max: self.max_frame_size,
+            });
+        }
+
+        Ok(frame)
+    }
+
+    /// Decode a frame received on a stream, which was encoded with `encode` in any format
+    pub fn decode(&self, frame: &[u8], stream: StreamType) -> Result<Packet, RecvError> {
+        if stream == PLAIN_STREAM {
+            return rmp_serde::from_read_ref(frame).map_err(RecvError::DecodeError);
+        }
+
+        let (header, payload) = match frame.split_first() {
+            Some(split) => split,
+            None => return Err(RecvError::EmptyFrame),
+        };
+        let format = Format::try_from(header & !COMPRESSED).map_err(RecvError::UnknownFormat)?;
+
+        // Decompress at most one byte more than the limit, enough to tell that the limit was exceeded
+        let decompressed;
+        let payload = if header & COMPRESSED != 0 {
+            let mut data = Vec::new();
+            ZlibDecoder::new(payload)
+                .take(self.max_frame_size as u64 + 1)
+                .read_to_end(&mut data)
+                .map_err(RecvError::DecompressError)?;
+
+            if data.len() > self.max_frame_size {
+                return Err(RecvError::FrameTooLarge {
+                    size: data.len(),
+                    max: self.max_frame_size,
+                });
+            }
+
+            decompressed = data;
+            decompressed.as_slice()
+        } else {
+            payload
+        };
+
+        match format {
+            Format::MessagePack => rmp_serde::from_read_ref(payload).map_err(RecvError::DecodeError),
+            Format::Bincode => bincode_options()
+                .with_limit(self.max_frame_size as u64)
+                .deserialize(payload)
+                .map_err(RecvError::BincodeDecodeError),
+        }
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
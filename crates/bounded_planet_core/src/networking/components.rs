@@ -1,7 +1,24 @@
-use super::id::ConnectionId;
+use serde::{Deserialize, Serialize};
+
+use super::{id::ConnectionId, queue::QueueDepth};
 
 /// This component represents a network connection with the given ID
 #[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Connection {
     pub id: ConnectionId
 }
+
+/// This component holds the number of items waiting in the send queues of a `Connection`, updated every frame
+#[derive(Debug, Clone, Default)]
+pub struct SendQueueDepth(pub QueueDepth);
+
+/// The certificate a peer authenticated with, added to its `Connection` entity when the connection opens. A server
+/// only sees this for clients when it requires client certificates (see `crypto::ClientAuth`).
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    /// SHA-256 fingerprint of the certificate, see `crypto::fingerprint`
+    pub fingerprint: String,
+
+    /// The certificate, DER encoded
+    pub certificate: Vec<u8>,
+}
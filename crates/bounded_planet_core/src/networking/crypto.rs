@@ -1,4 +1,17 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use ring::digest;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+use super::events::{NetworkError, ReceiveEvent};
 
 pub struct SkipServerVerification;
 
@@ -19,3 +32,386 @@ impl rustls::ServerCertVerifier for SkipServerVerification {
         Ok(rustls::ServerCertVerified::assertion())
     }
 }
+
+#[derive(Debug, Error)]
+pub enum CertificateError {
+    #[error("Failed to access {path:?}: {err}")]
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+
+    #[error("Failed to parse {path:?}: {err}")]
+    Parse {
+        path: PathBuf,
+        err: quinn::ParseError,
+    },
+
+    #[error("No certificates found in {0:?}")]
+    NoCertificates(PathBuf),
+
+    #[error("No private key found in {0:?}")]
+    NoPrivateKey(PathBuf),
+
+    #[error("Invalid certificate authority: {0:?}")]
+    InvalidAuthority(webpki::Error),
+
+    #[error("Failed to generate certificate: {0}")]
+    Generate(#[from] rcgen::RcgenError),
+}
+
+/// Check if a file is in PEM format (as opposed to DER)
+fn is_pem(data: &[u8]) -> bool {
+    data.starts_with(b"-----BEGIN")
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, CertificateError> {
+    fs::read(path).map_err(|err| CertificateError::Io { path: path.to_owned(), err })
+}
+
+/// Read every certificate in a PEM or DER file
+fn read_certificates(path: &Path) -> Result<Vec<rustls::Certificate>, CertificateError> {
+    let data = read(path)?;
+    let certs = if is_pem(&data) {
+        rustls::internal::pemfile::certs(&mut BufReader::new(data.as_slice()))
+            .map_err(|_| CertificateError::NoCertificates(path.to_owned()))?
+    } else {
+        vec![rustls::Certificate(data)]
+    };
+
+    if certs.is_empty() {
+        return Err(CertificateError::NoCertificates(path.to_owned()));
+    }
+
+    Ok(certs)
+}
+
+/// Load every certificate in a PEM or DER file
+pub fn load_certificates(path: &Path) -> Result<Vec<quinn::Certificate>, CertificateError> {
+    read_certificates(path)?
+        .iter()
+        .map(|cert| quinn::Certificate::from_der(&cert.0).map_err(|err| CertificateError::Parse { path: path.to_owned(), err }))
+        .collect()
+}
+
+/// Load a certificate chain from a PEM or DER file
+pub fn load_certificate_chain(path: &Path) -> Result<quinn::CertificateChain, CertificateError> {
+    Ok(quinn::CertificateChain::from_certs(load_certificates(path)?))
+}
+
+/// Load a private key from a PEM or DER file
+pub fn load_private_key(path: &Path) -> Result<quinn::PrivateKey, CertificateError> {
+    let data = read(path)?;
+    let key = if is_pem(&data) {
+        quinn::PrivateKey::from_pem(&data)
+    } else {
+        quinn::PrivateKey::from_der(&data)
+    };
+
+    key.map_err(|err| CertificateError::Parse { path: path.to_owned(), err })
+}
+
+/// Read a PKCS#8 or RSA private key from a PEM or DER file
+fn read_private_key(path: &Path) -> Result<rustls::PrivateKey, CertificateError> {
+    let data = read(path)?;
+    if !is_pem(&data) {
+        return Ok(rustls::PrivateKey(data));
+    }
+
+    let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut BufReader::new(data.as_slice())).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rustls::internal::pemfile::rsa_private_keys(&mut BufReader::new(data.as_slice())).unwrap_or_default();
+    }
+
+    keys.pop().ok_or_else(|| CertificateError::NoPrivateKey(path.to_owned()))
+}
+
+/// Get the SHA-256 fingerprint of a DER encoded certificate, as a hex string
+pub fn fingerprint(cert: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, cert))
+}
+
+/// A self-signed certificate and its private key, DER encoded
+pub struct SelfSigned {
+    pub key: Vec<u8>,
+    pub cert: Vec<u8>,
+
+    /// The same key and certificate, PEM encoded
+    key_pem: String,
+    cert_pem: String,
+}
+
+impl SelfSigned {
+    /// Generate a new key and a certificate for the given host names
+    pub fn generate(names: Vec<String>) -> Result<Self, CertificateError> {
+        let cert = rcgen::generate_simple_self_signed(names)?;
+
+        Ok(SelfSigned {
+            key: cert.serialize_private_key_der(),
+            cert: cert.serialize_der()?,
+            key_pem: cert.serialize_private_key_pem(),
+            cert_pem: cert.serialize_pem()?,
+        })
+    }
+
+    pub fn private_key(&self) -> Result<quinn::PrivateKey, quinn::ParseError> {
+        quinn::PrivateKey::from_der(&self.key)
+    }
+
+    pub fn certificate_chain(&self) -> Result<quinn::CertificateChain, quinn::ParseError> {
+        Ok(quinn::CertificateChain::from_certs(Some(quinn::Certificate::from_der(&self.cert)?)))
+    }
+
+    /// Write the key and certificate to PEM files
+    pub fn save(&self, key_path: &Path, cert_path: &Path) -> Result<(), CertificateError> {
+        let write = |path: &Path, pem: &str| {
+            fs::write(path, pem).map_err(|err| CertificateError::Io { path: path.to_owned(), err })
+        };
+
+        write(key_path, &self.key_pem)?;
+        write(cert_path, &self.cert_pem)
+    }
+}
+
+/// Fingerprints of the certificates of previously seen servers, stored in a file with one `host fingerprint` entry
+/// per line
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Mutex<HashMap<String, String>>,
+}
+
+impl KnownHosts {
+    /// Load known hosts from a file. A file which does not exist is treated as empty, and created when the first
+    /// host is added.
+    pub fn load(path: &Path) -> Result<Self, CertificateError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(CertificateError::Io { path: path.to_owned(), err }),
+        };
+
+        let hosts = contents
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some(host), Some(fingerprint)) => Some((host.to_owned(), fingerprint.to_lowercase())),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Ok(KnownHosts {
+            path: path.to_owned(),
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    /// Get the pinned fingerprint of a host
+    pub fn get(&self, host: &str) -> Option<String> {
+        self.hosts.lock().expect("Known hosts lock poisoned").get(host).cloned()
+    }
+
+    /// Pin the fingerprint of a host which has not been seen before, and append it to the file
+    pub fn pin(&self, host: &str, fingerprint: &str) -> Result<(), CertificateError> {
+        self.hosts
+            .lock()
+            .expect("Known hosts lock poisoned")
+            .insert(host.to_owned(), fingerprint.to_owned());
+
+        let io_err = |err| CertificateError::Io { path: self.path.clone(), err };
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(io_err)?;
+        writeln!(file, "{} {}", host, fingerprint).map_err(io_err)
+    }
+}
+
+/// Verifies the server's certificate against the trust anchors and, if it isn't signed by one of them, against the
+/// fingerprint pinned in the known hosts the first time the server was seen
+pub struct PinningVerifier {
+    webpki: rustls::WebPKIVerifier,
+    known_hosts: Option<Arc<KnownHosts>>,
+
+    /// Identifies the server in the known hosts, as `host:port`
+    host: String,
+
+    /// Mismatched certificates are reported through this as well as failing the connection
+    event_sender: UnboundedSender<ReceiveEvent>,
+}
+
+impl PinningVerifier {
+    pub fn new(known_hosts: Option<Arc<KnownHosts>>, host: String, event_sender: UnboundedSender<ReceiveEvent>) -> Arc<Self> {
+        Arc::new(PinningVerifier {
+            webpki: rustls::WebPKIVerifier::new(),
+            known_hosts,
+            host,
+            event_sender,
+        })
+    }
+}
+
+impl rustls::ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        // A certificate signed by a trust anchor is always accepted
+        let anchor_err = match self.webpki.verify_server_cert(roots, presented_certs, dns_name, ocsp_response) {
+            Ok(verified) => return Ok(verified),
+            Err(err) => err,
+        };
+
+        let (known_hosts, cert) = match (&self.known_hosts, presented_certs.first()) {
+            (Some(known_hosts), Some(cert)) => (known_hosts, cert),
+            _ => return Err(anchor_err),
+        };
+
+        let presented = fingerprint(&cert.0);
+        match known_hosts.get(&self.host) {
+            Some(expected) if expected == presented => Ok(rustls::ServerCertVerified::assertion()),
+
+            Some(expected) => {
+                warn!("Certificate of {} does not match the pinned fingerprint", self.host);
+                let _ = self.event_sender.send(ReceiveEvent::NetworkError(NetworkError::CertificateMismatch {
+                    host: self.host.clone(),
+                    expected,
+                    presented,
+                }));
+                Err(rustls::TLSError::General(format!("Certificate of {} does not match the pinned fingerprint", self.host)))
+            }
+
+            // Trust on first use
+            None => {
+                info!("Pinning certificate of {}: {}", self.host, presented);
+                if let Err(err) = known_hosts.pin(&self.host, &presented) {
+                    warn!("Failed to save known hosts: {}", err);
+                }
+                Ok(rustls::ServerCertVerified::assertion())
+            }
+        }
+    }
+}
+
+/// Configures which clients a private server admits. Clients must present a certificate which is either signed by
+/// one of the `authorities`, or whose fingerprint is in `allowed`.
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuth {
+    /// Certificates (usually CAs) trusted to sign client certificates
+    pub authorities: Vec<rustls::Certificate>,
+
+    /// Fingerprints of client certificates which are accepted whoever signed them, see `fingerprint`
+    pub allowed: HashSet<String>,
+}
+
+impl ClientAuth {
+    /// Trust every certificate in a PEM or DER file to sign client certificates
+    pub fn load_authorities(&mut self, path: &Path) -> Result<(), CertificateError> {
+        self.authorities.extend(read_certificates(path)?);
+        Ok(())
+    }
+
+    /// Allow every fingerprint in a file with one fingerprint per line. Blank lines and lines starting with `#` are
+    /// ignored.
+    pub fn load_allowed(&mut self, path: &Path) -> Result<(), CertificateError> {
+        let contents = fs::read_to_string(path).map_err(|err| CertificateError::Io { path: path.to_owned(), err })?;
+
+        let allowed = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase);
+
+        self.allowed.extend(allowed);
+        Ok(())
+    }
+
+    /// Create a verifier which enforces this configuration
+    pub fn verifier(&self) -> Result<Arc<ClientAuthVerifier>, CertificateError> {
+        let mut roots = rustls::RootCertStore::empty();
+        for authority in &self.authorities {
+            roots.add(authority).map_err(CertificateError::InvalidAuthority)?;
+        }
+
+        Ok(Arc::new(ClientAuthVerifier {
+            subjects: roots.get_subjects(),
+            webpki: if self.authorities.is_empty() { None } else { Some(rustls::AllowAnyAuthenticatedClient::new(roots)) },
+            allowed: self.allowed.clone(),
+        }))
+    }
+}
+
+/// Requires every client to present a certificate accepted by a `ClientAuth`
+pub struct ClientAuthVerifier {
+    /// Verifies certificates against the authorities, or `None` if there are no authorities
+    webpki: Option<Arc<dyn rustls::ClientCertVerifier>>,
+
+    /// Names of the authorities, sent to clients so they can choose a certificate
+    subjects: rustls::DistinguishedNames,
+
+    allowed: HashSet<String>,
+}
+
+impl rustls::ClientCertVerifier for ClientAuthVerifier {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(true)
+    }
+
+    fn client_auth_root_subjects(&self, _sni: Option<&webpki::DNSName>) -> Option<rustls::DistinguishedNames> {
+        Some(self.subjects.clone())
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[rustls::Certificate],
+        sni: Option<&webpki::DNSName>,
+    ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        // An allowed certificate is accepted even if it is self-signed
+        if let Some(cert) = presented_certs.first() {
+            if self.allowed.contains(&fingerprint(&cert.0)) {
+                return Ok(rustls::ClientCertVerified::assertion());
+            }
+        }
+
+        match &self.webpki {
+            Some(webpki) => webpki.verify_client_cert(presented_certs, sni),
+            None => Err(rustls::TLSError::General("Client certificate is not allowed".to_owned())),
+        }
+    }
+}
+
+/// A certificate chain and private key which a client presents to servers which require client certificates
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub certificates: Vec<rustls::Certificate>,
+    pub private_key: rustls::PrivateKey,
+}
+
+impl ClientIdentity {
+    /// Load a certificate chain and private key from PEM or DER files
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, CertificateError> {
+        Ok(ClientIdentity {
+            certificates: read_certificates(cert_path)?,
+            private_key: read_private_key(key_path)?,
+        })
+    }
+}
+
+impl From<&SelfSigned> for ClientIdentity {
+    fn from(generated: &SelfSigned) -> Self {
+        ClientIdentity {
+            certificates: vec![rustls::Certificate(generated.cert.clone())],
+            private_key: rustls::PrivateKey(generated.key.clone()),
+        }
+    }
+}
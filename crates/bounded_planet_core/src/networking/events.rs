@@ -1,14 +1,17 @@
-use std::sync::Arc;
+use std::{collections::HashSet, fmt, sync::Arc, time::Duration};
 
 use crate::networking::{id::ConnectionId, packets::Packet};
-use quinn::{ConnectionError, crypto::rustls::TlsSession, generic::SendStream};
-use tokio::sync::mpsc::UnboundedSender;
 
 use super::{
+    components::PeerIdentity,
+    handshake::HandshakeRejection,
     packets::StreamType,
+    queue::{OverflowPolicy, SendQueue},
+    rate_limit::{Violation, ViolationPolicy},
     serialization::{RecvError, SendError}
 };
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -29,12 +32,34 @@ pub enum NetworkError
         err: SendError,
     },
 
-    /// An error occurred attempting to send a packet through the MPSC to the async packet sender loop
-    #[error("MPSC to packet sender closed for stream {stream:?} of connection {connection:?}. Packet that wasn't sent: {failed_packet:?}")]
-    StreamSenderError {
+    /// A send queue was full and the overflow policy discarded a packet (or closed the connection)
+    #[error("Send queue for stream {stream:?} of connection {connection:?} overflowed. Policy: {policy:?}")]
+    QueueOverflow {
         connection: ConnectionId,
+
+        /// The stream whose queue overflowed, or None if the connection queue overflowed
         stream: Option<StreamType>,
-        failed_packet: Arc<Packet>,
+        policy: OverflowPolicy,
+
+        /// The packet which was discarded, if any
+        dropped: Option<Arc<Packet>>,
+    },
+
+    /// A datagram was discarded because it was larger than the maximum datagram size
+    #[error("Datagram of {size} bytes to connection {connection:?} exceeds maximum size of {max} bytes")]
+    DatagramTooLarge {
+        connection: ConnectionId,
+        size: usize,
+        max: usize,
+    },
+
+    /// The server presented a certificate which does not match the fingerprint pinned the first time it was seen.
+    /// This may mean that the server is being impersonated, or that it has a new certificate.
+    #[error("Certificate of {host} does not match the pinned fingerprint. Expected {expected}, presented {presented}")]
+    CertificateMismatch {
+        host: String,
+        expected: String,
+        presented: String,
     },
 
     /// An error occurred in quinn while attempting to connect
@@ -42,21 +67,183 @@ pub enum NetworkError
     ConnectionError(#[from] quinn::ConnectionError)
 }
 
+/// Why a connection was closed. Sent to the peer as the QUIC application error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DisconnectCode
+{
+    /// The connection was closed normally
+    Normal,
+
+    /// The server is shutting down
+    Shutdown,
+
+    /// The peer speaks an incompatible protocol version
+    ProtocolMismatch,
+
+    /// The peer failed to authenticate
+    AuthenticationFailed,
+
+    /// The peer could not keep up with the packets being sent to it
+    QueueOverflow,
+
+    /// The connection was lost without either application closing it (e.g. it timed out). Never sent to a peer.
+    ConnectionLost,
+
+    /// The peer exceeded a rate limit, see `networking::rate_limit`
+    RateLimited,
+
+    /// An application specific code
+    Other(u32),
+}
+
+impl From<DisconnectCode> for u32 {
+    fn from(code: DisconnectCode) -> Self {
+        match code {
+            DisconnectCode::Normal => 0,
+            DisconnectCode::Shutdown => 1,
+            DisconnectCode::ProtocolMismatch => 2,
+            DisconnectCode::AuthenticationFailed => 3,
+            DisconnectCode::QueueOverflow => 4,
+            DisconnectCode::ConnectionLost => 5,
+            DisconnectCode::RateLimited => 6,
+            DisconnectCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<u32> for DisconnectCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => DisconnectCode::Normal,
+            1 => DisconnectCode::Shutdown,
+            2 => DisconnectCode::ProtocolMismatch,
+            3 => DisconnectCode::AuthenticationFailed,
+            4 => DisconnectCode::QueueOverflow,
+            5 => DisconnectCode::ConnectionLost,
+            6 => DisconnectCode::RateLimited,
+            code => DisconnectCode::Other(code),
+        }
+    }
+}
+
+/// The reason a connection was closed, with a human readable message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisconnectReason {
+    pub code: DisconnectCode,
+    pub message: String,
+}
+
+impl DisconnectReason {
+    pub fn new(code: DisconnectCode, message: impl Into<String>) -> Self {
+        DisconnectReason {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl Default for DisconnectReason {
+    fn default() -> Self {
+        DisconnectReason::new(DisconnectCode::Normal, "")
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            write!(f, "{:?}", self.code)
+        } else {
+            write!(f, "{:?} ({})", self.code, self.message)
+        }
+    }
+}
+
+impl NetworkError {
+    /// Get the connection this error occurred on, if it is known
+    pub fn connection(&self) -> Option<ConnectionId> {
+        match self {
+            NetworkError::ReceiveError { connection, .. } => Some(*connection),
+            NetworkError::SendError { connection, .. } => Some(*connection),
+            NetworkError::QueueOverflow { connection, .. } => Some(*connection),
+            NetworkError::DatagramTooLarge { connection, .. } => Some(*connection),
+            NetworkError::CertificateMismatch { .. } => None,
+            NetworkError::ConnectionError(_) => None,
+        }
+    }
+
+    /// Get the connection this error occurred on, if it is known
+    pub fn connection_mut(&mut self) -> Option<&mut ConnectionId> {
+        match self {
+            NetworkError::ReceiveError { connection, .. } => Some(connection),
+            NetworkError::SendError { connection, .. } => Some(connection),
+            NetworkError::QueueOverflow { connection, .. } => Some(connection),
+            NetworkError::DatagramTooLarge { connection, .. } => Some(connection),
+            NetworkError::CertificateMismatch { .. } => None,
+            NetworkError::ConnectionError(_) => None,
+        }
+    }
+}
+
 /// An event generated by the network
 #[derive(Debug)]
 pub enum ReceiveEvent
 {
     /// A new connection has opened
-    Connected(ConnectionId, UnboundedSender<SendEvent>),
+    Connected(ConnectionId, SendQueue),
+
+    /// The peer of a new connection authenticated with a certificate
+    PeerIdentified {
+        connection: ConnectionId,
+        identity: PeerIdentity,
+    },
 
     /// A packet has arrived in a stream
     ReceivedPacket {
         connection: ConnectionId,
+        stream: StreamType,
         data: Arc<Packet>,
     },
 
+    /// The handshake with a peer completed and the connection is ready to use
+    HandshakeCompleted {
+        connection: ConnectionId,
+        capabilities: HashSet<String>,
+    },
+
+    /// The handshake with a peer failed, the connection is being closed
+    HandshakeRejected {
+        connection: ConnectionId,
+        reason: HandshakeRejection,
+    },
+
+    /// Nothing has been received from a peer for a while (see `HealthConfig::silence_timeout`)
+    PeerSilent {
+        connection: ConnectionId,
+        silent_for: Duration,
+    },
+
+    /// A peer exceeded a limit on the packets or streams it may send, the policy decides what was done about it
+    LimitExceeded {
+        connection: ConnectionId,
+        violation: Violation,
+        policy: ViolationPolicy,
+    },
+
+    /// A client reconnected and resumed a previous session. The new connection (`replaced`) has been merged into
+    /// the previous `connection`, and events from it will use the previous ID from now on.
+    SessionResumed {
+        connection: ConnectionId,
+        replaced: ConnectionId,
+    },
+
     /// A connection has closed
-    Disconnected(ConnectionId),
+    Disconnected {
+        connection: ConnectionId,
+        reason: DisconnectReason,
+
+        /// True if the peer closed the connection, false if it was closed locally or lost
+        by_peer: bool,
+    },
 
     /// The network socket has closed
     SocketClosed,
@@ -65,113 +252,88 @@ pub enum ReceiveEvent
     NetworkError(NetworkError),
 }
 
+impl ReceiveEvent {
+    /// Get the connection this event is about, if any
+    pub fn connection_mut(&mut self) -> Option<&mut ConnectionId> {
+        match self {
+            ReceiveEvent::Connected(connection, _) => Some(connection),
+            ReceiveEvent::PeerIdentified { connection, .. } => Some(connection),
+            ReceiveEvent::ReceivedPacket { connection, .. } => Some(connection),
+            ReceiveEvent::HandshakeCompleted { connection, .. } => Some(connection),
+            ReceiveEvent::HandshakeRejected { connection, .. } => Some(connection),
+            ReceiveEvent::PeerSilent { connection, .. } => Some(connection),
+            ReceiveEvent::LimitExceeded { connection, .. } => Some(connection),
+            ReceiveEvent::SessionResumed { connection, .. } => Some(connection),
+            ReceiveEvent::Disconnected { connection, .. } => Some(connection),
+            ReceiveEvent::SocketClosed => None,
+            ReceiveEvent::NetworkError(err) => err.connection_mut(),
+        }
+    }
+}
+
 /// An event to send to the network
 #[derive(Debug, Clone)]
 pub enum SendEvent
 {
-    /// Send a packet through a specific stream on a specific connection
+    /// Send a packet on a specific connection. The stream it is sent through is determined by `Packet::delivery`.
     SendPacket {
         connection: ConnectionId,
-        stream: StreamType,
         data: Arc<Packet>,
     },
 
-    /// Send a packet through a new stream which is created just for this packet.
-    /// This should be used for very large packets.
-    TransferPacket {
+    /// Send a packet as an unreliable datagram, regardless of the packet's `Delivery`. The packet may be lost and
+    /// will be discarded if it is too large to fit in a datagram.
+    SendDatagram {
         connection: ConnectionId,
         data: Arc<Packet>,
-    }
+    },
+
+    /// Close a connection. Packets sent to this connection before this event will be sent first. The reason is
+    /// sent to the peer and appears in its `Disconnected` event.
+    Disconnect {
+        connection: ConnectionId,
+        reason: DisconnectReason,
+    },
+
+    /// Send a packet to several connections, in the same way as `SendPacket`. The packet is encoded once and the
+    /// same frame is sent to every connection.
+    Broadcast {
+        target: SendTarget,
+        data: Arc<Packet>,
+    },
 }
 
-impl SendEvent {
-    /// Get the connectionid associated with this send event
-    pub fn get_connection(&self) -> ConnectionId {
-        match self {
-            SendEvent::SendPacket { connection, .. } => *connection,
-            SendEvent::TransferPacket { connection, .. } => *connection,
-        }
-    }
+/// The connections a `SendEvent::Broadcast` is sent to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SendTarget {
+    /// Every open connection
+    All,
 
-    /// Get a `NetworkError::StreamSenderError` from this send event 
-    pub fn as_stream_sender_error(&self) -> NetworkError {
-        let (c, s, f) = match self {
-            SendEvent::SendPacket { connection, stream, data } => (*connection, Some(*stream), data),
-            SendEvent::TransferPacket { connection, data } => (*connection, None, data)
-        };
-
-        NetworkError::StreamSenderError {
-            connection: c,
-            stream: s,
-            failed_packet: f.clone()
-        }
-    }
+    /// Every open connection except one, e.g. the connection which sent the packet being forwarded
+    AllExcept(ConnectionId),
 
-    /// Send this event through the given quinn connection, looking for open streams in the `senders` vec
-    pub async fn send(&self, senders: &mut Vec<SendStream<TlsSession>>, quinn_conn: &mut quinn::Connection) -> Result<(), NetworkError> {
+    /// Every connection in a group, see `networking::groups`
+    Group(String),
+}
 
+impl SendEvent {
+    /// Get the connectionid associated with this send event, `None` if it is sent to several connections
+    pub fn get_connection(&self) -> Option<ConnectionId> {
         match self {
-            SendEvent::SendPacket { stream, data, connection } => {
-                // Find (or create) the sender for this stream
-                let sender = match Self::get_stream_sender(&stream, senders, quinn_conn).await {
-                    Ok(sender) => sender,
-                    Err(err) => return Err(NetworkError::ConnectionError(err))
-                };
-
-                // Send the packet through the stream
-                if let Err(err) = data.send(sender).await {
-                    return Err(NetworkError::SendError {
-                        connection: *connection,
-                        stream: *stream,
-                        err,
-                    });
-                }
-
-                Ok(())
-            }
-
-            SendEvent::TransferPacket { data, .. } => {
-                // Open a new stream for this transfer
-                let uni = match quinn_conn.open_uni().await {
-                    Ok(uni) => uni,
-                    Err(err) => return Err(NetworkError::ConnectionError(err))
-                };
-
-                // Start sending the data
-                tokio::spawn(Self::send_transfer(uni, data.clone()));
-
-                Ok(())
-            }
+            SendEvent::SendPacket { connection, .. } => Some(*connection),
+            SendEvent::SendDatagram { connection, .. } => Some(*connection),
+            SendEvent::Disconnect { connection, .. } => Some(*connection),
+            SendEvent::Broadcast { .. } => None,
         }
-
-    }
-
-    async fn send_transfer(mut sender: SendStream<TlsSession>, data: Arc<Packet>) {
-        // Both of these method generate a result which is discarded.
-        // Results from transfers are not sent anywhere as that could potentially result in
-        // errors from a connection arriving in the ECS after is has closed!
-        let _ = data.send(&mut sender).await;
-        let _ = sender.finish().await;
     }
 
-    async fn get_stream_sender<'a>(
-        stream_type: &StreamType,
-        stream_lookup: &'a mut Vec<SendStream<TlsSession>>,
-        conn: &mut quinn::Connection
-    ) -> Result<&'a mut SendStream<TlsSession>, ConnectionError>
-    {
-        // Calculate the index of this sender simply as the enum variant index
-        let idx = *stream_type as usize;
-
-        // Keep opening streams until the list of senders is large enough
-        if idx >= stream_lookup.len() {
-            stream_lookup.reserve(idx - stream_lookup.len() + 1);
-        }
-        while idx >= stream_lookup.len() {
-            stream_lookup.push(conn.open_uni().await?);
+    /// Get the packet carried by this send event, if any
+    pub fn get_packet(&self) -> Option<&Arc<Packet>> {
+        match self {
+            SendEvent::SendPacket { data, .. } => Some(data),
+            SendEvent::SendDatagram { data, .. } => Some(data),
+            SendEvent::Disconnect { .. } => None,
+            SendEvent::Broadcast { data, .. } => Some(data),
         }
-
-        // The list is now large enough that the sender definitely exists
-        Ok(stream_lookup.get_mut(idx).expect("List was just grown to this size"))
     }
 }
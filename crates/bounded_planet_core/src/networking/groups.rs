@@ -0,0 +1,49 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+
+use super::{components::Connection, id::ConnectionId};
+
+/// Add this component to a `Connection` entity to put the connection in named groups, e.g. a team or an area of
+/// interest. Packets can be sent to every connection in a group with `SendTarget::Group`.
+#[derive(Debug, Clone, Default)]
+pub struct Groups(pub HashSet<String>);
+
+impl Groups {
+    pub fn new<I, S>(groups: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Groups(groups.into_iter().map(Into::into).collect())
+    }
+}
+
+/// The connections in each group, collected from the `Groups` components every frame just before packets are sent
+#[derive(Debug, Default)]
+pub struct NetworkGroups {
+    members: HashMap<String, Vec<ConnectionId>>,
+}
+
+impl NetworkGroups {
+    /// Get the connections in a group, empty if no connection is in it
+    pub fn members(&self, group: &str) -> &[ConnectionId] {
+        self.members.get(group).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Collect the connections in each group from the `Groups` components
+pub fn update_network_groups_system(mut groups: ResMut<NetworkGroups>, mut query: Query<(&Connection, &Groups)>) {
+    for members in groups.members.values_mut() {
+        members.clear();
+    }
+
+    for (connection, connection_groups) in &mut query.iter() {
+        for group in &connection_groups.0 {
+            groups.members.entry(group.clone()).or_default().push(connection.id);
+        }
+    }
+
+    // Forget groups which are empty, so they do not build up as groups come and go
+    groups.members.retain(|_, members| !members.is_empty());
+}
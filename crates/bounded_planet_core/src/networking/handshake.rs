@@ -0,0 +1,122 @@
+use std::{collections::HashSet, sync::Arc};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+
+use super::{
+    events::{DisconnectCode, DisconnectReason, ReceiveEvent, SendEvent},
+    packets::{Handshake, Packet},
+    systems::{NetworkConnections, SessionEventListenerState},
+};
+
+/// Version of the network protocol. Must be incremented whenever `Packet` (or anything it contains) changes in a way
+/// which is not compatible with previous builds, and whenever the way packets are carried changes (e.g. the header
+/// which identifies each stream, or which packets are sent as datagrams).
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// Optional protocol features supported by this build. Only capabilities supported by both ends of a connection are
+/// enabled for that connection.
+pub const CAPABILITIES: &[&str] = &[];
+
+/// ALPN protocols advertised by both client and server
+pub const ALPN_PROTOCOLS: &[&[u8]] = &[b"hq-29"];
+
+/// Reason a handshake with a peer was rejected
+#[derive(Debug, Clone, Error, Serialize, Deserialize)]
+pub enum HandshakeRejection {
+    #[error("Peer uses protocol version {remote}, expected version {local}")]
+    VersionMismatch {
+        local: u32,
+        remote: u32,
+    },
+}
+
+/// This component is attached to a `Connection` once the handshake has completed
+#[derive(Debug, Clone)]
+pub struct PeerCapabilities {
+    /// Capabilities supported by both ends of this connection
+    pub capabilities: HashSet<String>,
+}
+
+impl PeerCapabilities {
+    /// Check if a capability is enabled for this connection
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+#[derive(Default)]
+pub struct HandshakeState {
+    pub event_reader: EventReader<ReceiveEvent>,
+}
+
+/// Send a handshake to every new connection, and check the handshakes received from peers
+pub fn handshake_system(
+    mut commands: Commands,
+    mut state: ResMut<HandshakeState>,
+    session: Res<SessionEventListenerState>,
+    connections: Res<NetworkConnections>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut sender: ResMut<Events<SendEvent>>,
+) {
+    for evt in state.event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::Connected(connection, _) => {
+                sender.send(SendEvent::SendPacket {
+                    connection: *connection,
+                    data: Arc::new(Packet::Handshake(Handshake {
+                        protocol_version: PROTOCOL_VERSION,
+                        capabilities: CAPABILITIES.iter().map(|c| (*c).to_owned()).collect(),
+                    })),
+                });
+            }
+
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => {
+                let handshake = match &**data {
+                    Packet::Handshake(handshake) => handshake,
+                    _ => continue,
+                };
+
+                // Reject (and close) connections to peers which speak a different protocol version
+                if handshake.protocol_version != PROTOCOL_VERSION {
+                    warn!("Rejecting handshake from {:?}: protocol version {} (expected {})", connection, handshake.protocol_version, PROTOCOL_VERSION);
+                    let _ = session.event_sender.send(ReceiveEvent::HandshakeRejected {
+                        connection: *connection,
+                        reason: HandshakeRejection::VersionMismatch {
+                            local: PROTOCOL_VERSION,
+                            remote: handshake.protocol_version,
+                        },
+                    });
+                    sender.send(SendEvent::Disconnect {
+                        connection: *connection,
+                        reason: DisconnectReason::new(
+                            DisconnectCode::ProtocolMismatch,
+                            format!("Expected protocol version {}", PROTOCOL_VERSION),
+                        ),
+                    });
+                    continue;
+                }
+
+                // Enable the capabilities which both ends support
+                let capabilities = handshake.capabilities
+                    .iter()
+                    .filter(|c| CAPABILITIES.contains(&c.as_str()))
+                    .cloned()
+                    .collect::<HashSet<_>>();
+                info!("Handshake completed with {:?}. Capabilities: {:?}", connection, capabilities);
+
+                if let Some(e) = connections.connections.get(connection) {
+                    commands.insert_one(*e, PeerCapabilities { capabilities: capabilities.clone() });
+                }
+                let _ = session.event_sender.send(ReceiveEvent::HandshakeCompleted {
+                    connection: *connection,
+                    capabilities,
+                });
+            }
+
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tracing::warn;
+
+use super::{
+    components::Connection,
+    events::{ReceiveEvent, SendEvent},
+    packets::{Packet, Ping, Pong},
+    systems::{NetworkConnections, SessionEventListenerState},
+};
+
+/// Configures how the health of each connection is monitored
+#[derive(Debug, Clone, Copy)]
+pub struct HealthConfig {
+    /// How often a `Ping` is sent to each connection
+    pub ping_interval: Duration,
+
+    /// How long to wait for a `Pong` before a ping is counted as lost
+    pub ping_timeout: Duration,
+
+    /// Number of recent pings used to estimate packet loss
+    pub loss_window: usize,
+
+    /// How long a peer may send nothing before a `ReceiveEvent::PeerSilent` event is emitted
+    pub silence_timeout: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            ping_interval: Duration::from_secs(1),
+            ping_timeout: Duration::from_secs(3),
+            loss_window: 20,
+            silence_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// This component holds the quality of a `Connection`, updated every frame
+#[derive(Debug, Clone)]
+pub struct ConnectionHealth {
+    /// Smoothed round trip time, `None` until the first `Pong` arrives
+    pub rtt: Option<Duration>,
+
+    /// Smoothed variation in round trip time
+    pub jitter: Duration,
+
+    /// Fraction (0 to 1) of recent pings which were not answered
+    pub loss: f32,
+
+    /// When a packet was last received from the peer
+    pub last_received: Instant,
+
+    /// Set when the peer has sent nothing for longer than `HealthConfig::silence_timeout`
+    pub silent: bool,
+
+    /// Pings which have not been answered yet, by timestamp
+    outstanding: VecDeque<(u64, Instant)>,
+
+    /// Whether each recent ping was answered, oldest first
+    results: VecDeque<bool>,
+
+    /// When a ping was last sent
+    last_ping: Option<Instant>,
+}
+
+impl Default for ConnectionHealth {
+    fn default() -> Self {
+        ConnectionHealth {
+            rtt: None,
+            jitter: Duration::default(),
+            loss: 0.0,
+            last_received: Instant::now(),
+            silent: false,
+            outstanding: Default::default(),
+            results: Default::default(),
+            last_ping: None,
+        }
+    }
+}
+
+impl ConnectionHealth {
+    /// Update the RTT estimate with the response to a ping
+    fn pong_received(&mut self, timestamp: u64, now: Instant, loss_window: usize) {
+        let sent = match self.outstanding.iter().position(|(t, _)| *t == timestamp) {
+            Some(index) => self.outstanding.remove(index).map(|(_, sent)| sent),
+            None => None,
+        };
+
+        // Pongs for pings which have already been counted as lost (or were never sent) are ignored
+        let sent = match sent {
+            Some(sent) => sent,
+            None => return,
+        };
+
+        // Smooth the estimates in the same way as TCP (RFC 6298)
+        let sample = now - sent;
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.jitter = sample / 2;
+            }
+            Some(rtt) => {
+                let deviation = if rtt > sample { rtt - sample } else { sample - rtt };
+                self.jitter = (self.jitter * 3 + deviation) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+
+        self.record_result(true, loss_window);
+    }
+
+    /// Count pings which have not been answered in time as lost
+    fn expire_pings(&mut self, now: Instant, timeout: Duration, loss_window: usize) {
+        while let Some((_, sent)) = self.outstanding.front() {
+            if now - *sent < timeout {
+                break;
+            }
+
+            self.outstanding.pop_front();
+            self.record_result(false, loss_window);
+        }
+    }
+
+    fn record_result(&mut self, answered: bool, loss_window: usize) {
+        self.results.push_back(answered);
+        while self.results.len() > loss_window {
+            self.results.pop_front();
+        }
+
+        let lost = self.results.iter().filter(|answered| !**answered).count();
+        self.loss = lost as f32 / self.results.len() as f32;
+    }
+}
+
+#[derive(Default)]
+pub struct HealthState {
+    pub event_reader: EventReader<ReceiveEvent>,
+}
+
+/// Answer pings, send pings to every connection and track the health of every connection
+pub fn connection_health_system(
+    mut state: ResMut<HealthState>,
+    config: Res<HealthConfig>,
+    session: Res<SessionEventListenerState>,
+    connections: Res<NetworkConnections>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut sender: ResMut<Events<SendEvent>>,
+    mut query: Query<(&Connection, &mut ConnectionHealth)>,
+) {
+    let now = Instant::now();
+
+    for evt in state.event_reader.iter(&receiver) {
+        let (connection, data) = match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => (connection, data),
+            _ => continue,
+        };
+
+        let mut health = match connections.connections.get(connection).map(|e| query.get_mut::<ConnectionHealth>(*e)) {
+            Some(Ok(health)) => health,
+            _ => continue,
+        };
+
+        // Any packet shows that the peer is still there
+        health.last_received = now;
+        health.silent = false;
+
+        match **data {
+            Packet::Ping(Ping { timestamp }) => {
+                sender.send(SendEvent::SendPacket {
+                    connection: *connection,
+                    data: Arc::new(Packet::Pong(Pong { timestamp })),
+                });
+            }
+
+            Packet::Pong(Pong { timestamp }) => health.pong_received(timestamp, now, config.loss_window),
+
+            _ => {}
+        }
+    }
+
+    for (connection, mut health) in &mut query.iter() {
+        health.expire_pings(now, config.ping_timeout, config.loss_window);
+
+        // Send a new ping once the interval has passed
+        if health.last_ping.map_or(true, |last| now - last >= config.ping_interval) {
+            let ping = Ping::default();
+            health.outstanding.push_back((ping.timestamp, now));
+            health.last_ping = Some(now);
+
+            sender.send(SendEvent::SendPacket {
+                connection: connection.id,
+                data: Arc::new(Packet::Ping(ping)),
+            });
+        }
+
+        // Notify the ECS once when the peer goes silent
+        let silent_for = now - health.last_received;
+        if !health.silent && silent_for >= config.silence_timeout {
+            warn!("Connection {:?} has been silent for {:?}", connection.id, silent_for);
+            health.silent = true;
+
+            let _ = session.event_sender.send(ReceiveEvent::PeerSilent {
+                connection: connection.id,
+                silent_for,
+            });
+        }
+    }
+}
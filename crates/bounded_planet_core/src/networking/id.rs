@@ -1,7 +1,8 @@
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 /// Uniquely identifies a single network connection
-#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ConnectionId(Uuid);
 
 impl ConnectionId {
@@ -15,3 +16,7 @@ impl Default for ConnectionId {
         Self::new()
     }
 }
+
+/// Identifies a replicated entity. Assigned by the server and shared by the server and all clients.
+#[derive(Debug, Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct NetworkEntityId(pub u64);
@@ -0,0 +1,290 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use thiserror::Error;
+use tokio::sync::{
+    Notify,
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+use tracing::info;
+
+use super::{
+    codec::CodecConfig,
+    rate_limit::{RateLimitConfig, RateLimiter},
+    events::{DisconnectCode, DisconnectReason, NetworkError, ReceiveEvent},
+    id::ConnectionId,
+    packets::Packet,
+    queue::{Outgoing, QueueConfig, SendQueue},
+    serialization::SharedPacket,
+    stats::StatsRecorder,
+};
+
+/// Create an in-memory "socket" which connects a server and clients running in the same process. Give the listener
+/// to the server `Network` plugin and a clone of the connector to each client `Network` plugin.
+pub fn loopback() -> (LoopbackListener, LoopbackConnector) {
+    let (send, recv) = unbounded_channel();
+
+    let listener = LoopbackListener { incoming: Arc::new(Mutex::new(Some(recv))) };
+    let connector = LoopbackConnector { server: send };
+
+    (listener, connector)
+}
+
+/// The server end of a loopback socket
+#[derive(Clone)]
+pub struct LoopbackListener {
+    incoming: Arc<Mutex<Option<UnboundedReceiver<LoopbackEnd>>>>,
+}
+
+impl LoopbackListener {
+    /// Take the stream of new connections, a listener can only be used by one server
+    pub(crate) fn take(&self) -> UnboundedReceiver<LoopbackEnd> {
+        self.incoming
+            .lock()
+            .expect("Loopback listener lock poisoned")
+            .take()
+            .expect("Loopback listener is already in use by another server")
+    }
+}
+
+impl fmt::Debug for LoopbackListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoopbackListener").finish()
+    }
+}
+
+/// The client end of a loopback socket, used to open connections to the server
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    server: UnboundedSender<LoopbackEnd>,
+}
+
+impl LoopbackConnector {
+    /// Open a new connection to the server
+    pub(crate) fn connect(&self) -> Result<LoopbackEnd, LoopbackError> {
+        let (client, server) = LoopbackEnd::pair();
+        self.server.send(server).map_err(|_| LoopbackError::ServerClosed)?;
+        Ok(client)
+    }
+}
+
+impl fmt::Debug for LoopbackConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoopbackConnector").finish()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LoopbackError {
+    #[error("The loopback server has stopped accepting connections")]
+    ServerClosed,
+}
+
+/// Messages passed from one end of a loopback connection to the other
+enum Message {
+    /// A packet, encoded in the same way as a datagram
+    Data(Bytes),
+
+    /// The other end closed the connection
+    Close(DisconnectReason),
+}
+
+/// One end of a loopback connection
+pub(crate) struct LoopbackEnd {
+    incoming: UnboundedReceiver<Message>,
+    outgoing: UnboundedSender<Message>,
+}
+
+impl LoopbackEnd {
+    fn pair() -> (LoopbackEnd, LoopbackEnd) {
+        let (a_send, a_recv) = unbounded_channel();
+        let (b_send, b_recv) = unbounded_channel();
+
+        (
+            LoopbackEnd { incoming: a_recv, outgoing: b_send },
+            LoopbackEnd { incoming: b_recv, outgoing: a_send },
+        )
+    }
+}
+
+/// Accept new loopback connections until every connector has been dropped
+pub(crate) async fn poll_loopback_connections(
+    mut incoming: UnboundedReceiver<LoopbackEnd>,
+    event_sender: UnboundedSender<ReceiveEvent>,
+    queue_config: QueueConfig,
+    codec: Arc<CodecConfig>,
+    limits: Arc<RateLimitConfig>,
+) {
+    info!("Polling for incoming loopback connections");
+
+    while let Some(end) = incoming.recv().await {
+        tokio::spawn(run_loopback(end, event_sender.clone(), queue_config, codec.clone(), limits.clone()));
+    }
+
+    info!("All loopback connectors dropped");
+}
+
+/// Spawn the tasks which pump one end of a loopback connection, the equivalent of `Connecting` and `Connected` for QUIC
+pub(crate) async fn run_loopback(
+    end: LoopbackEnd,
+    event_sender: UnboundedSender<ReceiveEvent>,
+    queue_config: QueueConfig,
+    codec: Arc<CodecConfig>,
+    limits: Arc<RateLimitConfig>,
+) {
+    let id = ConnectionId::new();
+    info!("loopback connection opened: {:?}", id);
+
+    // Create a new queue which the ECS can use to send packets through this connection
+    let queue = SendQueue::new(queue_config);
+    event_sender
+        .send(ReceiveEvent::Connected(id, queue.clone()))
+        .expect("Failed to send network event");
+
+    // Notified when this end closes the connection
+    let closed = Arc::new(Notify::new());
+
+    let limiter = RateLimiter::new(id, limits, queue.clone(), event_sender.clone());
+
+    let LoopbackEnd { incoming, outgoing } = end;
+    tokio::spawn(receive(id, incoming, closed.clone(), queue.clone(), event_sender.clone(), codec.clone(), limiter));
+    tokio::spawn(send_datagrams(id, outgoing.clone(), queue.clone(), event_sender.clone(), codec.clone()));
+    tokio::spawn(send_packets(id, outgoing, closed, queue, event_sender, codec));
+}
+
+/// Forward packets from the other end to the ECS until the connection closes
+async fn receive(
+    id: ConnectionId,
+    mut incoming: UnboundedReceiver<Message>,
+    closed: Arc<Notify>,
+    queue: SendQueue,
+    event_sender: UnboundedSender<ReceiveEvent>,
+    codec: Arc<CodecConfig>,
+    limiter: Arc<RateLimiter>,
+) {
+    let (reason, by_peer) = loop {
+        let message = tokio::select! {
+            message = incoming.recv() => message,
+
+            // This end closed the connection, the reason was recorded in the queue when it did
+            _ = closed.notified() => break (queue.close_reason().unwrap_or_default(), false),
+        };
+
+        match message {
+            Some(Message::Data(bytes)) => {
+                let event = match Packet::from_datagram(&bytes, &codec) {
+                    Ok((stream, packet)) => {
+                        queue.recorder().received(stream, bytes.len());
+
+                        // Loopback never loses packets, so every packet is held back when throttled
+                        if !limiter.admit(&packet).await {
+                            continue;
+                        }
+
+                        ReceiveEvent::ReceivedPacket {
+                            connection: id,
+                            stream,
+                            data: Arc::new(packet),
+                        }
+                    }
+                    Err(err) => ReceiveEvent::NetworkError(NetworkError::ReceiveError {
+                        connection: id,
+                        err,
+                    }),
+                };
+                event_sender.send(event).expect("Failed to send network event");
+            }
+
+            Some(Message::Close(reason)) => break (reason, true),
+
+            // The other end went away without closing the connection
+            None => break (DisconnectReason::new(DisconnectCode::ConnectionLost, "Loopback peer dropped"), false),
+        }
+    };
+
+    // Send a final event indicating that this connection closed
+    event_sender
+        .send(ReceiveEvent::Disconnected { connection: id, reason, by_peer })
+        .expect("Failed to send network event");
+}
+
+/// Pump the connection queue and pass packets to the other end
+async fn send_packets(
+    id: ConnectionId,
+    outgoing: UnboundedSender<Message>,
+    closed: Arc<Notify>,
+    queue: SendQueue,
+    event_sender: UnboundedSender<ReceiveEvent>,
+    codec: Arc<CodecConfig>,
+) {
+    while let Some(evt) = queue.next_event().await {
+        let data = match evt {
+            Outgoing::Packet(data) => data,
+
+            Outgoing::Disconnect(reason) => {
+                queue.set_close_reason(reason.clone());
+                let _ = outgoing.send(Message::Close(reason));
+                closed.notify();
+                break;
+            }
+        };
+
+        if !send(id, &outgoing, &data, &event_sender, &codec, false, queue.recorder()) {
+            break;
+        }
+    }
+
+    queue.close_streams();
+}
+
+/// Pass queued datagrams to the other end. Loopback never loses datagrams.
+async fn send_datagrams(
+    id: ConnectionId,
+    outgoing: UnboundedSender<Message>,
+    queue: SendQueue,
+    event_sender: UnboundedSender<ReceiveEvent>,
+    codec: Arc<CodecConfig>,
+) {
+    let datagrams = queue.datagrams();
+    while let Some(data) = datagrams.pop().await {
+        if !send(id, &outgoing, &data, &event_sender, &codec, true, queue.recorder()) {
+            break;
+        }
+    }
+}
+
+/// Encode a packet and pass it to the other end, counting it as a datagram if `datagram` is set. Returns false if the
+/// other end has gone away.
+fn send(
+    id: ConnectionId,
+    outgoing: &UnboundedSender<Message>,
+    data: &SharedPacket,
+    event_sender: &UnboundedSender<ReceiveEvent>,
+    codec: &CodecConfig,
+    datagram: bool,
+    stats: &StatsRecorder,
+) -> bool {
+    // Encode the packet so that loopback connections exercise serialization in the same way as real connections
+    let stream = data.packet().stream_type();
+    match data.to_datagram(codec) {
+        Ok(bytes) => {
+            if datagram {
+                stats.sent_datagram(stream, bytes.len());
+            } else {
+                stats.sent(stream, bytes.len());
+            }
+            outgoing.send(Message::Data(bytes)).is_ok()
+        }
+        Err(err) => {
+            let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                connection: id,
+                stream,
+                err,
+            }));
+            true
+        }
+    }
+}
@@ -3,7 +3,22 @@ pub mod components;
 pub mod systems;
 pub mod packets;
 pub mod serialization;
+pub mod codec;
 pub mod events;
+pub mod handshake;
+pub mod queue;
+pub mod rate_limit;
+pub mod replication;
+pub mod health;
+pub mod groups;
+pub mod rpc;
+pub mod stats;
+pub mod capture;
+pub mod loopback;
+
+/// Harness for testing the networking layer, enabled by the `testing` feature
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub mod client;
 pub mod server;
@@ -1,26 +1,277 @@
-use std::time::SystemTime;
+use std::{convert::TryFrom, sync::Arc, time::SystemTime};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::land::MeshData;
+use crate::land::{MeshData, WorldManifest};
 
-/// Uniquely identifies a single unidirectional stream of data within a single network connection
-#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+use super::id::NetworkEntityId;
+
+/// Uniquely identifies a single unidirectional stream of data within a single network connection.
+/// The discriminant is sent as a header at the start of every stream so the receiver knows which logical channel
+/// packets arrived on.
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[repr(u8)]
 pub enum StreamType {
-    TextChat,
-    PingPong,
-    WorldTileData
+    TextChat = 0,
+    PingPong = 1,
+    WorldTileData = 2,
+    Auth = 3,
+    /// Must never change, so that peers running different protocol versions can always exchange handshakes
+    Handshake = 4,
+    Replication = 5,
+    Rpc = 6,
+}
+
+impl TryFrom<u8> for StreamType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(StreamType::TextChat),
+            1 => Ok(StreamType::PingPong),
+            2 => Ok(StreamType::WorldTileData),
+            3 => Ok(StreamType::Auth),
+            4 => Ok(StreamType::Handshake),
+            5 => Ok(StreamType::Replication),
+            6 => Ok(StreamType::Rpc),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Whether a packet must arrive
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum Reliability {
+    /// The packet will be retransmitted until it arrives (or the connection closes)
+    Reliable,
+
+    /// The packet may be lost, duplicated or arrive out of order. These packets are sent as QUIC datagrams, so they are
+    /// never blocked behind other packets. Must be small enough to fit in a single datagram.
+    Unreliable,
+}
+
+/// Whether a packet must arrive in order relative to other packets on the same stream
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum Ordering {
+    /// The packet is sent through the shared stream for its `StreamType` and arrives after all packets sent before it
+    Ordered,
+
+    /// The packet is sent through a new stream created just for this packet. It will not block (or be blocked by)
+    /// any other packets. This should be used for very large packets.
+    Unordered,
+}
+
+/// Describes how a packet should be sent across the network
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub struct Delivery {
+    pub stream: StreamType,
+    pub reliability: Reliability,
+    pub ordering: Ordering,
+}
+
+impl Delivery {
+    const fn ordered(stream: StreamType) -> Delivery {
+        Delivery {
+            stream,
+            reliability: Reliability::Reliable,
+            ordering: Ordering::Ordered,
+        }
+    }
+
+    const fn unordered(stream: StreamType) -> Delivery {
+        Delivery {
+            stream,
+            reliability: Reliability::Reliable,
+            ordering: Ordering::Unordered,
+        }
+    }
+
+    const fn unreliable(stream: StreamType) -> Delivery {
+        Delivery {
+            stream,
+            reliability: Reliability::Unreliable,
+            ordering: Ordering::Unordered,
+        }
+    }
 }
 
 /// Enum of all packets in the network protocol
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Packet {
+    /// Must remain the first variant (and `Handshake` must never change layout) so that peers running
+    /// different protocol versions can always decode it. Handshakes are never framed by the codec, see
+    /// `CodecConfig::encode`.
+    Handshake(Handshake),
     AuthRequest(AuthRequest),
     AuthResponse(AuthResponse),
     TextChat(TextChat),
     Ping(Ping),
     Pong(Pong),
     WorldTileDataRequest(WorldTileDataRequest),
-    WorldTileData(WorldTileData)
+    WorldTileData(WorldTileData),
+    Replication(Replication),
+    Session(Session),
+    WorldInfoRequest(WorldInfoRequest),
+    WorldInfo(WorldInfo),
+
+    /// A request, which expects a `Response` (or `RequestFailed`) with the same ID in return. See `networking::rpc`.
+    Request {
+        id: RequestId,
+        request: RpcPayload,
+    },
+
+    /// The response to the `Request` with the same ID
+    Response {
+        id: RequestId,
+        response: RpcPayload,
+    },
+
+    /// Sent instead of a `Response` when a request could not be answered
+    RequestFailed {
+        id: RequestId,
+        error: RpcError,
+    },
+}
+
+impl Packet {
+    /// Get the stream, reliability and ordering this packet should be sent with
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            Packet::Handshake(_) => Delivery::ordered(StreamType::Handshake),
+            Packet::AuthRequest(_) => Delivery::ordered(StreamType::Auth),
+            Packet::AuthResponse(_) => Delivery::ordered(StreamType::Auth),
+            Packet::TextChat(_) => Delivery::ordered(StreamType::TextChat),
+            Packet::Ping(_) => Delivery::unreliable(StreamType::PingPong),
+            Packet::Pong(_) => Delivery::unreliable(StreamType::PingPong),
+            Packet::WorldTileDataRequest(_) => Delivery::ordered(StreamType::WorldTileData),
+            Packet::WorldTileData(_) => Delivery::unordered(StreamType::WorldTileData),
+            Packet::Replication(_) => Delivery::ordered(StreamType::Replication),
+            Packet::Session(_) => Delivery::ordered(StreamType::Auth),
+            Packet::WorldInfoRequest(_) => Delivery::ordered(StreamType::WorldTileData),
+            Packet::WorldInfo(_) => Delivery::ordered(StreamType::WorldTileData),
+            Packet::Request { request, .. } => request.delivery(),
+            Packet::Response { response, .. } => response.delivery(),
+            Packet::RequestFailed { .. } => Delivery::ordered(StreamType::Rpc),
+        }
+    }
+
+    /// Get the stream this packet should be sent through
+    pub fn stream_type(&self) -> StreamType {
+        self.delivery().stream
+    }
+
+    /// Get the type of this packet. Requests and responses have the type of the packet they wrap, so they share its
+    /// rate limits.
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            Packet::Handshake(_) => PacketKind::Handshake,
+            Packet::AuthRequest(_) => PacketKind::AuthRequest,
+            Packet::AuthResponse(_) => PacketKind::AuthResponse,
+            Packet::TextChat(_) => PacketKind::TextChat,
+            Packet::Ping(_) => PacketKind::Ping,
+            Packet::Pong(_) => PacketKind::Pong,
+            Packet::WorldTileDataRequest(_) => PacketKind::WorldTileDataRequest,
+            Packet::WorldTileData(_) => PacketKind::WorldTileData,
+            Packet::Replication(_) => PacketKind::Replication,
+            Packet::Session(_) => PacketKind::Session,
+            Packet::WorldInfoRequest(_) => PacketKind::WorldInfoRequest,
+            Packet::WorldInfo(_) => PacketKind::WorldInfo,
+            Packet::Request { request, .. } => request.kind(),
+            Packet::Response { response, .. } => response.kind(),
+            Packet::RequestFailed { .. } => PacketKind::RequestFailed,
+        }
+    }
+
+    /// Check if this packet may be received from a connection which has not yet authenticated
+    pub fn allowed_before_auth(&self) -> bool {
+        match self {
+            Packet::Request { request, .. } => request.allowed_before_auth(),
+            _ => matches!(self, Packet::Handshake(_) | Packet::AuthRequest(_) | Packet::Session(_) | Packet::Ping(_) | Packet::Pong(_)),
+        }
+    }
+}
+
+/// The packets which can be sent as a request or response through `networking::rpc`. This is a separate type
+/// rather than a `Packet`, so that requests can not be nested inside each other: decoding a deeply nested packet
+/// would overflow the stack.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RpcPayload {
+    AuthRequest(AuthRequest),
+    AuthResponse(AuthResponse),
+    WorldTileDataRequest(WorldTileDataRequest),
+    WorldTileData(WorldTileData),
+    WorldInfoRequest(WorldInfoRequest),
+    WorldInfo(WorldInfo),
+}
+
+impl RpcPayload {
+    /// Get the stream, reliability and ordering this payload is sent with, the same as the plain packet
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            RpcPayload::AuthRequest(_) => Delivery::ordered(StreamType::Auth),
+            RpcPayload::AuthResponse(_) => Delivery::ordered(StreamType::Auth),
+            RpcPayload::WorldTileDataRequest(_) => Delivery::ordered(StreamType::WorldTileData),
+            RpcPayload::WorldTileData(_) => Delivery::unordered(StreamType::WorldTileData),
+            RpcPayload::WorldInfoRequest(_) => Delivery::ordered(StreamType::WorldTileData),
+            RpcPayload::WorldInfo(_) => Delivery::ordered(StreamType::WorldTileData),
+        }
+    }
+
+    /// Get the type of the plain packet this payload corresponds to
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            RpcPayload::AuthRequest(_) => PacketKind::AuthRequest,
+            RpcPayload::AuthResponse(_) => PacketKind::AuthResponse,
+            RpcPayload::WorldTileDataRequest(_) => PacketKind::WorldTileDataRequest,
+            RpcPayload::WorldTileData(_) => PacketKind::WorldTileData,
+            RpcPayload::WorldInfoRequest(_) => PacketKind::WorldInfoRequest,
+            RpcPayload::WorldInfo(_) => PacketKind::WorldInfo,
+        }
+    }
+
+    /// Check if this payload may be requested by a connection which has not yet authenticated
+    pub fn allowed_before_auth(&self) -> bool {
+        matches!(self, RpcPayload::AuthRequest(_))
+    }
+}
+
+impl From<RpcPayload> for Packet {
+    /// Get the plain packet with the same contents, to answer requests which were sent without a `RequestId`
+    fn from(payload: RpcPayload) -> Self {
+        match payload {
+            RpcPayload::AuthRequest(p) => Packet::AuthRequest(p),
+            RpcPayload::AuthResponse(p) => Packet::AuthResponse(p),
+            RpcPayload::WorldTileDataRequest(p) => Packet::WorldTileDataRequest(p),
+            RpcPayload::WorldTileData(p) => Packet::WorldTileData(p),
+            RpcPayload::WorldInfoRequest(p) => Packet::WorldInfoRequest(p),
+            RpcPayload::WorldInfo(p) => Packet::WorldInfo(p),
+        }
+    }
+}
+
+/// Identifies the type of a `Packet`, without its contents
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub enum PacketKind {
+    Handshake,
+    AuthRequest,
+    AuthResponse,
+    TextChat,
+    Ping,
+    Pong,
+    WorldTileDataRequest,
+    WorldTileData,
+    Replication,
+    Session,
+    WorldInfoRequest,
+    WorldInfo,
+    RequestFailed,
+}
+
+/// Handshake packet, sent by both ends of a connection as soon as it opens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
 }
 
 /// Ping packet, expects a returned "Pong" response
@@ -32,14 +283,19 @@ pub struct Ping {
 impl Default for Ping {
     fn default() -> Self {
         Ping {
-            timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("System time is before unix epoch")
-                .as_millis() as u64
+            timestamp: timestamp_now()
         }
     }
 }
 
+/// Get the current time as a timestamp (milliseconds since the unix epoch), as used in packets
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("System time is before unix epoch")
+        .as_millis() as u64
+}
+
 /// Pong packet, contains the timestamp of the Ping packet it is responding to
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Pong {
@@ -53,11 +309,69 @@ pub struct AuthRequest {
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Result of an authentication attempt, sent from the server to the client in response to an `AuthRequest`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 pub enum AuthResponse {
     Ok,
     IncorrectUsername,
     IncorrectPassword,
+
+    /// The connection failed to authenticate too many times and is being closed
+    TooManyAttempts,
+
+    /// The connection did not authenticate in time and is being closed
+    TimedOut,
+}
+
+/// A secret issued by the server which a client can use to resume its session after reconnecting
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub struct SessionToken(pub [u8; 32]);
+
+/// Session management, exchanged after the handshake
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Session {
+    /// Sent by the client to start a new session, or resume a previous one
+    Request {
+        resume: Option<SessionToken>,
+    },
+
+    /// Sent by the server in response to `Request`. If the session was not resumed the client must authenticate.
+    Accepted {
+        token: SessionToken,
+        resumed: bool,
+    },
+}
+
+/// Identifies a request sent through `networking::rpc`, unique among the requests in flight on one connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub struct RequestId(pub u32);
+
+/// Why a request did not get a response
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Error)]
+pub enum RpcError {
+    /// The peer does not have what was requested
+    #[error("Not found")]
+    NotFound,
+
+    /// The peer refused to answer the request
+    #[error("Refused: {0}")]
+    Refused(String),
+
+    /// The peer failed to answer the request
+    #[error("Failed: {0}")]
+    Failed(String),
+
+    /// No response arrived in time. Never sent by a peer.
+    #[error("Timed out")]
+    TimedOut,
+
+    /// The connection closed before a response arrived. Never sent by a peer.
+    #[error("Connection closed")]
+    Disconnected,
+
+    /// The peer responded with a packet of the wrong type. Never sent by a peer.
+    #[error("Unexpected response: {0:?}")]
+    UnexpectedResponse(PacketKind),
 }
 
 /// A text chat message
@@ -78,5 +392,51 @@ pub struct WorldTileDataRequest {
 /// World tile data packet, requested by the client
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WorldTileData {
-    pub mesh_data: MeshData
+    pub mesh_data: Arc<MeshData>
+}
+
+/// World info request packet, sent by the client to the server to find out which tiles exist
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorldInfoRequest;
+
+/// World info packet, requested by the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorldInfo {
+    pub manifest: WorldManifest
+}
+
+/// The serialized state of a single replicated component
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ComponentData {
+    /// Identifies the type of the component, see `Replicate::KIND`
+    pub kind: String,
+    pub data: Vec<u8>,
+}
+
+/// Changes to the replicated entities on the server, sent from the server to the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Replication {
+    /// A new entity has been replicated to the client, with the current state of all its replicated components
+    Spawn {
+        entity: NetworkEntityId,
+        components: Vec<ComponentData>,
+
+        /// Server time (see `timestamp_now`) at which this state was captured
+        server_time: u64,
+    },
+
+    /// Some replicated components of an entity have been changed, added or removed
+    Update {
+        entity: NetworkEntityId,
+        changed: Vec<ComponentData>,
+        removed: Vec<String>,
+
+        /// Server time (see `timestamp_now`) at which this state was captured
+        server_time: u64,
+    },
+
+    /// An entity is no longer replicated to the client
+    Despawn {
+        entity: NetworkEntityId,
+    },
 }
@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::Notify;
+use tracing::trace;
+
+use super::{
+    events::{DisconnectReason, SendEvent},
+    packets::{Packet, Reliability, StreamType},
+    serialization::SharedPacket,
+    stats::{ConnectionStats, StatsRecorder},
+};
+
+/// What to do when a bounded send queue is full
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest item in the queue to make room for the new one
+    DropOldest,
+
+    /// Discard the new item
+    DropNewest,
+
+    /// Close the connection
+    Disconnect,
+}
+
+/// Configures the sizes of the bounded queues between the ECS and the network
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// Maximum number of events waiting to be dispatched to a single connection
+    pub connection_capacity: usize,
+
+    /// Maximum number of packets waiting to be written to a single stream of a connection
+    pub stream_capacity: usize,
+
+    /// What to do when either kind of queue is full
+    pub policy: OverflowPolicy,
+
+    /// Maximum number of packets waiting to be sent as datagrams to a single connection. When this queue is full the
+    /// oldest datagram is discarded, regardless of `policy`.
+    pub datagram_capacity: usize,
+
+    /// Maximum size (in bytes) of an encoded datagram. Larger datagrams are discarded. The limit negotiated with the
+    /// peer may be lower than this.
+    pub max_datagram_size: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            connection_capacity: 1024,
+            stream_capacity: 256,
+
+            // Silently dropping packets from reliable streams could leave the peer in an inconsistent state, so by
+            // default a peer which can't keep up is disconnected.
+            policy: OverflowPolicy::Disconnect,
+
+            datagram_capacity: 64,
+            max_datagram_size: 1200,
+        }
+    }
+}
+
+/// A bounded multi-producer, single-consumer queue
+pub struct BoundedQueue<T> {
+    inner: Arc<BoundedQueueInner<T>>,
+}
+
+struct BoundedQueueInner<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+impl<T> Clone for BoundedQueue<T> {
+    fn clone(&self) -> Self {
+        BoundedQueue {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        BoundedQueue {
+            inner: Arc::new(BoundedQueueInner {
+                items: Mutex::new(VecDeque::new()),
+                capacity,
+                closed: AtomicBool::new(false),
+                notify: Notify::new(),
+            })
+        }
+    }
+
+    /// Push an item into the queue. If the queue is full the policy decides which item is discarded, the discarded
+    /// item is returned as an error. With `OverflowPolicy::Disconnect` the new item is returned and the caller is
+    /// responsible for closing the connection.
+    pub fn push(&self, item: T, policy: OverflowPolicy) -> Result<(), T> {
+        let mut items = self.inner.items.lock().expect("Queue lock poisoned");
+
+        let result = if items.len() < self.inner.capacity {
+            items.push_back(item);
+            Ok(())
+        } else {
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    let oldest = items.pop_front();
+                    items.push_back(item);
+                    oldest.map_or(Ok(()), Err)
+                }
+                OverflowPolicy::DropNewest | OverflowPolicy::Disconnect => Err(item),
+            }
+        };
+
+        drop(items);
+        self.inner.notify.notify();
+
+        result
+    }
+
+    /// Push an item into the queue, ignoring the capacity limit
+    pub fn push_unbounded(&self, item: T) {
+        self.inner.items.lock().expect("Queue lock poisoned").push_back(item);
+        self.inner.notify.notify();
+    }
+
+    /// Remove all items from the queue
+    pub fn clear(&self) {
+        self.inner.items.lock().expect("Queue lock poisoned").clear();
+    }
+
+    /// Wait for the next item in the queue. Returns `None` once the queue has been closed and is empty.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            if let Some(item) = self.inner.items.lock().expect("Queue lock poisoned").pop_front() {
+                return Some(item);
+            }
+
+            if self.inner.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            self.inner.notify.notified().await;
+        }
+    }
+
+    /// Close the queue. Items already in the queue can still be popped.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.notify.notify();
+    }
+
+    /// Number of items currently waiting in the queue
+    pub fn len(&self) -> usize {
+        self.inner.items.lock().expect("Queue lock poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An item in the event queue of a connection
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    Packet(SharedPacket),
+    Disconnect(DisconnectReason),
+}
+
+impl Outgoing {
+    fn into_packet(self) -> Option<Arc<Packet>> {
+        match self {
+            Outgoing::Packet(packet) => Some(packet.into_packet()),
+            Outgoing::Disconnect(_) => None,
+        }
+    }
+}
+
+/// The queues used to send data to a single connection. Events are pushed into the `events` queue by the ECS, the
+/// connection task then moves packets into a queue per stream. Unreliable packets skip the `events` queue and are
+/// pushed straight into the `datagrams` queue, so they are never held up behind reliable packets.
+#[derive(Clone)]
+pub struct SendQueue {
+    events: BoundedQueue<Outgoing>,
+    streams: Arc<Mutex<HashMap<StreamType, BoundedQueue<SharedPacket>>>>,
+    datagrams: BoundedQueue<SharedPacket>,
+
+    /// Set once a `Disconnect` has been queued, packets sent after it are discarded
+    disconnecting: Arc<AtomicBool>,
+
+    close_reason: Arc<Mutex<Option<DisconnectReason>>>,
+    stats: StatsRecorder,
+    config: QueueConfig,
+}
+
+impl std::fmt::Debug for SendQueue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendQueue")
+            .field("depth", &self.depth())
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+/// Number of items waiting in the send queues of a connection
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth {
+    pub events: usize,
+    pub streams: HashMap<StreamType, usize>,
+    pub datagrams: usize,
+}
+
+impl QueueDepth {
+    /// Number of items waiting in every queue added together
+    pub fn total(&self) -> usize {
+        self.events + self.streams.values().sum::<usize>() + self.datagrams
+    }
+}
+
+impl SendQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        SendQueue {
+            events: BoundedQueue::new(config.connection_capacity),
+            streams: Default::default(),
+            datagrams: BoundedQueue::new(config.datagram_capacity),
+            disconnecting: Default::default(),
+            close_reason: Default::default(),
+            stats: Default::default(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Queue an event to be sent. `Disconnect` events are never discarded. Packets sent after a `Disconnect` would
+    /// never be sent, so they are silently discarded, which also means they can't push the `Disconnect` out of a full
+    /// queue. If the queue is full the discarded packet (if any) is returned as an error. Datagrams never fail, when
+    /// their queue is full the oldest datagram is silently discarded. A `Broadcast` is sent to this connection alone,
+    /// the ECS sends it to each of its targets.
+    pub fn send(&self, event: SendEvent) -> Result<(), Option<Arc<Packet>>> {
+        match event {
+            SendEvent::Disconnect { reason, .. } => {
+                self.disconnecting.store(true, Ordering::Release);
+                self.events.push_unbounded(Outgoing::Disconnect(reason));
+                Ok(())
+            }
+
+            SendEvent::SendDatagram { data, .. } => {
+                self.push_datagram(SharedPacket::new(data));
+                Ok(())
+            }
+
+            SendEvent::SendPacket { data, .. } | SendEvent::Broadcast { data, .. } => self.send_shared(SharedPacket::new(data)),
+        }
+    }
+
+    /// Queue a packet to be sent, which may also be queued for other connections. It is sent in the same way as
+    /// `SendEvent::SendPacket`.
+    pub fn send_shared(&self, packet: SharedPacket) -> Result<(), Option<Arc<Packet>>> {
+        if self.is_disconnecting() {
+            trace!("Connection is closing, discarded packet");
+            return Ok(());
+        }
+
+        if packet.packet().delivery().reliability == Reliability::Unreliable {
+            self.push_datagram(packet);
+            return Ok(());
+        }
+
+        self.events
+            .push(Outgoing::Packet(packet), self.config.policy)
+            .map_err(Outgoing::into_packet)
+    }
+
+    fn push_datagram(&self, data: SharedPacket) {
+        if self.is_disconnecting() {
+            trace!("Connection is closing, discarded datagram");
+            return;
+        }
+
+        // Newer state supersedes older state, so when the queue is full the oldest datagram is the one to lose
+        if self.datagrams.push(data, OverflowPolicy::DropOldest).is_err() {
+            trace!("Datagram queue full, discarded oldest datagram");
+        }
+    }
+
+    /// Discard everything waiting to be sent and close the connection as soon as possible
+    pub fn disconnect_now(&self, reason: DisconnectReason) {
+        self.disconnecting.store(true, Ordering::Release);
+        self.events.clear();
+        self.events.push_unbounded(Outgoing::Disconnect(reason));
+    }
+
+    /// Check if a `Disconnect` has been queued
+    pub fn is_disconnecting(&self) -> bool {
+        self.disconnecting.load(Ordering::Acquire)
+    }
+
+    /// Record the reason this end is closing the connection, reported in the `Disconnected` event
+    pub(crate) fn set_close_reason(&self, reason: DisconnectReason) {
+        *self.close_reason.lock().expect("Queue lock poisoned") = Some(reason);
+    }
+
+    /// Get the reason this end closed the connection, if it has been closed locally
+    pub(crate) fn close_reason(&self) -> Option<DisconnectReason> {
+        self.close_reason.lock().expect("Queue lock poisoned").clone()
+    }
+
+    /// Discard everything waiting to be sent, for queues which no connection task is pumping
+    pub(crate) fn discard(&self) {
+        self.events.clear();
+        for queue in self.streams.lock().expect("Queue lock poisoned").values() {
+            queue.clear();
+        }
+        self.datagrams.clear();
+    }
+
+    /// Stop accepting events, the connection task will exit once all queued events have been sent
+    pub fn close(&self) {
+        self.events.close();
+    }
+
+    /// Get the queue for the given stream, creating it if necessary. Returns true if the queue was created.
+    pub(crate) fn stream(&self, stream: StreamType) -> (BoundedQueue<SharedPacket>, bool) {
+        let mut streams = self.streams.lock().expect("Queue lock poisoned");
+
+        if let Some(queue) = streams.get(&stream) {
+            return (queue.clone(), false);
+        }
+
+        let queue = BoundedQueue::new(self.config.stream_capacity);
+        streams.insert(stream, queue.clone());
+        (queue, true)
+    }
+
+    /// Close all stream queues and the datagram queue, stream tasks exit once their queues are empty
+    pub(crate) fn close_streams(&self) {
+        for queue in self.streams.lock().expect("Queue lock poisoned").values() {
+            queue.close();
+        }
+        self.datagrams.close();
+    }
+
+    pub(crate) fn datagrams(&self) -> BoundedQueue<SharedPacket> {
+        self.datagrams.clone()
+    }
+
+    pub(crate) async fn next_event(&self) -> Option<Outgoing> {
+        self.events.pop().await
+    }
+
+    /// Get the number of items waiting in each queue
+    pub fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            events: self.events.len(),
+            streams: self.streams
+                .lock()
+                .expect("Queue lock poisoned")
+                .iter()
+                .map(|(stream, queue)| (*stream, queue.len()))
+                .collect(),
+            datagrams: self.datagrams.len(),
+        }
+    }
+
+    /// Counts the traffic through this connection
+    pub(crate) fn recorder(&self) -> &StatsRecorder {
+        &self.stats
+    }
+
+    /// Get the traffic through this connection since it opened
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            queued: self.depth().total(),
+            ..self.stats.snapshot()
+        }
+    }
+}
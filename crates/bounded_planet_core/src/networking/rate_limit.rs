@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Notify, mpsc::UnboundedSender};
+use tracing::warn;
+
+use super::{
+    events::{DisconnectCode, DisconnectReason, ReceiveEvent},
+    id::ConnectionId,
+    packets::{Packet, PacketKind},
+    queue::SendQueue,
+};
+
+/// What to do when a peer exceeds a limit
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ViolationPolicy {
+    /// Discard the packet (or refuse the stream)
+    Drop,
+
+    /// Stop reading from the peer until the limit allows more. Unreliable packets can't be held back, so they are
+    /// discarded instead.
+    Throttle,
+
+    /// Close the connection
+    Disconnect,
+}
+
+/// A limit which a peer exceeded
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Violation {
+    /// More packets of this kind arrived than the rate limit allows
+    Packet(PacketKind),
+
+    /// The peer tried to open more than `RateLimitConfig::max_incoming_streams` streams at once
+    Streams,
+}
+
+/// A token bucket rate: up to `burst` packets may arrive at once, refilling at `per_second` packets per second
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Configures the limits on packets and streams received from each connection
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Rate limits for each kind of packet. Packets which are not listed are not limited.
+    pub packets: HashMap<PacketKind, Rate>,
+
+    /// Maximum number of incoming streams which may be open at once
+    pub max_incoming_streams: usize,
+
+    /// What to do when a peer exceeds any limit
+    pub policy: ViolationPolicy,
+}
+
+impl RateLimitConfig {
+    /// No limits, for connections to a trusted peer
+    pub fn unlimited() -> Self {
+        RateLimitConfig {
+            packets: Default::default(),
+            max_incoming_streams: usize::MAX,
+            policy: ViolationPolicy::Drop,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let mut packets = HashMap::new();
+
+        // Every request is answered with a large transfer
+        packets.insert(PacketKind::WorldTileDataRequest, Rate { burst: 16, per_second: 4.0 });
+        packets.insert(PacketKind::WorldInfoRequest, Rate { burst: 4, per_second: 1.0 });
+        packets.insert(PacketKind::TextChat, Rate { burst: 10, per_second: 2.0 });
+        packets.insert(PacketKind::AuthRequest, Rate { burst: 3, per_second: 0.5 });
+        packets.insert(PacketKind::Ping, Rate { burst: 10, per_second: 5.0 });
+
+        RateLimitConfig {
+            packets,
+            max_incoming_streams: 32,
+            policy: ViolationPolicy::Throttle,
+        }
+    }
+}
+
+/// Rate limits packets of a single kind
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    /// Take a token, or return how long to wait until one is available
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(f64::from(self.rate.burst));
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.rate.per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_second))
+        } else {
+            // The bucket never refills, so a throttled connection just checks again every so often
+            Err(Duration::from_secs(1))
+        }
+    }
+}
+
+/// Enforces the limits of a `RateLimitConfig` on a single connection. Shared by every task reading from the connection.
+pub(crate) struct RateLimiter {
+    id: ConnectionId,
+    config: Arc<RateLimitConfig>,
+    buckets: Mutex<HashMap<PacketKind, TokenBucket>>,
+    open_streams: AtomicUsize,
+    stream_closed: Notify,
+    queue: SendQueue,
+    event_sender: UnboundedSender<ReceiveEvent>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(id: ConnectionId, config: Arc<RateLimitConfig>, queue: SendQueue, event_sender: UnboundedSender<ReceiveEvent>) -> Arc<Self> {
+        Arc::new(RateLimiter {
+            id,
+            config,
+            buckets: Default::default(),
+            open_streams: AtomicUsize::new(0),
+            stream_closed: Notify::new(),
+            queue,
+            event_sender,
+        })
+    }
+
+    /// Check a packet received through a reliable stream. Returns true if the packet should be published, waiting
+    /// first if the connection is being throttled.
+    pub(crate) async fn admit(&self, packet: &Packet) -> bool {
+        loop {
+            let wait = match self.take(packet.kind()) {
+                Ok(()) => return true,
+                Err(wait) => wait,
+            };
+
+            self.violated(Violation::Packet(packet.kind()));
+            match self.config.policy {
+                ViolationPolicy::Throttle => tokio::time::delay_for(wait).await,
+                ViolationPolicy::Drop | ViolationPolicy::Disconnect => return false,
+            }
+        }
+    }
+
+    /// Check a packet received as a datagram. Returns true if the packet should be published.
+    pub(crate) fn admit_unreliable(&self, packet: &Packet) -> bool {
+        if self.take(packet.kind()).is_ok() {
+            return true;
+        }
+
+        self.violated(Violation::Packet(packet.kind()));
+        false
+    }
+
+    /// Reserve one of the incoming streams. The stream is released when the returned guard is dropped. Returns
+    /// `None` if the stream should be refused, waiting first if the connection is being throttled.
+    pub(crate) async fn open_stream(self: &Arc<Self>) -> Option<StreamGuard> {
+        loop {
+            let open = self.open_streams.fetch_add(1, Ordering::SeqCst);
+            if open < self.config.max_incoming_streams {
+                return Some(StreamGuard(self.clone()));
+            }
+            self.open_streams.fetch_sub(1, Ordering::SeqCst);
+
+            self.violated(Violation::Streams);
+            match self.config.policy {
+                ViolationPolicy::Throttle => self.stream_closed.notified().await,
+                ViolationPolicy::Drop | ViolationPolicy::Disconnect => return None,
+            }
+        }
+    }
+
+    fn take(&self, kind: PacketKind) -> Result<(), Duration> {
+        let rate = match self.config.packets.get(&kind) {
+            Some(rate) => *rate,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .expect("Rate limiter lock poisoned")
+            .entry(kind)
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .take(now)
+    }
+
+    /// Report a violation to the ECS, and close the connection if the policy requires it
+    fn violated(&self, violation: Violation) {
+        warn!("Connection {:?} exceeded limit: {:?}", self.id, violation);
+
+        let _ = self.event_sender.send(ReceiveEvent::LimitExceeded {
+            connection: self.id,
+            violation,
+            policy: self.config.policy,
+        });
+
+        if self.config.policy == ViolationPolicy::Disconnect {
+            self.queue.disconnect_now(DisconnectReason::new(DisconnectCode::RateLimited, format!("Exceeded limit: {:?}", violation)));
+        }
+    }
+}
+
+/// Holds one of the incoming streams of a connection open, see `RateLimiter::open_stream`
+pub(crate) struct StreamGuard(Arc<RateLimiter>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.open_streams.fetch_sub(1, Ordering::SeqCst);
+        self.0.stream_closed.notify();
+    }
+}
@@ -0,0 +1,80 @@
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    client::replication::ReplicationClientState,
+    server::replication::{ReplicationServerState, collect_component, REPLICATION_COLLECT_STAGE},
+};
+
+/// A component which can be replicated from the server to clients
+pub trait Replicate: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Identifies this type of component on the network. Must be unique among all replicated components.
+    const KIND: &'static str;
+}
+
+/// Add this component to an entity on the server to replicate it (and all of its replicated components) to clients
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Replicated;
+
+/// A replicated copy of the `Transform` of an entity
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NetworkTransform {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+impl Replicate for NetworkTransform {
+    const KIND: &'static str = "transform";
+}
+
+impl From<&Transform> for NetworkTransform {
+    fn from(transform: &Transform) -> Self {
+        let (scale, rotation, translation) = transform.value().to_scale_rotation_translation();
+        let rotation: Vec4 = rotation.into();
+
+        NetworkTransform {
+            translation: translation.into(),
+            rotation: rotation.into(),
+            scale: scale.into(),
+        }
+    }
+}
+
+impl From<&NetworkTransform> for Transform {
+    fn from(transform: &NetworkTransform) -> Self {
+        let rotation: Vec4 = transform.rotation.into();
+
+        Transform::new(Mat4::from_scale_rotation_translation(
+            transform.scale.into(),
+            rotation.into(),
+            transform.translation.into(),
+        ))
+    }
+}
+
+/// Extension methods to register replicated components with an `AppBuilder`
+pub trait AppReplicationExt {
+    /// Replicate components of type `T`. Must be called after the server or client `Replication` plugin is added.
+    fn replicate<T: Replicate>(&mut self) -> &mut Self;
+}
+
+impl AppReplicationExt for AppBuilder {
+    fn replicate<T: Replicate>(&mut self) -> &mut Self {
+        let is_server = self.resources().contains::<ReplicationServerState>();
+        let is_client = self.resources().contains::<ReplicationClientState>();
+        assert!(is_server || is_client, "`replicate` must be called after a `Replication` plugin is added");
+
+        // The server sends changes to these components to clients
+        if is_server {
+            self.add_system_to_stage(REPLICATION_COLLECT_STAGE, collect_component::<T>.system());
+        }
+
+        // The client applies changes to these components received from the server
+        if let Some(mut client) = self.resources_mut().get_mut::<ReplicationClientState>() {
+            client.register::<T>();
+        }
+
+        self
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tracing::warn;
+
+use super::{
+    events::{ReceiveEvent, SendEvent},
+    id::ConnectionId,
+    packets::{AuthRequest, AuthResponse, Packet, RequestId, RpcError, RpcPayload, WorldInfo, WorldInfoRequest, WorldTileData, WorldTileDataRequest},
+};
+
+/// A packet which can be sent as a request, and is answered with a packet of another type. Both must be variants of
+/// `RpcPayload`, as well as of `Packet` so they can be sent without a `RequestId`.
+pub trait Request: Sized + Send + Sync + 'static {
+    type Response: Send + Sync + 'static;
+
+    fn into_payload(self) -> RpcPayload;
+    fn from_payload(payload: &RpcPayload) -> Option<&Self>;
+
+    /// Get the request from a plain packet, sent without a `RequestId`
+    fn from_packet(packet: &Packet) -> Option<&Self>;
+
+    fn response_into_payload(response: Self::Response) -> RpcPayload;
+    fn response_from_payload(payload: &RpcPayload) -> Option<&Self::Response>;
+}
+
+macro_rules! impl_request {
+    ($request:ident => $response:ident) => {
+        impl Request for $request {
+            type Response = $response;
+
+            fn into_payload(self) -> RpcPayload {
+                RpcPayload::$request(self)
+            }
+
+            fn from_payload(payload: &RpcPayload) -> Option<&Self> {
+                match payload {
+                    RpcPayload::$request(request) => Some(request),
+                    _ => None,
+                }
+            }
+
+            fn from_packet(packet: &Packet) -> Option<&Self> {
+                match packet {
+                    Packet::$request(request) => Some(request),
+                    _ => None,
+                }
+            }
+
+            fn response_into_payload(response: Self::Response) -> RpcPayload {
+                RpcPayload::$response(response)
+            }
+
+            fn response_from_payload(payload: &RpcPayload) -> Option<&Self::Response> {
+                match payload {
+                    RpcPayload::$response(response) => Some(response),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_request!(WorldTileDataRequest => WorldTileData);
+impl_request!(AuthRequest => AuthResponse);
+impl_request!(WorldInfoRequest => WorldInfo);
+
+/// Identifies a request which has been sent, and the type of response it expects
+pub struct ResponseHandle<R> {
+    pub connection: ConnectionId,
+    pub id: RequestId,
+    _request: PhantomData<fn() -> R>,
+}
+
+impl<R> Clone for ResponseHandle<R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R> Copy for ResponseHandle<R> {}
+
+impl<R> fmt::Debug for ResponseHandle<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseHandle")
+            .field("connection", &self.connection)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<R: Request> ResponseHandle<R> {
+    /// Get the response to this request, if the event is about this request
+    pub fn get<'a>(&self, evt: &'a ResponseEvent) -> Option<Result<&'a R::Response, RpcError>> {
+        if evt.connection != self.connection || evt.id != self.id {
+            return None;
+        }
+
+        Some(evt.payload().and_then(|payload| {
+            R::response_from_payload(payload).ok_or_else(|| RpcError::UnexpectedResponse(payload.kind()))
+        }))
+    }
+}
+
+/// Published when a request sent with `Requests::send` is answered, or fails
+#[derive(Debug)]
+pub struct ResponseEvent {
+    pub connection: ConnectionId,
+    pub id: RequestId,
+
+    /// The `Packet::Response` which answered the request
+    result: Result<Arc<Packet>, RpcError>,
+}
+
+impl ResponseEvent {
+    /// Get the payload which answered the request, without checking its type
+    pub fn payload(&self) -> Result<&RpcPayload, RpcError> {
+        match &self.result {
+            Ok(packet) => match &**packet {
+                Packet::Response { response, .. } => Ok(response),
+                packet => Err(RpcError::UnexpectedResponse(packet.kind())),
+            },
+            Err(err) => Err(err.clone()),
+        }
+    }
+}
+
+/// A request received from a peer. Requests sent as plain packets (without a `RequestId`) are answered with plain
+/// packets, so peers which correlate responses by hand keep working.
+#[derive(Debug)]
+pub struct IncomingRequest<'a, R> {
+    pub connection: ConnectionId,
+    pub id: Option<RequestId>,
+    pub request: &'a R,
+}
+
+impl<'a, R: Request> IncomingRequest<'a, R> {
+    /// Get the request in an event, if it is a packet of this type
+    pub fn from_event(evt: &'a ReceiveEvent) -> Option<Self> {
+        let (connection, data) = match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => (*connection, data),
+            _ => return None,
+        };
+
+        match &**data {
+            Packet::Request { id, request } => R::from_payload(request).map(|request| IncomingRequest {
+                connection,
+                id: Some(*id),
+                request,
+            }),
+            packet => R::from_packet(packet).map(|request| IncomingRequest {
+                connection,
+                id: None,
+                request,
+            }),
+        }
+    }
+
+    /// Answer the request
+    pub fn respond(&self, sender: &mut Events<SendEvent>, response: R::Response) {
+        let response = R::response_into_payload(response);
+        let data = match self.id {
+            Some(id) => Packet::Response { id, response },
+            None => Packet::from(response),
+        };
+
+        sender.send(SendEvent::SendPacket {
+            connection: self.connection,
+            data: Arc::new(data),
+        });
+    }
+
+    /// Tell the peer the request could not be answered. Plain requests can not be failed, so nothing is sent.
+    pub fn fail(&self, sender: &mut Events<SendEvent>, error: RpcError) {
+        if let Some(id) = self.id {
+            sender.send(SendEvent::SendPacket {
+                connection: self.connection,
+                data: Arc::new(Packet::RequestFailed { id, error }),
+            });
+        }
+    }
+}
+
+impl<'a, R: Request + Clone> IncomingRequest<'a, R> {
+    /// Keep the request, to answer it in a later frame (e.g. once the data it asks for has loaded)
+    pub fn defer(&self) -> DeferredRequest<R> {
+        DeferredRequest {
+            connection: self.connection,
+            id: self.id,
+            request: self.request.clone(),
+        }
+    }
+}
+
+/// A request kept to be answered later, see `IncomingRequest::defer`
+#[derive(Debug, Clone)]
+pub struct DeferredRequest<R> {
+    pub connection: ConnectionId,
+    pub id: Option<RequestId>,
+    pub request: R,
+}
+
+impl<R: Request> DeferredRequest<R> {
+    /// Get the request back, to answer it
+    pub fn incoming(&self) -> IncomingRequest<'_, R> {
+        IncomingRequest {
+            connection: self.connection,
+            id: self.id,
+            request: &self.request,
+        }
+    }
+}
+
+/// Sends requests and tracks the ones which have not been answered yet. Each request is wrapped in a
+/// `Packet::Request` with a new `RequestId`, which the peer answers through `IncomingRequest`.
+#[derive(Default)]
+pub struct Requests {
+    next_id: u32,
+
+    /// Deadline of each request in flight
+    pending: HashMap<(ConnectionId, RequestId), Instant>,
+
+    event_reader: EventReader<ReceiveEvent>,
+}
+
+impl Requests {
+    /// Send a request. The response is published as a `ResponseEvent`, see `ResponseHandle::get`. If it does not
+    /// arrive within the timeout the request fails with `RpcError::TimedOut`.
+    pub fn send<R: Request>(
+        &mut self,
+        sender: &mut Events<SendEvent>,
+        connection: ConnectionId,
+        request: R,
+        timeout: Duration,
+    ) -> ResponseHandle<R> {
+        let id = RequestId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pending.insert((connection, id), Instant::now() + timeout);
+
+        sender.send(SendEvent::SendPacket {
+            connection,
+            data: Arc::new(Packet::Request {
+                id,
+                request: request.into_payload(),
+            }),
+        });
+
+        ResponseHandle {
+            connection,
+            id,
+            _request: PhantomData,
+        }
+    }
+
+    /// Number of requests which have not been answered yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn complete(&mut self, responses: &mut Events<ResponseEvent>, connection: ConnectionId, id: RequestId, result: Result<Arc<Packet>, RpcError>) {
+        if self.pending.remove(&(connection, id)).is_none() {
+            warn!("Connection {:?} answered request {:?}, which is not pending (it may have timed out)", connection, id);
+            return;
+        }
+
+        responses.send(ResponseEvent {
+            connection,
+            id,
+            result,
+        });
+    }
+
+    fn fail_where(&mut self, responses: &mut Events<ResponseEvent>, error: RpcError, mut predicate: impl FnMut(ConnectionId, Instant) -> bool) {
+        let failed: Vec<_> = self.pending
+            .iter()
+            .filter(|((connection, _), deadline)| predicate(*connection, **deadline))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for (connection, id) in failed {
+            self.complete(responses, connection, id, Err(error.clone()));
+        }
+    }
+}
+
+/// Match responses to the requests they answer, and fail requests which time out or whose connection closes
+pub fn rpc_system(
+    mut requests: ResMut<Requests>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut responses: ResMut<Events<ResponseEvent>>,
+) {
+    let requests: &mut Requests = &mut requests;
+
+    let mut answered = Vec::new();
+    let mut closed = Vec::new();
+    for evt in requests.event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => match **data {
+                Packet::Response { id, .. } => answered.push((*connection, id, Ok(data.clone()))),
+                Packet::RequestFailed { id, ref error } => answered.push((*connection, id, Err(error.clone()))),
+                _ => {}
+            },
+            ReceiveEvent::Disconnected { connection, .. } => closed.push(Some(*connection)),
+            ReceiveEvent::SocketClosed => closed.push(None),
+            _ => {}
+        }
+    }
+
+    for (connection, id, result) in answered {
+        requests.complete(&mut responses, connection, id, result);
+    }
+
+    // `None` means every connection closed along with the socket
+    for closed in closed {
+        requests.fail_where(&mut responses, RpcError::Disconnected, |connection, _| closed.map_or(true, |closed| closed == connection));
+    }
+
+    let now = Instant::now();
+    requests.fail_where(&mut responses, RpcError::TimedOut, |_, deadline| deadline <= now);
+}
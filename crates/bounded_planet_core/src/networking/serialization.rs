@@ -1,57 +1,175 @@
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
 use quinn::{ReadExactError, crypto::Session, generic::RecvStream};
 use quinn::{generic::SendStream, WriteError};
 
 use tracing::trace;
 
-use crate::networking::packets::*;
+use crate::networking::{codec::CodecConfig, packets::*};
 
 impl Packet {
-    /// Send this packet to a network stream. Should be read at the other end with `Packet::receive(stream)`
-    pub async fn send<T: Session>(&self, stream: &mut SendStream<T>) -> Result<(), SendError> {
-        // Encode packet into messagepack format
-        let bytes = rmp_serde::to_vec(self).map_err(SendError::EncodeError)?;
-
-        // Prefix with length (4 bytes, network order)
-        let len_bytes = (bytes.len() as u32).to_be_bytes();
-        stream
-            .write_all(&len_bytes)
-            .await
-            .map_err(SendError::WriteError)?;
-
-        // Write data to socket
-        stream
-            .write_all(&bytes)
-            .await
-            .map_err(SendError::WriteError)?;
-
-        trace!("Sent {} bytes", bytes.len());
-
-        Ok(())
+    /// Send this packet to a network stream. Should be read at the other end with `Packet::receive(stream, codec)`.
+    /// Returns the number of bytes written.
+    pub async fn send<T: Session>(&self, stream: &mut SendStream<T>, codec: &CodecConfig) -> Result<usize, SendError> {
+        let frame = codec.encode(self, self.stream_type())?;
+        write_frame(stream, &frame).await
     }
 
-    /// Receive a packet from a network stream. Should have been written with `packet.send(stream)`
-    pub async fn receive<T: Session>(recv: &mut RecvStream<T>) -> Result<Packet, RecvError> {
+    /// Receive a packet from a network stream of the given type. Should have been written with
+    /// `packet.send(stream, codec)`. Returns the packet and the number of bytes read.
+    pub async fn receive<T: Session>(recv: &mut RecvStream<T>, stream: StreamType, codec: &CodecConfig) -> Result<(Packet, usize), RecvError> {
         // Read 4 byte network ordered length prefix
         let mut length_prefix_buf = [0u8; 4];
         recv
             .read_exact(&mut length_prefix_buf)
             .await
             .map_err(RecvError::ReadExactError)?;
-        let length_prefix = u32::from_be_bytes(length_prefix_buf);
+        let length_prefix = u32::from_be_bytes(length_prefix_buf) as usize;
+
+        // Refuse to allocate space for frames larger than the limit
+        if length_prefix > codec.max_frame_size {
+            return Err(RecvError::FrameTooLarge {
+                size: length_prefix,
+                max: codec.max_frame_size,
+            });
+        }
 
         // Read that many bytes
-        let mut data = vec![0; length_prefix as usize];
+        let mut data = vec![0; length_prefix];
         recv
             .read_exact(&mut data.as_mut_slice())
             .await
             .map_err(RecvError::ReadExactError)?;
 
         // Decode it
-        let packet: Packet = rmp_serde::from_read_ref(&data).map_err(RecvError::DecodeError)?;
+        let packet = codec.decode(&data, stream)?;
 
         trace!("Received {} bytes", length_prefix);
 
-        Ok(packet)
+        Ok((packet, length_prefix_buf.len() + length_prefix))
+    }
+}
+
+impl Packet {
+    /// Encode this packet into a single datagram, prefixed with the type of stream it logically belongs to
+    pub fn to_datagram(&self, stream: StreamType, codec: &CodecConfig) -> Result<Bytes, SendError> {
+        Ok(datagram(stream, &codec.encode(self, stream)?))
+    }
+
+    /// Decode a datagram which was encoded with `packet.to_datagram(stream, codec)`
+    pub fn from_datagram(datagram: &[u8], codec: &CodecConfig) -> Result<(StreamType, Packet), RecvError> {
+        let (header, body) = match datagram.split_first() {
+            Some(split) => split,
+            None => return Err(RecvError::EmptyDatagram),
+        };
+
+        let stream = StreamType::try_from(*header).map_err(RecvError::UnknownStreamType)?;
+        let packet = codec.decode(body, stream)?;
+
+        trace!("Received {} byte datagram", datagram.len());
+
+        Ok((stream, packet))
+    }
+}
+
+/// Write an encoded frame to a network stream, prefixed with its length. Returns the number of bytes written.
+async fn write_frame<T: Session>(stream: &mut SendStream<T>, frame: &[u8]) -> Result<usize, SendError> {
+    // Prefix with length (4 bytes, network order)
+    let len_bytes = (frame.len() as u32).to_be_bytes();
+    stream
+        .write_all(&len_bytes)
+        .await
+        .map_err(SendError::WriteError)?;
+
+    // Write data to socket
+    stream
+        .write_all(frame)
+        .await
+        .map_err(SendError::WriteError)?;
+
+    trace!("Sent {} bytes", frame.len());
+
+    Ok(len_bytes.len() + frame.len())
+}
+
+/// Prefix an encoded frame with the type of stream it logically belongs to
+fn datagram(stream: StreamType, frame: &[u8]) -> Bytes {
+    let mut bytes = Vec::with_capacity(frame.len() + 1);
+    bytes.push(stream as u8);
+    bytes.extend_from_slice(frame);
+
+    Bytes::from(bytes)
+}
+
+/// A packet which is encoded the first time it is sent. Clones share the encoded frame, so a packet broadcast to many
+/// connections is only encoded once. Every connection of an app uses the same `CodecConfig`, so the frame is the same
+/// for all of them.
+#[derive(Debug, Clone)]
+pub struct SharedPacket {
+    packet: Arc<Packet>,
+    frame: Arc<Mutex<Option<Arc<Vec<u8>>>>>,
+}
+
+impl SharedPacket {
+    pub fn new(packet: Arc<Packet>) -> Self {
+        SharedPacket {
+            packet,
+            frame: Default::default(),
+        }
+    }
+
+    pub fn packet(&self) -> &Arc<Packet> {
+        &self.packet
+    }
+
+    pub fn into_packet(self) -> Arc<Packet> {
+        self.packet
+    }
+
+    /// Get the encoded frame, encoding the packet if this is the first time it is needed
+    pub fn frame(&self, codec: &CodecConfig) -> Result<Arc<Vec<u8>>, SendError> {
+        let mut frame = self.frame.lock().expect("Frame lock poisoned");
+        if let Some(frame) = &*frame {
+            return Ok(frame.clone());
+        }
+
+        let encoded = Arc::new(codec.encode(&self.packet, self.packet.stream_type())?);
+        *frame = Some(encoded.clone());
+        Ok(encoded)
+    }
+
+    /// Send this packet to a network stream, in the same way as `Packet::send`
+    pub async fn send<T: Session>(&self, stream: &mut SendStream<T>, codec: &CodecConfig) -> Result<usize, SendError> {
+        let frame = self.frame(codec)?;
+        write_frame(stream, &frame).await
+    }
+
+    /// Encode this packet into a single datagram, in the same way as `Packet::to_datagram`
+    pub fn to_datagram(&self, codec: &CodecConfig) -> Result<Bytes, SendError> {
+        Ok(datagram(self.packet.stream_type(), &self.frame(codec)?))
+    }
+}
+
+impl StreamType {
+    /// Write the header identifying this stream type. Must be sent once, before any packets are sent on a new stream.
+    pub async fn send_header<T: Session>(self, stream: &mut SendStream<T>) -> Result<(), SendError> {
+        stream
+            .write_all(&[self as u8])
+            .await
+            .map_err(SendError::WriteError)
+    }
+
+    /// Read the header identifying the type of a new stream. Should have been written with `stream_type.send_header(stream)`
+    pub async fn receive_header<T: Session>(recv: &mut RecvStream<T>) -> Result<StreamType, RecvError> {
+        let mut header = [0u8; 1];
+        recv
+            .read_exact(&mut header)
+            .await
+            .map_err(RecvError::ReadExactError)?;
+
+        StreamType::try_from(header[0]).map_err(RecvError::UnknownStreamType)
     }
 }
 
@@ -61,8 +179,23 @@ pub enum SendError {
     /// Sending a packet failed due to a serialisation error
     EncodeError(rmp_serde::encode::Error),
 
+    /// Sending a packet failed due to a serialisation error in the bincode format
+    BincodeEncodeError(bincode::Error),
+
+    /// Sending a packet failed while compressing it
+    CompressError(std::io::Error),
+
+    /// The encoded packet is larger than the maximum frame size
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+
     /// Sending a packet failed while writing to the socket
     WriteError(WriteError),
+
+    /// Sending a datagram failed
+    DatagramError(quinn::SendDatagramError),
 }
 
 #[derive(Debug)]
@@ -71,6 +204,30 @@ pub enum RecvError {
     /// Receiving a packet failed due to a deserialisation error
     DecodeError(rmp_serde::decode::Error),
 
+    /// Receiving a packet failed due to a deserialisation error in the bincode format
+    BincodeDecodeError(bincode::Error),
+
+    /// Receiving a packet failed while decompressing it
+    DecompressError(std::io::Error),
+
+    /// The peer sent a frame larger than the maximum frame size (before or after decompression)
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+
+    /// A frame was encoded in a format which is not known
+    UnknownFormat(u8),
+
+    /// A frame with no content was received
+    EmptyFrame,
+
     /// Receiving a packet failed while reading from the socket
     ReadExactError(ReadExactError),
+
+    /// A stream was opened with a header which does not identify any known stream type
+    UnknownStreamType(u8),
+
+    /// A datagram with no content was received
+    EmptyDatagram,
 }
@@ -0,0 +1,389 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use ring::{pbkdf2, rand::{SecureRandom, SystemRandom}};
+use thiserror::Error;
+use tokio::{
+    runtime::Handle,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+use tracing::{error, info, warn};
+
+use crate::networking::{
+    events::{DisconnectCode, DisconnectReason, ReceiveEvent, SendEvent},
+    id::ConnectionId,
+    packets::{AuthRequest, AuthResponse, Packet},
+    rpc::{DeferredRequest, IncomingRequest},
+    systems::{NetworkConnections, SessionEventListenerState},
+};
+
+/// Number of PBKDF2 iterations used when hashing new passwords
+const DEFAULT_HASH_ITERATIONS: u32 = 100_000;
+
+/// Length of the random salt generated for each new password
+const SALT_LEN: usize = 16;
+
+/// Length of the derived password hash
+const HASH_LEN: usize = 32;
+
+/// Salt and hash which passwords for unknown usernames are checked against, so that they take as long to refuse as
+/// an incorrect password
+const DUMMY_SALT: [u8; SALT_LEN] = [0; SALT_LEN];
+const DUMMY_HASH: [u8; HASH_LEN] = [0; HASH_LEN];
+
+/// A store of user credentials which the server can check authentication requests against
+pub trait CredentialStore: Send + Sync + 'static {
+    /// Check a username and password, returning `AuthResponse::Ok` if they are correct. This may be slow (e.g. hashing
+    /// the password), so it is called on a blocking task rather than in a system.
+    fn verify(&self, username: &str, password: &str) -> AuthResponse;
+}
+
+#[derive(Debug, Error)]
+pub enum CredentialStoreError {
+    #[error("Failed to access credential file {path:?}: {err}")]
+    Io {
+        path: PathBuf,
+        err: std::io::Error,
+    },
+
+    #[error("Malformed entry on line {line} of credential file {path:?}")]
+    MalformedEntry {
+        path: PathBuf,
+        line: usize,
+    },
+
+    #[error("Usernames may not be empty or contain ':'")]
+    InvalidUsername,
+
+    #[error("Failed to generate a random salt")]
+    RandomError,
+}
+
+/// A salted PBKDF2 hash of a password
+#[derive(Debug, Clone)]
+struct HashedPassword {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// A credential store backed by a file with one `username:iterations:salt:hash` entry per line.
+/// Salt and hash are hex encoded.
+#[derive(Debug, Default)]
+pub struct HashedFileCredentialStore {
+    users: HashMap<String, HashedPassword>,
+}
+
+impl HashedFileCredentialStore {
+    /// Load credentials from a file. A file which does not exist is treated as an empty store.
+    pub fn load(path: &Path) -> Result<Self, CredentialStoreError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(err) => return Err(CredentialStoreError::Io { path: path.to_owned(), err }),
+        };
+
+        let mut users = HashMap::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let malformed = || CredentialStoreError::MalformedEntry { path: path.to_owned(), line: idx + 1 };
+
+            let parts = line.split(':').collect::<Vec<_>>();
+            if let [username, iterations, salt, hash] = parts[..] {
+                let iterations = iterations.parse::<u32>().ok().and_then(NonZeroU32::new).ok_or_else(malformed)?;
+                let salt = hex::decode(salt).map_err(|_| malformed())?;
+                let hash = hex::decode(hash).map_err(|_| malformed())?;
+                users.insert(username.to_owned(), HashedPassword { iterations, salt, hash });
+            } else {
+                return Err(malformed());
+            }
+        }
+
+        Ok(HashedFileCredentialStore { users })
+    }
+
+    /// Write all credentials to a file, replacing it
+    pub fn save(&self, path: &Path) -> Result<(), CredentialStoreError> {
+        let io_err = |err| CredentialStoreError::Io { path: path.to_owned(), err };
+
+        let mut file = fs::File::create(path).map_err(io_err)?;
+        for (username, hashed) in &self.users {
+            writeln!(
+                file,
+                "{}:{}:{}:{}",
+                username,
+                hashed.iterations,
+                hex::encode(&hashed.salt),
+                hex::encode(&hashed.hash)
+            ).map_err(io_err)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a user (or replace the password of an existing user)
+    pub fn add_user(&mut self, username: &str, password: &str) -> Result<(), CredentialStoreError> {
+        if username.is_empty() || username.contains(':') {
+            return Err(CredentialStoreError::InvalidUsername);
+        }
+
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| CredentialStoreError::RandomError)?;
+
+        let iterations = NonZeroU32::new(DEFAULT_HASH_ITERATIONS).expect("Iteration count is zero");
+        let mut hash = vec![0; HASH_LEN];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+
+        self.users.insert(username.to_owned(), HashedPassword { iterations, salt, hash });
+
+        Ok(())
+    }
+}
+
+impl CredentialStore for HashedFileCredentialStore {
+    fn verify(&self, username: &str, password: &str) -> AuthResponse {
+        let hashed = match self.users.get(username) {
+            Some(hashed) => hashed,
+            None => {
+                // Otherwise the response time would reveal which usernames exist
+                let iterations = NonZeroU32::new(DEFAULT_HASH_ITERATIONS).expect("Iteration count is zero");
+                let _ = pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &DUMMY_SALT, password.as_bytes(), &DUMMY_HASH);
+                return AuthResponse::IncorrectUsername;
+            }
+        };
+
+        match pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, hashed.iterations, &hashed.salt, password.as_bytes(), &hashed.hash) {
+            Ok(()) => AuthResponse::Ok,
+            Err(_) => AuthResponse::IncorrectPassword,
+        }
+    }
+}
+
+/// This component is attached to a `Connection` once it has successfully authenticated
+#[derive(Debug, Clone)]
+pub struct Authenticated {
+    pub username: String,
+}
+
+/// Add this plugin (after the server `Network` plugin) to require all connections to authenticate before any
+/// other packets from them are published to the ECS. Must be added inside the tokio runtime.
+pub struct Auth {
+    pub store: Arc<dyn CredentialStore>,
+
+    /// Number of failed attempts after which a connection is closed
+    pub max_attempts: u32,
+
+    /// How long a connection may stay open without authenticating
+    pub timeout: Duration,
+}
+
+impl Plugin for Auth {
+    fn build(&self, app: &mut AppBuilder) {
+        app.resources_mut()
+            .get_mut::<SessionEventListenerState>()
+            .expect("`Auth` plugin must be added after the `Network` plugin")
+            .require_auth = true;
+
+        let (verified_sender, verified_receiver) = unbounded_channel();
+        app.add_resource(AuthState {
+            store: self.store.clone(),
+            max_attempts: self.max_attempts,
+            timeout: self.timeout,
+            event_reader: Default::default(),
+            pending: Default::default(),
+            verified_sender,
+            verified_receiver,
+            runtime: Handle::current(),
+        });
+
+        app.add_system(authenticate_connections.system());
+    }
+}
+
+/// A connection which has not yet authenticated
+struct PendingAuth {
+    opened: Instant,
+    failed_attempts: u32,
+
+    /// Set while a request from this connection is being verified, other requests are ignored until it completes
+    verifying: bool,
+}
+
+/// Internal state of the authentication system
+pub struct AuthState {
+    store: Arc<dyn CredentialStore>,
+    max_attempts: u32,
+    timeout: Duration,
+    event_reader: EventReader<ReceiveEvent>,
+    pending: HashMap<ConnectionId, PendingAuth>,
+
+    /// Requests which have been verified in the background, with no response if verification failed
+    verified_sender: UnboundedSender<(DeferredRequest<AuthRequest>, Option<AuthResponse>)>,
+    verified_receiver: UnboundedReceiver<(DeferredRequest<AuthRequest>, Option<AuthResponse>)>,
+
+    /// Runtime to verify requests on, systems may not run on a runtime thread
+    runtime: Handle,
+}
+
+impl AuthState {
+    /// Verify a request on a blocking task, the result is received through `verified_receiver`
+    fn verify(&self, request: DeferredRequest<AuthRequest>) {
+        let store = self.store.clone();
+        let sender = self.verified_sender.clone();
+        self.runtime.spawn(async move {
+            let AuthRequest { username, password } = request.request.clone();
+            let response = match tokio::task::spawn_blocking(move || store.verify(&username, &password)).await {
+                Ok(response) => Some(response),
+                Err(err) => {
+                    error!("Task verifying credentials of {:?} failed: {}", request.connection, err);
+                    None
+                }
+            };
+
+            // The server may have shut down, in which case nobody is waiting for the result
+            let _ = sender.send((request, response));
+        });
+    }
+}
+
+/// Verify authentication requests from unauthenticated connections in the background, then accept them or close
+/// connections which fail too many times
+fn authenticate_connections(
+    mut commands: Commands,
+    mut state: ResMut<AuthState>,
+    mut session: ResMut<SessionEventListenerState>,
+    connections: Res<NetworkConnections>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut sender: ResMut<Events<SendEvent>>,
+) {
+    let state: &mut AuthState = &mut state;
+
+    let mut to_verify = Vec::new();
+    for evt in state.event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::Connected(id, _) => {
+                state.pending.insert(*id, PendingAuth {
+                    opened: Instant::now(),
+                    failed_attempts: 0,
+                    verifying: false,
+                });
+            }
+
+            ReceiveEvent::Disconnected { connection, .. } => {
+                state.pending.remove(connection);
+            }
+
+            // A connection which resumed a session is already authenticated
+            ReceiveEvent::SessionResumed { replaced, .. } => {
+                state.pending.remove(replaced);
+            }
+
+            ReceiveEvent::ReceivedPacket { .. } => {
+                let request = match IncomingRequest::<AuthRequest>::from_event(evt) {
+                    Some(request) => request,
+                    None => continue,
+                };
+
+                // Ignore requests from connections which are already authenticated
+                let pending = match state.pending.get_mut(&request.connection) {
+                    Some(pending) => pending,
+                    None => continue,
+                };
+
+                if pending.verifying {
+                    warn!("Connection {:?} sent another authentication request while one is being verified", request.connection);
+                    continue;
+                }
+                pending.verifying = true;
+                to_verify.push(request.defer());
+            }
+
+            _ => {}
+        }
+    }
+
+    for request in to_verify {
+        state.verify(request);
+    }
+
+    // Answer the requests which have been verified
+    while let Ok((request, response)) = state.verified_receiver.try_recv() {
+        let connection = &request.connection;
+        let username = &request.request.username;
+
+        // The connection may have closed (or timed out) while its request was being verified
+        let pending = match state.pending.get_mut(connection) {
+            Some(pending) => pending,
+            None => continue,
+        };
+        pending.verifying = false;
+
+        // Verification failed without checking the credentials, so this does not count as an attempt. The client
+        // gets no response, and is closed if it does not try again before the timeout.
+        let response = match response {
+            Some(response) => response,
+            None => continue,
+        };
+
+        if response == AuthResponse::Ok {
+            info!("Connection {:?} authenticated as {:?}", connection, username);
+            state.pending.remove(connection);
+            session.unauthenticated.remove(connection);
+            if let Some(e) = connections.connections.get(connection) {
+                commands.insert_one(*e, Authenticated { username: username.clone() });
+            }
+            request.incoming().respond(&mut sender, response);
+            continue;
+        }
+
+        pending.failed_attempts += 1;
+        warn!("Connection {:?} failed to authenticate ({} attempts)", connection, pending.failed_attempts);
+
+        if pending.failed_attempts >= state.max_attempts {
+            state.pending.remove(connection);
+            request.incoming().respond(&mut sender, AuthResponse::TooManyAttempts);
+            sender.send(SendEvent::Disconnect {
+                connection: *connection,
+                reason: DisconnectReason::new(DisconnectCode::AuthenticationFailed, "Too many failed attempts"),
+            });
+        } else {
+            request.incoming().respond(&mut sender, response);
+        }
+    }
+
+    // Close connections which have not authenticated in time
+    let timeout = state.timeout;
+    let expired = state.pending
+        .iter()
+        .filter(|(_, p)| p.opened.elapsed() > timeout)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    for id in expired {
+        warn!("Connection {:?} did not authenticate in time", id);
+        state.pending.remove(&id);
+        send_auth_response(&mut sender, id, AuthResponse::TimedOut);
+        sender.send(SendEvent::Disconnect {
+            connection: id,
+            reason: DisconnectReason::new(DisconnectCode::AuthenticationFailed, "Did not authenticate in time"),
+        });
+    }
+}
+
+fn send_auth_response(sender: &mut Events<SendEvent>, connection: ConnectionId, response: AuthResponse) {
+    sender.send(SendEvent::SendPacket {
+        connection,
+        data: Arc::new(Packet::AuthResponse(response)),
+    });
+}
@@ -1 +1,5 @@
 pub mod plugin;
+pub mod auth;
+pub mod replication;
+pub mod session;
+pub mod shutdown;
@@ -9,27 +9,86 @@ use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tracing::info;
 
 use crate::networking::{
+    codec::CodecConfig,
+    crypto::{CertificateError, ClientAuth},
     events::{
         ReceiveEvent,
         SendEvent
     },
+    groups::{
+        NetworkGroups,
+        update_network_groups_system
+    },
+    handshake::{
+        ALPN_PROTOCOLS,
+        HandshakeState,
+        handshake_system
+    },
+    health::{
+        HealthConfig,
+        HealthState,
+        connection_health_system
+    },
+    loopback::{
+        LoopbackListener,
+        poll_loopback_connections
+    },
+    queue::QueueConfig,
+    rate_limit::RateLimitConfig,
+    rpc::{
+        Requests,
+        ResponseEvent,
+        rpc_system
+    },
+    stats::{
+        NetworkStats,
+        update_connection_stats_system
+    },
     systems::{
         Connecting,
         NetworkConnections,
         SessionEventListenerState,
         receive_net_events_system,
         send_net_events_system,
+        update_queue_depths_system,
         SEND_NET_EVENT_STAGE,
         RECEIVE_NET_EVENT_STAGE
     }
 };
 
+/// How the server accepts connections
+#[derive(Debug)]
+pub enum ServerTransport {
+    /// Listen for QUIC connections on a UDP socket
+    Quic {
+        private_key: quinn::PrivateKey,
+        certificate: quinn::CertificateChain,
+        addr: SocketAddr,
+
+        /// If set, only clients presenting a certificate accepted by this are admitted
+        client_auth: Option<ClientAuth>,
+    },
+
+    /// Accept in-memory connections from clients in the same process, see `networking::loopback`
+    Loopback(LoopbackListener),
+}
+
 /// Add this plugin to start a server which sends and receives packets to a large number of network connections
 #[derive(Debug)]
 pub struct Network {
-    pub private_key: quinn::PrivateKey,
-    pub certificate: quinn::CertificateChain,
-    pub addr: SocketAddr,
+    pub transport: ServerTransport,
+
+    /// Sizes and overflow policy of the send queues of each connection
+    pub queue_config: QueueConfig,
+
+    /// How packets are encoded, and the limits on packets received from clients
+    pub codec: CodecConfig,
+
+    /// Limits on the packets and streams each client may send
+    pub rate_limit: RateLimitConfig,
+
+    /// How the health of each connection is monitored
+    pub health_config: HealthConfig,
 }
 
 impl Plugin for Network {
@@ -41,6 +100,10 @@ impl Plugin for Network {
             event_receiver: recv,
             stream_senders: Default::default(),
             send_event_reader: Default::default(),
+            require_auth: false,
+            unauthenticated: Default::default(),
+            resumable: Default::default(),
+            aliases: Default::default(),
         });
 
         app.init_resource::<NetworkConnections>();
@@ -48,22 +111,59 @@ impl Plugin for Network {
         app.add_event::<ReceiveEvent>();
         app.add_event::<SendEvent>();
 
-        // Create listen socket
-        let listening = create_endpoint(
-            self.addr,
-            self.private_key.clone(),
-            self.certificate.clone(),
-        )
-        .expect("Failed to create socket");
-
-        // Spawn a task that polls the socket for events and sends them into an mspc
-        tokio::spawn(poll_new_connections(listening, send));
+        let codec = Arc::new(self.codec.clone());
+        let limits = Arc::new(self.rate_limit.clone());
+        match &self.transport {
+            ServerTransport::Quic { private_key, certificate, addr, client_auth } => {
+                // Create listen socket
+                let listening = create_endpoint(
+                    *addr,
+                    private_key.clone(),
+                    certificate.clone(),
+                    client_auth.as_ref(),
+                )
+                .expect("Failed to create socket");
+
+                // Spawn a task that polls the socket for events and sends them into an mspc
+                tokio::spawn(poll_new_connections(listening, send, self.queue_config, codec, limits));
+            }
+
+            // Spawn a task that accepts loopback connections
+            ServerTransport::Loopback(listener) => {
+                tokio::spawn(poll_loopback_connections(listener.take(), send, self.queue_config, codec, limits));
+            }
+        }
 
         // Add a system that consumes all network events from an MPSC and publishes them as ECS events
         app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, receive_net_events_system.system());
 
-        // Add a system that consumes ECS events and forwards them to MPSCs which will eventually be sent over the network
+        // Add a system that collects the connections in each group, just before broadcasts to groups are sent
+        app.init_resource::<NetworkGroups>();
+        app.add_system_to_stage(SEND_NET_EVENT_STAGE, update_network_groups_system.system());
+
+        // Add a system that consumes ECS events and forwards them to send queues which will eventually be sent over the network
         app.add_system_to_stage(SEND_NET_EVENT_STAGE, send_net_events_system.system());
+
+        // Add a system that publishes the depth of each connection's send queues to the ECS
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, update_queue_depths_system.system());
+
+        // Add a system that publishes the traffic through each connection, and the totals, to the ECS
+        app.init_resource::<NetworkStats>();
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, update_connection_stats_system.system());
+
+        // Add a system that exchanges protocol versions with every new connection
+        app.init_resource::<HandshakeState>();
+        app.add_system(handshake_system.system());
+
+        // Add a system that pings every connection and tracks its health
+        app.add_resource(self.health_config);
+        app.init_resource::<HealthState>();
+        app.add_system(connection_health_system.system());
+
+        // Add a system that matches responses to the requests they answer, and times out unanswered requests
+        app.init_resource::<Requests>();
+        app.add_event::<ResponseEvent>();
+        app.add_system_to_stage(RECEIVE_NET_EVENT_STAGE, rpc_system.system());
     }
 }
 
@@ -71,12 +171,15 @@ impl Plugin for Network {
 async fn poll_new_connections(
     mut incoming: Incoming<TlsSession>,
     event_sender: UnboundedSender<ReceiveEvent>,
+    queue_config: QueueConfig,
+    codec: Arc<CodecConfig>,
+    limits: Arc<RateLimitConfig>,
 ) {
     info!("Polling for incoming connections");
 
     // Keep polling for new incoming connections being opened
     while let Some(conn) = incoming.next().await {
-        tokio::spawn(Connecting::new(conn, event_sender.clone()).run());
+        tokio::spawn(Connecting::new(conn, event_sender.clone(), queue_config, codec.clone(), limits.clone()).run());
     }
 
     // Once the socket has closed notify the ECS about it. If sending this fails (because the ECS has stopped listening) just silently give up.
@@ -90,7 +193,10 @@ enum CreateEndpointError {
     TLSError(#[from] rustls::TLSError),
 
     #[error(transparent)]
-    EndpointError(#[from] quinn::EndpointError)
+    EndpointError(#[from] quinn::EndpointError),
+
+    #[error(transparent)]
+    CertificateError(#[from] CertificateError),
 }
 
 /// Create a network endpoint
@@ -98,6 +204,7 @@ fn create_endpoint(
     listen: SocketAddr,
     private_key: quinn::PrivateKey,
     certificate: quinn::CertificateChain,
+    client_auth: Option<&ClientAuth>,
 ) -> Result<Incoming<TlsSession>, CreateEndpointError>
 {
     // Configure endpoint
@@ -106,7 +213,7 @@ fn create_endpoint(
     let mut server_config = quinn::ServerConfig::default();
     server_config.transport = Arc::new(transport_config);
     let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(&[b"hq-29"]);
+    server_config.protocols(ALPN_PROTOCOLS);
 
     // Configure encryption
     server_config.certificate(
@@ -115,8 +222,15 @@ fn create_endpoint(
     )?;
 
     // Begin listening for connections, drop the endpoint because we don't need to establish any outgoing connections
+    let mut server_config = server_config.build();
+    if let Some(client_auth) = client_auth {
+        Arc::get_mut(&mut server_config.crypto)
+            .expect("Failed to get mutable reference to crypto configuration")
+            .set_client_certificate_verifier(client_auth.verifier()?);
+    }
+
     let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
+    endpoint.listen(server_config);
     let (_, incoming) = endpoint.bind(&listen)?;
 
     Ok(incoming)
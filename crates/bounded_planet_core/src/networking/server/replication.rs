@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy::prelude::*;
+use tracing::error;
+
+use crate::networking::{
+    components::Connection,
+    events::SendEvent,
+    handshake::PeerCapabilities,
+    id::{ConnectionId, NetworkEntityId},
+    packets::{ComponentData, Packet, Replication as ReplicationPacket, timestamp_now},
+    replication::{AppReplicationExt, NetworkTransform, Replicate, Replicated},
+    systems::{SessionEventListenerState, SEND_NET_EVENT_STAGE},
+};
+
+/// The stage at which the state of replicated components is collected
+pub const REPLICATION_COLLECT_STAGE: &str = "replication_collect";
+
+/// The stage at which changes to replicated entities are sent to clients
+pub const REPLICATION_SEND_STAGE: &str = "replication_send";
+
+/// Add this plugin (after the server `Network` plugin) to replicate entities marked with `Replicated` to every client
+/// which has completed the handshake (and authenticated, if the `Auth` plugin is used). Register replicated
+/// components with `app.replicate::<T>()`. `NetworkTransform` is always replicated, and is added to every replicated
+/// entity which has a `Transform`.
+pub struct Replication;
+
+impl Plugin for Replication {
+    fn build(&self, app: &mut AppBuilder) {
+        app.init_resource::<ReplicationServerState>();
+
+        app.add_stage_before(SEND_NET_EVENT_STAGE, REPLICATION_COLLECT_STAGE);
+        app.add_stage_after(REPLICATION_COLLECT_STAGE, REPLICATION_SEND_STAGE);
+
+        // Give every replicated entity a network ID
+        app.add_system_to_stage(stage::POST_UPDATE, assign_network_ids.system());
+
+        // Copy transforms into `NetworkTransform` so they are replicated
+        app.add_system_to_stage(stage::POST_UPDATE, update_network_transforms.system());
+
+        app.add_system_to_stage(REPLICATION_COLLECT_STAGE, track_replicated_entities.system());
+        app.add_system_to_stage(REPLICATION_SEND_STAGE, send_replication.system());
+
+        app.replicate::<NetworkTransform>();
+    }
+}
+
+/// Internal state of the server replication systems
+#[derive(Default)]
+pub struct ReplicationServerState {
+    /// The next network ID to assign
+    next_id: u64,
+
+    /// The latest encoded state of every replicated component of every replicated entity, by component kind
+    entities: HashMap<NetworkEntityId, HashMap<&'static str, Vec<u8>>>,
+
+    /// Entities which were seen this frame
+    alive: HashSet<NetworkEntityId>,
+
+    /// Components which changed this frame
+    changed: HashMap<NetworkEntityId, Vec<ComponentData>>,
+
+    /// Components which were removed this frame
+    removed: HashMap<NetworkEntityId, Vec<String>>,
+
+    /// The entities each client knows about
+    clients: HashMap<ConnectionId, HashSet<NetworkEntityId>>,
+}
+
+/// Assign a `NetworkEntityId` to every replicated entity which does not have one yet, and start replicating its
+/// transform if it has one
+fn assign_network_ids(
+    mut commands: Commands,
+    mut state: ResMut<ReplicationServerState>,
+    mut query: Query<Without<NetworkEntityId, (Entity, &Replicated, Option<&Transform>)>>,
+) {
+    for (entity, _, transform) in &mut query.iter() {
+        let id = NetworkEntityId(state.next_id);
+        state.next_id += 1;
+        commands.insert_one(entity, id);
+
+        if let Some(transform) = transform {
+            commands.insert_one(entity, NetworkTransform::from(transform));
+        }
+    }
+}
+
+/// Copy the `Transform` of replicated entities into their `NetworkTransform`
+fn update_network_transforms(mut query: Query<With<Replicated, (&Transform, &mut NetworkTransform)>>) {
+    for (transform, mut network) in &mut query.iter() {
+        *network = NetworkTransform::from(transform);
+    }
+}
+
+/// Record which replicated entities still exist
+fn track_replicated_entities(
+    mut state: ResMut<ReplicationServerState>,
+    mut query: Query<With<Replicated, &NetworkEntityId>>,
+) {
+    let state: &mut ReplicationServerState = &mut state;
+
+    for id in &mut query.iter() {
+        state.alive.insert(*id);
+        state.entities.entry(*id).or_default();
+    }
+}
+
+/// Record the state of every component of type `T` on replicated entities, and which of them changed
+pub(crate) fn collect_component<T: Replicate>(
+    mut state: ResMut<ReplicationServerState>,
+    mut query: Query<With<Replicated, (&NetworkEntityId, &T)>>,
+) {
+    let state: &mut ReplicationServerState = &mut state;
+
+    let mut seen = HashSet::new();
+    for (id, component) in &mut query.iter() {
+        seen.insert(*id);
+
+        let data = match rmp_serde::to_vec(component) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Failed to encode replicated component {}: {:?}", T::KIND, err);
+                continue;
+            }
+        };
+
+        // Compare against the last state sent, so only components which actually changed are sent
+        let components = state.entities.entry(*id).or_default();
+        if components.get(T::KIND) != Some(&data) {
+            components.insert(T::KIND, data.clone());
+            state.changed.entry(*id).or_default().push(ComponentData {
+                kind: T::KIND.to_owned(),
+                data,
+            });
+        }
+    }
+
+    // Find components which have been removed (or whose entity has been despawned)
+    for (id, components) in state.entities.iter_mut() {
+        if !seen.contains(id) && components.remove(T::KIND).is_some() {
+            state.removed.entry(*id).or_default().push(T::KIND.to_owned());
+        }
+    }
+}
+
+/// Send spawn, update and despawn packets to every client which is ready to receive them
+fn send_replication(
+    mut state: ResMut<ReplicationServerState>,
+    session: Res<SessionEventListenerState>,
+    mut connections: Query<(&Connection, &PeerCapabilities)>,
+    mut sender: ResMut<Events<SendEvent>>,
+) {
+    // Break up `state` in a way that Rust is happy with
+    let state: &mut ReplicationServerState = &mut state;
+    let ReplicationServerState { entities, alive, changed, removed, clients, .. } = state;
+
+    // Forget entities which no longer exist
+    let despawned = entities
+        .keys()
+        .filter(|id| !alive.contains(id))
+        .copied()
+        .collect::<Vec<_>>();
+    for id in &despawned {
+        entities.remove(id);
+    }
+
+    // Everything sent this frame is stamped with the same time
+    let server_time = timestamp_now();
+
+    // Build update packets once, they are shared by every client
+    let mut updates = HashMap::new();
+    for (id, changed) in changed.drain() {
+        updates.insert(id, ReplicationPacket::Update { entity: id, changed, removed: Vec::new(), server_time });
+    }
+    for (id, kinds) in removed.drain() {
+        let update = updates
+            .entry(id)
+            .or_insert_with(|| ReplicationPacket::Update { entity: id, changed: Vec::new(), removed: Vec::new(), server_time });
+        if let ReplicationPacket::Update { removed, .. } = update {
+            removed.extend(kinds);
+        }
+    }
+    let updates = updates
+        .into_iter()
+        .filter(|(id, _)| entities.contains_key(id))
+        .map(|(id, update)| (id, Arc::new(Packet::Replication(update))))
+        .collect::<HashMap<_, _>>();
+
+    // Forget clients which have disconnected
+    clients.retain(|id, _| session.stream_senders.contains_key(id));
+
+    for (connection, _) in &mut connections.iter() {
+        // Nothing is replicated until the client has authenticated
+        if session.unauthenticated.contains(&connection.id) {
+            continue;
+        }
+
+        let known = clients.entry(connection.id).or_default();
+
+        for id in &despawned {
+            if known.remove(id) {
+                sender.send(SendEvent::SendPacket {
+                    connection: connection.id,
+                    data: Arc::new(Packet::Replication(ReplicationPacket::Despawn { entity: *id })),
+                });
+            }
+        }
+
+        for (id, components) in entities.iter() {
+            // Send the full state of entities the client has not seen yet, and changes to all other entities
+            if known.insert(*id) {
+                let components = components
+                    .iter()
+                    .map(|(kind, data)| ComponentData { kind: (*kind).to_owned(), data: data.clone() })
+                    .collect();
+
+                sender.send(SendEvent::SendPacket {
+                    connection: connection.id,
+                    data: Arc::new(Packet::Replication(ReplicationPacket::Spawn { entity: *id, components, server_time })),
+                });
+            } else if let Some(update) = updates.get(id) {
+                sender.send(SendEvent::SendPacket {
+                    connection: connection.id,
+                    data: update.clone(),
+                });
+            }
+        }
+    }
+
+    alive.clear();
+}
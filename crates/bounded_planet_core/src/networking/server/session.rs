@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::{error, info};
+
+use crate::networking::{
+    components::Connection,
+    events::{ReceiveEvent, SendEvent},
+    id::ConnectionId,
+    packets::{Packet, Session, SessionToken},
+    systems::{NetworkConnections, SessionEventListenerState},
+};
+
+/// Add this plugin (after the server `Network` plugin, and `Auth` plugin if used) to issue session tokens to clients.
+/// When a client disconnects its entity is kept for the grace period, if it reconnects with its token in that time it
+/// takes over the old entity and `ConnectionId` instead of appearing as a new connection.
+pub struct Sessions {
+    /// How long the entity of a disconnected client is kept, waiting for it to resume its session
+    pub grace_period: Duration,
+}
+
+impl Plugin for Sessions {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(SessionState {
+            grace_period: self.grace_period,
+            event_reader: Default::default(),
+            sessions: Default::default(),
+            tokens: Default::default(),
+        });
+
+        app.add_system(manage_sessions.system());
+    }
+}
+
+/// A session which has been issued to a client
+struct SessionInfo {
+    /// The connection which owns this session
+    connection: ConnectionId,
+    entity: Entity,
+
+    /// Set once the connection is allowed to resume this session (i.e. once it has authenticated)
+    resumable: bool,
+
+    /// Set when the connection closed, the session can only be resumed until the grace period after this
+    suspended_at: Option<Instant>,
+}
+
+/// Internal state of the session system
+pub struct SessionState {
+    grace_period: Duration,
+    event_reader: EventReader<ReceiveEvent>,
+    sessions: HashMap<SessionToken, SessionInfo>,
+
+    /// Map from live connections to the token of their session
+    tokens: HashMap<ConnectionId, SessionToken>,
+}
+
+/// Create a new session owned by the given connection
+fn issue_session(
+    sessions: &mut HashMap<SessionToken, SessionInfo>,
+    tokens: &mut HashMap<ConnectionId, SessionToken>,
+    connection: ConnectionId,
+    entity: Entity,
+    resumable: bool,
+) -> Option<SessionToken> {
+    let mut token = [0; 32];
+    if SystemRandom::new().fill(&mut token).is_err() {
+        error!("Failed to generate a session token for {:?}", connection);
+        return None;
+    }
+
+    let token = SessionToken(token);
+    sessions.insert(token, SessionInfo { connection, entity, resumable, suspended_at: None });
+    tokens.insert(connection, token);
+
+    Some(token)
+}
+
+/// Issue and resume sessions, and clean up sessions which were not resumed in time
+fn manage_sessions(
+    mut commands: Commands,
+    mut state: ResMut<SessionState>,
+    mut session: ResMut<SessionEventListenerState>,
+    mut connections: ResMut<NetworkConnections>,
+    receiver: Res<Events<ReceiveEvent>>,
+    mut sender: ResMut<Events<SendEvent>>,
+) {
+    // Break up `state` in a way that Rust is happy with
+    let state: &mut SessionState = &mut state;
+    let SessionState { grace_period, event_reader, sessions, tokens } = state;
+    let grace_period = *grace_period;
+    let now = Instant::now();
+
+    for evt in event_reader.iter(&receiver) {
+        match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } => {
+                let resume = match &**data {
+                    Packet::Session(Session::Request { resume }) => resume,
+                    _ => continue,
+                };
+
+                // Each connection only gets one session
+                if tokens.contains_key(connection) {
+                    continue;
+                }
+
+                // Find the suspended session this connection wants to resume, if it is still within the grace period. The
+                // previous connection must have closed (the server may take a while to notice a dead connection).
+                let resumed = resume.and_then(|token| {
+                    let suspended_at = sessions.get(&token)?.suspended_at?;
+                    if now - suspended_at <= grace_period {
+                        sessions.remove(&token)
+                    } else {
+                        None
+                    }
+                });
+
+                let token = if let Some(previous) = resumed {
+                    let (old, new) = (previous.connection, *connection);
+                    info!("Connection {:?} resumed the session of {:?}", new, old);
+
+                    // Move the queue of the new connection to the old ID, events from the new connection will use
+                    // the old ID from now on
+                    if let Some(queue) = session.stream_senders.remove(&new) {
+                        session.stream_senders.insert(old, queue);
+                    }
+                    session.aliases.insert(new, old);
+                    session.unauthenticated.remove(&new);
+                    session.resumable.insert(old);
+
+                    // Replace the entity of the new connection with the entity of the old one
+                    if let Some(e) = connections.connections.remove(&new) {
+                        commands.despawn(e);
+                    }
+                    connections.connections.insert(old, previous.entity);
+                    commands.insert_one(previous.entity, Connection { id: old });
+
+                    let _ = session.event_sender.send(ReceiveEvent::SessionResumed {
+                        connection: old,
+                        replaced: new,
+                    });
+
+                    // Tokens are single use, issue a new one for the resumed session
+                    issue_session(sessions, tokens, old, previous.entity, true).map(|token| (old, token, true))
+                } else {
+                    match connections.connections.get(connection) {
+                        Some(e) => issue_session(sessions, tokens, *connection, *e, false).map(|token| (*connection, token, false)),
+                        None => None,
+                    }
+                };
+
+                if let Some((connection, token, resumed)) = token {
+                    sender.send(SendEvent::SendPacket {
+                        connection,
+                        data: Arc::new(Packet::Session(Session::Accepted { token, resumed })),
+                    });
+                }
+            }
+
+            // Keep the sessions of connections which closed for the grace period
+            ReceiveEvent::Disconnected { connection, .. } => {
+                if let Some(token) = tokens.remove(connection) {
+                    match sessions.get_mut(&token) {
+                        Some(info) if info.resumable => info.suspended_at = Some(now),
+                        _ => {
+                            sessions.remove(&token);
+                        }
+                    }
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    // Sessions become resumable once their connection has authenticated
+    for (connection, token) in tokens.iter() {
+        if let Some(info) = sessions.get_mut(token) {
+            if !info.resumable && !session.unauthenticated.contains(connection) {
+                info.resumable = true;
+                session.resumable.insert(*connection);
+            }
+        }
+    }
+
+    // Clean up sessions which were not resumed in time
+    let expired = sessions
+        .iter()
+        .filter(|(_, info)| info.suspended_at.map_or(false, |t| now - t > grace_period))
+        .map(|(token, _)| *token)
+        .collect::<Vec<_>>();
+    for token in expired {
+        if let Some(info) = sessions.remove(&token) {
+            info!("Session of {:?} expired", info.connection);
+            commands.despawn(info.entity);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::{app::AppExit, prelude::*};
+use tracing::{info, warn};
+
+use crate::networking::{
+    events::{DisconnectCode, DisconnectReason, SendEvent},
+    id::ConnectionId,
+    systems::SessionEventListenerState,
+};
+
+/// Add this plugin (after the server `Network` plugin) to shut the server down cleanly. Once shutdown is requested
+/// through the `ShutdownHandle` resource every connection is closed with `DisconnectCode::Shutdown`, and the app
+/// exits once they have all closed (or the timeout passes).
+pub struct Shutdown {
+    /// How long to wait for connections to close before exiting anyway
+    pub timeout: Duration,
+
+    /// Handle used to request shutdown, clone it before adding the plugin to request shutdown from outside the ECS
+    pub handle: ShutdownHandle,
+}
+
+impl Plugin for Shutdown {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_resource(self.handle.clone());
+        app.add_resource(ShutdownState {
+            timeout: self.timeout,
+            draining_since: None,
+            closed: Default::default(),
+        });
+
+        app.add_system(shutdown_system.system());
+    }
+}
+
+/// Requests the server to shut down. Can be cloned and used from any thread (e.g. a signal handler).
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Request the server to close all connections and exit
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Internal state of the shutdown system
+pub struct ShutdownState {
+    timeout: Duration,
+
+    /// Set once shutdown has started
+    draining_since: Option<Instant>,
+
+    /// Connections which have been told to close
+    closed: HashSet<ConnectionId>,
+}
+
+/// Close every connection once shutdown is requested, and exit once they have all closed
+fn shutdown_system(
+    mut state: ResMut<ShutdownState>,
+    handle: Res<ShutdownHandle>,
+    session: Res<SessionEventListenerState>,
+    mut sender: ResMut<Events<SendEvent>>,
+    mut exit: ResMut<Events<AppExit>>,
+) {
+    if !handle.is_requested() {
+        return;
+    }
+
+    let draining_since = match state.draining_since {
+        Some(t) => t,
+        None => {
+            info!("Shutting down, closing {} connections", session.stream_senders.len());
+            let now = Instant::now();
+            state.draining_since = Some(now);
+            now
+        }
+    };
+
+    // Close every connection, including ones which opened after shutdown started
+    for connection in session.stream_senders.keys() {
+        if state.closed.insert(*connection) {
+            sender.send(SendEvent::Disconnect {
+                connection: *connection,
+                reason: DisconnectReason::new(DisconnectCode::Shutdown, "Server shutting down"),
+            });
+        }
+    }
+
+    // Connections are removed from the session state once they have closed
+    if session.stream_senders.is_empty() {
+        info!("All connections closed, exiting");
+        exit.send(AppExit);
+    } else if draining_since.elapsed() > state.timeout {
+        warn!("{} connections did not close in time, exiting anyway", session.stream_senders.len());
+        exit.send(AppExit);
+    }
+}
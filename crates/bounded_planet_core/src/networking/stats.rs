@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    ops::AddAssign,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use tracing::info;
+
+use super::{
+    components::Connection,
+    packets::StreamType,
+    systems::SessionEventListenerState,
+};
+
+/// Number of packets and bytes which passed through a connection in one direction
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+impl AddAssign for Traffic {
+    fn add_assign(&mut self, other: Self) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Traffic through a connection in both directions
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ChannelStats {
+    pub sent: Traffic,
+    pub received: Traffic,
+}
+
+impl AddAssign for ChannelStats {
+    fn add_assign(&mut self, other: Self) {
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+/// This component holds counters of the traffic through a `Connection` since it opened, updated every frame
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    /// Traffic through each logical stream, including packets which were sent as datagrams
+    pub streams: HashMap<StreamType, ChannelStats>,
+
+    /// Traffic sent as datagrams. These packets are also counted in `streams`.
+    pub datagrams: ChannelStats,
+
+    /// Number of QUIC streams open in either direction
+    pub open_streams: usize,
+
+    /// Number of events and packets waiting in the send queues
+    pub queued: usize,
+
+    /// Number of errors reported for the connection, see `NetworkError`
+    pub errors: u64,
+}
+
+impl ConnectionStats {
+    /// Get the traffic through every stream added together
+    pub fn total(&self) -> ChannelStats {
+        let mut total = ChannelStats::default();
+        for stats in self.streams.values() {
+            total += *stats;
+        }
+        total
+    }
+
+    fn add(&mut self, other: &ConnectionStats) {
+        for (stream, stats) in &other.streams {
+            *self.streams.entry(*stream).or_default() += *stats;
+        }
+        self.datagrams += other.datagrams;
+        self.open_streams += other.open_streams;
+        self.queued += other.queued;
+        self.errors += other.errors;
+    }
+}
+
+/// Totals of the traffic through every connection since the network plugin started, updated every frame
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    /// Number of open connections
+    pub connections: usize,
+
+    /// Traffic through every connection, including connections which have closed
+    pub total: ConnectionStats,
+
+    /// Traffic through connections which have closed, and errors which did not occur on an open connection
+    closed: ConnectionStats,
+}
+
+impl NetworkStats {
+    /// Add the final stats of a connection which has closed to the totals
+    pub(crate) fn connection_closed(&mut self, stats: ConnectionStats) {
+        self.closed.add(&ConnectionStats {
+            open_streams: 0,
+            queued: 0,
+            ..stats
+        });
+    }
+
+    /// Count an error which did not occur on an open connection
+    pub(crate) fn error(&mut self) {
+        self.closed.errors += 1;
+    }
+}
+
+/// Counts the traffic through a single connection. Shared by the `SendQueue` of the connection and every task
+/// pumping it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StatsRecorder(Arc<Mutex<ConnectionStats>>);
+
+impl StatsRecorder {
+    fn update(&self, update: impl FnOnce(&mut ConnectionStats)) {
+        update(&mut self.0.lock().expect("Stats lock poisoned"));
+    }
+
+    /// Count a packet sent through a stream
+    pub(crate) fn sent(&self, stream: StreamType, bytes: usize) {
+        self.update(|stats| stats.streams.entry(stream).or_default().sent.record(bytes));
+    }
+
+    /// Count a packet received through a stream
+    pub(crate) fn received(&self, stream: StreamType, bytes: usize) {
+        self.update(|stats| stats.streams.entry(stream).or_default().received.record(bytes));
+    }
+
+    /// Count a packet sent as a datagram
+    pub(crate) fn sent_datagram(&self, stream: StreamType, bytes: usize) {
+        self.update(|stats| {
+            stats.streams.entry(stream).or_default().sent.record(bytes);
+            stats.datagrams.sent.record(bytes);
+        });
+    }
+
+    /// Count a packet received as a datagram
+    pub(crate) fn received_datagram(&self, stream: StreamType, bytes: usize) {
+        self.update(|stats| {
+            stats.streams.entry(stream).or_default().received.record(bytes);
+            stats.datagrams.received.record(bytes);
+        });
+    }
+
+    pub(crate) fn error(&self) {
+        self.update(|stats| stats.errors += 1);
+    }
+
+    /// Count a stream as open until the returned guard is dropped
+    pub(crate) fn open_stream(&self) -> OpenStream {
+        self.update(|stats| stats.open_streams += 1);
+        OpenStream(self.clone())
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        self.0.lock().expect("Stats lock poisoned").clone()
+    }
+}
+
+/// Counts a stream as open, see `StatsRecorder::open_stream`
+pub(crate) struct OpenStream(StatsRecorder);
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.update(|stats| stats.open_streams -= 1);
+    }
+}
+
+/// Copy the stats of each connection into the ECS and add up the totals
+pub fn update_connection_stats_system(
+    session: Res<SessionEventListenerState>,
+    mut totals: ResMut<NetworkStats>,
+    mut query: Query<(&Connection, &mut ConnectionStats)>,
+) {
+    let totals: &mut NetworkStats = &mut totals;
+    totals.connections = session.stream_senders.len();
+    totals.total = totals.closed.clone();
+
+    for queue in session.stream_senders.values() {
+        totals.total.add(&queue.stats());
+    }
+
+    for (connection, mut stats) in &mut query.iter() {
+        if let Some(queue) = session.stream_senders.get(&connection.id) {
+            *stats = queue.stats();
+        }
+    }
+}
+
+/// Configures how often `log_network_stats_system` logs the traffic totals
+#[derive(Debug, Clone, Copy)]
+pub struct StatsLogConfig {
+    pub interval: Duration,
+}
+
+impl Default for StatsLogConfig {
+    fn default() -> Self {
+        StatsLogConfig {
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct StatsLogState {
+    last: Option<Instant>,
+}
+
+/// Log the traffic totals every `StatsLogConfig::interval`
+pub fn log_network_stats_system(config: Res<StatsLogConfig>, stats: Res<NetworkStats>, mut state: Local<StatsLogState>) {
+    // The first dump is a full interval after startup
+    let now = Instant::now();
+    let last = *state.last.get_or_insert(now);
+    if now - last < config.interval {
+        return;
+    }
+    state.last = Some(now);
+
+    let total = stats.total.total();
+    info!(
+        "Network stats: {} connections, sent {} packets ({} bytes), received {} packets ({} bytes), {} open streams, {} queued, {} errors",
+        stats.connections,
+        total.sent.packets,
+        total.sent.bytes,
+        total.received.packets,
+        total.received.bytes,
+        stats.total.open_streams,
+        stats.total.queued,
+        stats.total.errors,
+    );
+
+    for (stream, channel) in &stats.total.streams {
+        info!("  {:?}: sent {:?}, received {:?}", stream, channel.sent, channel.received);
+    }
+}
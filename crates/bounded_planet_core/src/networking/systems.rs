@@ -1,19 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::sync::Arc;
 
-use bevy::prelude::{Commands, Entity, EventReader, Events, ResMut};
-use quinn::{IncomingUniStreams, crypto::rustls::TlsSession, generic::RecvStream};
+use bevy::prelude::{Commands, Entity, EventReader, Events, Query, Res, ResMut};
+use futures::{FutureExt, stream::FuturesUnordered};
+use quinn::{Datagrams, IncomingUniStreams, VarInt, crypto::rustls::TlsSession, generic::RecvStream};
 use tokio::{
     stream::StreamExt,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel}
+    sync::mpsc::{UnboundedReceiver, UnboundedSender}
 };
-use tracing::{error, info, warn};
+use tracing::{error, info, trace, warn};
 
 use super::{
-    components::Connection,
-    events::{NetworkError, ReceiveEvent, SendEvent},
+    codec::CodecConfig,
+    rate_limit::{RateLimitConfig, RateLimiter, StreamGuard},
+    components::{Connection, PeerIdentity, SendQueueDepth},
+    crypto::fingerprint,
+    events::{DisconnectCode, DisconnectReason, NetworkError, ReceiveEvent, SendEvent, SendTarget},
+    groups::NetworkGroups,
+    health::ConnectionHealth,
     id::ConnectionId,
-    packets::Packet,
+    packets::{Ordering, Packet, StreamType},
+    queue::{BoundedQueue, Outgoing, OverflowPolicy, QueueConfig, SendQueue},
+    serialization::{SendError, SharedPacket},
+    stats::{ConnectionStats, NetworkStats, StatsRecorder},
 };
 
 /// The stage at which [`SendEvent`]s are sent across the network.
@@ -31,7 +41,10 @@ pub fn log_net_events(mut state: ResMut<NetEventLoggerState>, receiver: ResMut<E
     for evt in state.event_reader.iter(&receiver) {
         match evt {
             ReceiveEvent::Connected(cid, _) => info!("New Connection: {:?}", cid),
-            ReceiveEvent::Disconnected(cid) => info!("Disconnected: {:?}", cid),
+            ReceiveEvent::Disconnected { connection, reason, by_peer } => info!("Disconnected: {:?} {} (by peer: {})", connection, reason, by_peer),
+            ReceiveEvent::HandshakeRejected { connection, reason } => error!("Handshake Rejected: {:?} {}", connection, reason),
+            ReceiveEvent::PeerSilent { connection, silent_for } => warn!("Peer Silent: {:?} for {:?}", connection, silent_for),
+            ReceiveEvent::LimitExceeded { connection, violation, policy } => warn!("Limit Exceeded: {:?} {:?} (policy: {:?})", connection, violation, policy),
             ReceiveEvent::SocketClosed => warn!("Socket Closed"),
             ReceiveEvent::NetworkError(err) => error!("Network Error: {:?}", err),
 
@@ -42,8 +55,8 @@ pub fn log_net_events(mut state: ResMut<NetEventLoggerState>, receiver: ResMut<E
 
 /// Internal state of the network session system
 pub struct SessionEventListenerState {
-    /// Map from ConnectionID => PacketSender MPSC
-    pub stream_senders: HashMap<ConnectionId, UnboundedSender<SendEvent>>,
+    /// Map from ConnectionID => queue of events to send to that connection
+    pub stream_senders: HashMap<ConnectionId, SendQueue>,
 
     /// MPSC sender for the accompanying event_receiver
     pub event_sender: UnboundedSender<ReceiveEvent>,
@@ -53,6 +66,21 @@ pub struct SessionEventListenerState {
 
     /// Event reader used for pulling send events and publishing them to the network
     pub send_event_reader: EventReader<SendEvent>,
+
+    /// If set, new connections are added to `unauthenticated` when they open
+    pub require_auth: bool,
+
+    /// Connections which have not yet authenticated. Packets from these connections are not published to the ECS
+    /// unless they are allowed before authentication (see `Packet::allowed_before_auth`).
+    pub unauthenticated: HashSet<ConnectionId>,
+
+    /// Connections whose entity is kept (without its `Connection` component) when they close, so that their session
+    /// can be resumed
+    pub resumable: HashSet<ConnectionId>,
+
+    /// Map from the ID of a connection which resumed a session to the ID of the connection it replaced. Events to
+    /// and from the new connection use the old ID.
+    pub aliases: HashMap<ConnectionId, ConnectionId>,
 }
 
 /// ECS resources containing a map of active network connections
@@ -66,6 +94,7 @@ pub fn receive_net_events_system(
     mut commands: Commands,
     mut session: ResMut<SessionEventListenerState>,
     mut entities: ResMut<NetworkConnections>,
+    mut stats: ResMut<NetworkStats>,
     mut net_events: ResMut<Events<ReceiveEvent>>
 ) {
     // Break up `session` in a way that Rust is happy with
@@ -73,7 +102,15 @@ pub fn receive_net_events_system(
     let SessionEventListenerState { event_receiver, .. } = session;
 
     // Pull network events from MPSC and publish them
-    while let Ok(event) = event_receiver.try_recv() {
+    while let Ok(mut event) = event_receiver.try_recv() {
+
+        // Events from a connection which resumed a session use the ID of the session's original connection
+        if let Some(connection) = event.connection_mut() {
+            if let Some(alias) = session.aliases.get(connection) {
+                *connection = *alias;
+            }
+        }
+
         match event {
 
             // A new connection has opened, allocate an entry in the hashmap
@@ -82,24 +119,55 @@ pub fn receive_net_events_system(
                 // Create an entity representing this connection
                 commands.spawn((
                     Connection { id },
+                    SendQueueDepth::default(),
+                    ConnectionHealth::default(),
+                    ConnectionStats::default(),
                 ));
                 entities.connections.insert(id, commands.current_entity().expect("`spawn` did not create an entity"));
 
-                // Store the MPSC to send to this stream in the hashmap
+                // Store the queue to send to this connection in the hashmap
                 session.stream_senders.insert(id, packet_sender.clone());
+
+                // Hold back packets from this connection until it authenticates
+                if session.require_auth {
+                    session.unauthenticated.insert(id);
+                }
             }
 
-            ReceiveEvent::Disconnected(id) => {
+            // Attach the identity of the peer to the entity representing its connection
+            ReceiveEvent::PeerIdentified { connection, ref identity } => {
+                if let Some(e) = entities.connections.get(&connection) {
+                    commands.insert_one(*e, identity.clone());
+                }
+            }
+
+            // Refuse packets from connections which have not authenticated yet
+            ReceiveEvent::ReceivedPacket { connection, ref data, .. } if session.unauthenticated.contains(&connection) && !data.allowed_before_auth() => {
+                warn!("Discarding packet from unauthenticated connection {:?}", connection);
+                continue;
+            }
 
-                // Delete the entity representing this connection
+            ReceiveEvent::Disconnected { connection: id, .. } => {
+
+                // Delete the entity representing this connection. If the session may be resumed keep the entity,
+                // but remove the `Connection` so it is no longer treated as connected.
                 if let Some(e) = entities.connections.remove(&id) {
-                    commands.despawn(e);
+                    if session.resumable.remove(&id) {
+                        commands.remove_one::<Connection>(e);
+                    } else {
+                        commands.despawn(e);
+                    }
                 } else {
                     warn!("Failed to delete connection Entity for ConnectionId:{:?}", id);
                 }
 
-                // drop all stream senders
-                session.stream_senders.remove(&id);
+                // drop all stream senders, keeping the traffic through this connection in the totals
+                if let Some(queue) = session.stream_senders.remove(&id) {
+                    stats.connection_closed(queue.stats());
+                    queue.close();
+                }
+                session.unauthenticated.remove(&id);
+                session.aliases.retain(|_, alias| *alias != id);
             }
 
             // When the socket closes throw away all session state
@@ -111,7 +179,21 @@ pub fn receive_net_events_system(
                 }
 
                 // drop all stream senders
-                session.stream_senders.clear();
+                for (_, queue) in session.stream_senders.drain() {
+                    stats.connection_closed(queue.stats());
+                    queue.close();
+                }
+                session.unauthenticated.clear();
+                session.resumable.clear();
+                session.aliases.clear();
+            }
+
+            // Count errors against the connection they occurred on
+            ReceiveEvent::NetworkError(ref mut err) => {
+                match err.connection_mut().and_then(|connection| session.stream_senders.get(connection)) {
+                    Some(queue) => queue.recorder().error(),
+                    None => stats.error(),
+                }
             }
 
             _ => {}
@@ -122,28 +204,81 @@ pub fn receive_net_events_system(
     }
 }
 
-/// Take ECS events and forward them to MPSCs to be sent over the network
-pub fn send_net_events_system(mut session: ResMut<SessionEventListenerState>, send_events: ResMut<Events<SendEvent>>)
+/// Take ECS events and forward them to the send queues of each connection to be sent over the network
+pub fn send_net_events_system(mut session: ResMut<SessionEventListenerState>, groups: Res<NetworkGroups>, send_events: ResMut<Events<SendEvent>>)
 {
-    // Publish packets ready to send to appropriate MPSC channels
-    for send in session.send_event_reader.iter(&send_events)
+    let SessionEventListenerState { send_event_reader, stream_senders, aliases, event_sender, .. } = &mut *session;
+    let resolve = |connection: ConnectionId| aliases.get(&connection).copied().unwrap_or(connection);
+
+    // Publish packets ready to send to the appropriate queues
+    for send in send_event_reader.iter(&send_events)
     {
-        // Try to get the MPSC sender for this connection, early exit if it does not exist
-        let connection = send.get_connection();
-        let sender = if let Some(sender) = session.stream_senders.get(&connection) {
-            sender
-        } else {
-            error!("Attempted to send to a non-existant connection: {:?}", connection);
-            continue;
-        };
+        match send {
+            SendEvent::Broadcast { target, data } => {
+                let connections: Vec<ConnectionId> = match target {
+                    SendTarget::All => stream_senders.keys().copied().collect(),
+                    SendTarget::AllExcept(except) => {
+                        let except = resolve(*except);
+                        stream_senders.keys().copied().filter(|connection| *connection != except).collect()
+                    }
+                    SendTarget::Group(group) => groups.members(group).iter().map(|connection| resolve(*connection)).collect(),
+                };
+
+                // Every connection shares the same packet, so it is only encoded once
+                let packet = SharedPacket::new(data.clone());
+                for connection in connections {
+                    queue_send(stream_senders, event_sender, connection, |queue| queue.send_shared(packet.clone()));
+                }
+            }
 
-        // Try to send a message through this MPSC. Sending may fail if the receiving end of the
-        // MPSC has been dropped - in that case raise an error.
-        if sender.send(send.clone()).is_err() {
-            warn!("Failed to publish packet from ECS->MPSC");
-            session.event_sender
-                .send(ReceiveEvent::NetworkError(send.as_stream_sender_error()))
-                .expect("Failed to send error event!");
+            _ => {
+                if let Some(connection) = send.get_connection() {
+                    queue_send(stream_senders, event_sender, resolve(connection), |queue| queue.send(send.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Push something into the send queue of a connection. If the queue is full the overflow policy decides what happens.
+fn queue_send(
+    stream_senders: &HashMap<ConnectionId, SendQueue>,
+    event_sender: &UnboundedSender<ReceiveEvent>,
+    connection: ConnectionId,
+    send: impl FnOnce(&SendQueue) -> Result<(), Option<Arc<Packet>>>,
+) {
+    // Try to get the queue for this connection, early exit if it does not exist
+    let queue = if let Some(queue) = stream_senders.get(&connection) {
+        queue
+    } else {
+        error!("Attempted to send to a non-existant connection: {:?}", connection);
+        return;
+    };
+
+    if let Err(dropped) = send(queue) {
+        let policy = queue.config().policy;
+        warn!("Send queue for connection {:?} is full, policy: {:?}", connection, policy);
+
+        if policy == OverflowPolicy::Disconnect {
+            queue.disconnect_now(DisconnectReason::new(DisconnectCode::QueueOverflow, "Send queue overflowed"));
+        }
+
+        event_sender
+            .send(ReceiveEvent::NetworkError(NetworkError::QueueOverflow {
+                connection,
+                stream: None,
+                policy,
+                dropped,
+            }))
+            .expect("Failed to send error event!");
+    }
+}
+
+/// Copy the depth of each connection's send queues into the ECS
+pub fn update_queue_depths_system(session: Res<SessionEventListenerState>, mut query: Query<(&Connection, &mut SendQueueDepth)>) {
+    for (connection, mut depth) in &mut query.iter() {
+        if let Some(queue) = session.stream_senders.get(&connection.id) {
+            depth.0 = queue.depth();
         }
     }
 }
@@ -153,14 +288,26 @@ pub struct Connecting {
     id: ConnectionId,
     connecting: quinn::Connecting,
     event_sender: UnboundedSender<ReceiveEvent>,
+    queue_config: QueueConfig,
+    codec: Arc<CodecConfig>,
+    limits: Arc<RateLimitConfig>,
 }
 
 impl Connecting {
-    pub fn new(connecting: quinn::Connecting, event_sender: UnboundedSender<ReceiveEvent>) -> Self {
+    pub fn new(
+        connecting: quinn::Connecting,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        queue_config: QueueConfig,
+        codec: Arc<CodecConfig>,
+        limits: Arc<RateLimitConfig>,
+    ) -> Self {
         Connecting {
             id: ConnectionId::new(),
             connecting,
-            event_sender
+            event_sender,
+            queue_config,
+            codec,
+            limits,
         }
     }
 
@@ -169,7 +316,7 @@ impl Connecting {
         info!("connection incoming: {:?}", self.id);
 
         // Wait for connection to finish connecting
-        let quinn::NewConnection { connection, uni_streams, .. } = match self.connecting.await {
+        let quinn::NewConnection { connection, uni_streams, datagrams, .. } = match self.connecting.await {
             Ok(connection) => connection,
             Err(e) => {
                 self.event_sender.send(ReceiveEvent::NetworkError(
@@ -179,21 +326,36 @@ impl Connecting {
             }
         };
 
-        // Create a new MPSC which the ECS can use to send packets through this connection
-        let (send, recv) = unbounded_channel();
+        // Create a new queue which the ECS can use to send packets through this connection
+        let queue = SendQueue::new(self.queue_config);
 
         // Send an intial event indicating that this connection opened
         self.event_sender
-            .send(ReceiveEvent::Connected(self.id, send))
+            .send(ReceiveEvent::Connected(self.id, queue.clone()))
             .expect("Failed to send network event");
 
+        // Identify the peer by the certificate it authenticated with, if it presented one
+        let certificate = connection.authentication_data()
+            .peer_certificates
+            .and_then(|certs| certs.iter().next().map(|cert| cert.0.clone()));
+        if let Some(certificate) = certificate {
+            let identity = PeerIdentity { fingerprint: fingerprint(&certificate), certificate };
+            info!("Peer of {:?} identified as {}", self.id, identity.fingerprint);
+            self.event_sender
+                .send(ReceiveEvent::PeerIdentified { connection: self.id, identity })
+                .expect("Failed to send network event");
+        }
+
         // Start running tasks to send/receive to this connection
         tokio::spawn(Connected {
             id: self.id,
             send: self.event_sender,
             connection,
             uni_streams,
-            recv
+            datagrams,
+            queue,
+            codec: self.codec,
+            limits: self.limits,
         }.run());
     }    
 }
@@ -203,81 +365,364 @@ struct Connected {
     id: ConnectionId,
     connection: quinn::Connection,
     uni_streams: IncomingUniStreams,
-    recv: UnboundedReceiver<SendEvent>,
-    send: UnboundedSender<ReceiveEvent>
+    datagrams: Datagrams,
+    queue: SendQueue,
+    send: UnboundedSender<ReceiveEvent>,
+    codec: Arc<CodecConfig>,
+    limits: Arc<RateLimitConfig>,
 }
 
 impl Connected {
     /// start running the async tasks required to pump this connection
     pub async fn run(self) {
+        // Every task reading from the connection shares the same limits
+        let limiter = RateLimiter::new(self.id, self.limits, self.queue.clone(), self.send.clone());
+
         // Spawn a task which polls for new incoming streams
-        tokio::spawn(Self::poll_incoming_streams(self.uni_streams, self.send.clone(), self.id));
+        tokio::spawn(Self::poll_incoming_streams(self.uni_streams, self.queue.clone(), self.send.clone(), self.id, self.codec.clone(), limiter.clone()));
+
+        // Spawn a task which reads incoming datagrams
+        tokio::spawn(Self::poll_incoming_datagrams(self.datagrams, self.send.clone(), self.id, self.codec.clone(), limiter, self.queue.recorder().clone()));
+
+        // Spawn a task which sends queued datagrams
+        tokio::spawn(Self::send_datagrams(self.id, self.connection.clone(), self.queue.clone(), self.send.clone(), self.codec.clone()));
 
         // Spawn a task which opens new outgoing streams and sends packets to them
-        tokio::spawn(Self::send_to_streams(self.connection, self.recv, self.send));
+        tokio::spawn(Self::send_to_streams(self.id, self.connection, self.queue, self.send, self.codec));
     }
 
     /// keep watch for new incoming streams and spawn async tasks to send/receive to the stream
-    async fn poll_incoming_streams(mut uni_streams: IncomingUniStreams, event_sender: UnboundedSender<ReceiveEvent>, id: ConnectionId) {
-        // Keep getting events from the connection until it closes
-        while let Some(stream) = uni_streams.next().await {
-            match stream {
-                Err(quinn::ConnectionError::ApplicationClosed { .. }) => break,
-                Err(e) => {
+    async fn poll_incoming_streams(
+        mut uni_streams: IncomingUniStreams,
+        queue: SendQueue,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        id: ConnectionId,
+        codec: Arc<CodecConfig>,
+        limiter: Arc<RateLimiter>,
+    ) {
+        // Keep getting events from the connection until it closes, and work out why it closed
+        let (reason, by_peer) = loop {
+            match uni_streams.next().await {
+                // Hold the stream in a slot until it finishes, or refuse it if there are too many open
+                Some(Ok(mut recv)) => match limiter.open_stream().await {
+                    Some(guard) => {
+                        tokio::spawn(Self::read_from_stream(id, recv, event_sender.clone(), codec.clone(), limiter.clone(), guard, queue.recorder().clone()));
+                    }
+                    None => {
+                        let _ = recv.stop(VarInt::from_u32(0));
+                    }
+                },
+
+                // The peer closed the connection, it sent the reason along with the close
+                Some(Err(quinn::ConnectionError::ApplicationClosed(close))) => {
+                    let code = u32::try_from(u64::from(close.error_code)).unwrap_or(u32::MAX);
+                    let message = String::from_utf8_lossy(&close.reason).into_owned();
+                    break (DisconnectReason::new(code.into(), message), true);
+                }
+
+                // This end closed the connection, the reason was recorded in the queue when it did
+                Some(Err(quinn::ConnectionError::LocallyClosed)) | None => {
+                    break (queue.close_reason().unwrap_or_default(), false);
+                }
+
+                Some(Err(e)) => {
+                    let reason = DisconnectReason::new(DisconnectCode::ConnectionLost, e.to_string());
                     event_sender.send(ReceiveEvent::NetworkError(
                         NetworkError::ConnectionError(e)
                     )).expect("Failed to send network event");
-                    break;
-                },
-                Ok(recv) => {
-                    tokio::spawn(Self::read_from_stream(id, recv, event_sender.clone()));
+                    break (reason, false);
                 }
-            };
-        }
+            }
+        };
 
         // Send a final event indicating that this connection closed
         event_sender
-            .send(ReceiveEvent::Disconnected(id))
+            .send(ReceiveEvent::Disconnected { connection: id, reason, by_peer })
             .expect("Failed to send network event");
     }
 
-    /// Pump the MPSCs for new packets that need sending
+    /// Pump the connection queue for new packets that need sending, and dispatch them to the queue for their stream
     async fn send_to_streams(
-        mut conn: quinn::Connection,
-        mut recv: UnboundedReceiver<SendEvent>,
-        event_sender: UnboundedSender<ReceiveEvent>
+        id: ConnectionId,
+        conn: quinn::Connection,
+        queue: SendQueue,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        codec: Arc<CodecConfig>,
     ) {
-        // Create a list of open streams. When a request comes in to send over a non-existant stream open it and store it here.
-        // Streams are never closed. This is fine since there are a fixed number of streams (as defined in the StreamType enum).
-        let mut stream_lookup = Vec::new();
-    
-        // Keep pulling events from the stream until "None" is received (indicating that all senders have been dropped).
-        while let Some(evt) = recv.recv().await
+        // Tasks writing to each stream. A stream (and the task writing to it) is created the first time a packet is
+        // sent to it. Streams are never closed. This is fine since there are a fixed number of streams (as defined
+        // in the StreamType enum).
+        let mut stream_tasks = Vec::new();
+
+        // Tasks sending unordered transfers, each on its own stream. Finished tasks are dropped as new ones start.
+        let mut transfer_tasks = FuturesUnordered::new();
+
+        // Keep pulling events from the queue until "None" is received (indicating that the queue has been closed).
+        while let Some(evt) = queue.next_event().await
         {
-            // Send the packet and break out of the loop if sending errorred
-            if let Err(err) = evt.send(&mut stream_lookup, &mut conn).await {
-                event_sender.send(ReceiveEvent::NetworkError(err)).expect("Failed to send error event!");
+            let data = match evt {
+                Outgoing::Packet(data) => data,
+
+                // Close all stream queues and wait for the stream and transfer tasks to finish sending what they have
+                Outgoing::Disconnect(reason) => {
+                    queue.close_streams();
+                    for task in stream_tasks.drain(..) {
+                        let _ = task.await;
+                    }
+                    while transfer_tasks.next().await.is_some() {}
+
+                    close_connection(&conn, &queue, reason);
+                    break;
+                }
+            };
+
+            let stream = data.packet().stream_type();
+            match data.packet().delivery().ordering {
+                Ordering::Ordered => {
+                    // Find (or create) the queue for this stream
+                    let (stream_queue, created) = queue.stream(stream);
+                    if created {
+                        stream_tasks.push(tokio::spawn(Self::write_to_stream(id, stream, conn.clone(), stream_queue.clone(), event_sender.clone(), codec.clone(), queue.recorder().clone())));
+                    }
+
+                    // Push the packet into the stream queue, if it is full the overflow policy decides what happens
+                    let policy = queue.config().policy;
+                    if let Err(dropped) = stream_queue.push(data, policy) {
+                        let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::QueueOverflow {
+                            connection: id,
+                            stream: Some(stream),
+                            policy,
+                            dropped: Some(dropped.into_packet()),
+                        }));
+
+                        if policy == OverflowPolicy::Disconnect {
+                            let reason = DisconnectReason::new(DisconnectCode::QueueOverflow, "Stream queue overflowed");
+                            close_connection(&conn, &queue, reason);
+                            break;
+                        }
+                    }
+                }
+
+                Ordering::Unordered => {
+                    // Open a new stream for this transfer
+                    let uni = match conn.open_uni().await {
+                        Ok(uni) => uni,
+                        Err(err) => {
+                            let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::ConnectionError(err)));
+                            break;
+                        }
+                    };
+
+                    // Start sending the data
+                    while let Some(Some(_)) = transfer_tasks.next().now_or_never() {}
+                    transfer_tasks.push(tokio::spawn(Self::send_transfer(uni, stream, data, codec.clone(), queue.recorder().clone())));
+                }
+            }
+        }
+
+        queue.close_streams();
+    }
+
+    /// Open a stream and write every packet pushed into the queue to it
+    async fn write_to_stream(
+        id: ConnectionId,
+        stream: StreamType,
+        conn: quinn::Connection,
+        queue: BoundedQueue<SharedPacket>,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        codec: Arc<CodecConfig>,
+        stats: StatsRecorder,
+    ) {
+        let mut sender = match conn.open_uni().await {
+            Ok(sender) => sender,
+            Err(err) => {
+                let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::ConnectionError(err)));
+                return;
+            }
+        };
+        let _open = stats.open_stream();
+
+        // Identify this stream to the receiver
+        if let Err(err) = stream.send_header(&mut sender).await {
+            let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                connection: id,
+                stream,
+                err,
+            }));
+            return;
+        }
+
+        // Keep sending packets until the queue is closed, break out of the loop if sending errors
+        while let Some(data) = queue.pop().await {
+            match data.send(&mut sender, &codec).await {
+                Ok(bytes) => stats.sent(stream, bytes),
+                Err(err) => {
+                    let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                        connection: id,
+                        stream,
+                        err,
+                    }));
+                    return;
+                }
+            }
+        }
+
+        let _ = sender.finish().await;
+    }
+
+    async fn send_transfer(mut sender: quinn::SendStream, stream: StreamType, data: SharedPacket, codec: Arc<CodecConfig>, stats: StatsRecorder) {
+        let _open = stats.open_stream();
+
+        // All of these method generate a result which is discarded.
+        // Results from transfers are not sent anywhere as that could potentially result in
+        // errors from a connection arriving in the ECS after is has closed!
+        if stream.send_header(&mut sender).await.is_err() {
+            return;
+        }
+        if let Ok(bytes) = data.send(&mut sender, &codec).await {
+            stats.sent(stream, bytes);
+        }
+        let _ = sender.finish().await;
+    }
+
+    /// Read datagrams from the connection and publish the packets they contain to the ECS
+    async fn poll_incoming_datagrams(
+        mut datagrams: Datagrams,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        id: ConnectionId,
+        codec: Arc<CodecConfig>,
+        limiter: Arc<RateLimiter>,
+        stats: StatsRecorder,
+    ) {
+        // Connection errors are reported by `poll_incoming_streams`, so just stop when the connection closes
+        while let Some(Ok(datagram)) = datagrams.next().await {
+            let decoded = Packet::from_datagram(&datagram, &codec);
+            if let Ok((stream, _)) = &decoded {
+                stats.received_datagram(*stream, datagram.len());
+            }
+
+            let event = match decoded {
+                Ok((_, pkt)) if !limiter.admit_unreliable(&pkt) => continue,
+
+                Ok((stream, pkt)) => ReceiveEvent::ReceivedPacket {
+                    connection: id,
+                    stream,
+                    data: Arc::new(pkt),
+                },
+
+                // A bad datagram does not affect any other packets, so keep reading
+                Err(err) => ReceiveEvent::NetworkError(NetworkError::ReceiveError {
+                    connection: id,
+                    err,
+                }),
+            };
+
+            if event_sender.send(event).is_err() {
                 break;
             }
         }
     }
 
+    /// Pump the datagram queue for packets that need sending and send each one as a datagram
+    async fn send_datagrams(
+        id: ConnectionId,
+        conn: quinn::Connection,
+        queue: SendQueue,
+        event_sender: UnboundedSender<ReceiveEvent>,
+        codec: Arc<CodecConfig>,
+    ) {
+        let datagrams = queue.datagrams();
+        while let Some(data) = datagrams.pop().await {
+            let stream = data.packet().stream_type();
+
+            // Check that the peer accepts datagrams, and find the largest datagram which may be sent
+            let max = match conn.max_datagram_size() {
+                Some(max) => max.min(queue.config().max_datagram_size),
+                None => {
+                    let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                        connection: id,
+                        stream,
+                        err: SendError::DatagramError(quinn::SendDatagramError::UnsupportedByPeer),
+                    }));
+                    continue;
+                }
+            };
+
+            let bytes = match data.to_datagram(&codec) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                        connection: id,
+                        stream,
+                        err,
+                    }));
+                    continue;
+                }
+            };
+
+            if bytes.len() > max {
+                let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::DatagramTooLarge {
+                    connection: id,
+                    size: bytes.len(),
+                    max,
+                }));
+                continue;
+            }
+
+            trace!("Sending {} byte datagram to {:?}", bytes.len(), id);
+            let size = bytes.len();
+            match conn.send_datagram(bytes) {
+                Ok(()) => queue.recorder().sent_datagram(stream, size),
+
+                // The connection has closed, there is nothing left to do
+                Err(quinn::SendDatagramError::ConnectionClosed(_)) => break,
+
+                Err(err) => {
+                    let _ = event_sender.send(ReceiveEvent::NetworkError(NetworkError::SendError {
+                        connection: id,
+                        stream,
+                        err: SendError::DatagramError(err),
+                    }));
+                }
+            }
+        }
+    }
+
     /// Handle all the work of reading from a specific stream
     async fn read_from_stream(
         connection_id: ConnectionId,
         mut stream_recv: RecvStream<TlsSession>,
         event_sender: UnboundedSender<ReceiveEvent>,
+        codec: Arc<CodecConfig>,
+        limiter: Arc<RateLimiter>,
+        _guard: StreamGuard,
+        stats: StatsRecorder,
     ) {
-        info!("Stream incoming: conn:{:?}", connection_id);
+        let _open = stats.open_stream();
+
+        // Find out which logical stream this is
+        let stream = match StreamType::receive_header(&mut stream_recv).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                event_sender.send(ReceiveEvent::NetworkError(
+                    NetworkError::ReceiveError {
+                        connection: connection_id,
+                        err,
+                    }
+                )).expect("Failed to send error event!");
+                return;
+            }
+        };
+
+        info!("Stream incoming: conn:{:?} stream:{:?}", connection_id, stream);
 
         // Pull packets from this stream and publish them to the ECS through the event_sender
         loop {
-            let pkt = Packet::receive(&mut stream_recv).await;
-            let se = match pkt {
-                Ok(pkt) => event_sender.send(ReceiveEvent::ReceivedPacket {
-                    connection: connection_id,
-                    data: Arc::new(pkt),
-                }),
+            let pkt = match Packet::receive(&mut stream_recv, stream, &codec).await {
+                Ok((pkt, bytes)) => {
+                    stats.received(stream, bytes);
+                    pkt
+                }
                 Err(err) => {
                     event_sender.send(ReceiveEvent::NetworkError(
                         NetworkError::ReceiveError {
@@ -288,7 +733,25 @@ impl Connected {
                     break;
                 },
             };
-            se.expect("Failed to send event");
+
+            // Hold back (or discard) packets which exceed the rate limit
+            if !limiter.admit(&pkt).await {
+                continue;
+            }
+
+            event_sender.send(ReceiveEvent::ReceivedPacket {
+                connection: connection_id,
+                stream,
+                data: Arc::new(pkt),
+            }).expect("Failed to send event");
         }
     }
 }
+
+/// Close a connection, sending the reason to the peer as the application error code and reason
+fn close_connection(conn: &quinn::Connection, queue: &SendQueue, reason: DisconnectReason) {
+    // Record the reason first, the incoming stream task reports it as soon as the connection closes
+    let (code, message) = (reason.code.into(), reason.message.clone());
+    queue.set_close_reason(reason);
+    conn.close(VarInt::from_u32(code), message.as_bytes());
+}
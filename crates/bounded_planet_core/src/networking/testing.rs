@@ -0,0 +1,401 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use thiserror::Error;
+use tokio::{
+    runtime::{Builder, Runtime},
+    task::yield_now,
+    time::delay_for,
+};
+use url::Url;
+
+use super::{
+    client::{
+        connection::{ClientTransport, ReconnectConfig, ServerTrust},
+        plugin::Network as ClientNetwork,
+    },
+    components::Connection,
+    crypto::{CertificateError, ClientAuth, ClientIdentity, load_certificate_chain, load_private_key},
+    events::{ReceiveEvent, SendEvent},
+    id::ConnectionId,
+    loopback::loopback,
+    queue::QueueConfig,
+    rate_limit::RateLimitConfig,
+    server::plugin::{Network as ServerNetwork, ServerTransport},
+    systems::NetworkConnections,
+};
+
+/// Number of times the runtime yields to the connection tasks after each app update. Every yield runs all the tasks
+/// which are ready, so this is enough for a packet to make its way from one app, through the connection tasks, to
+/// the other app.
+const SETTLE_YIELDS: usize = 64;
+
+/// How long each step waits for packets sent over QUIC, which go through the OS rather than the runtime
+const QUIC_STEP_WAIT: Duration = Duration::from_millis(1);
+
+/// How often `NetworkHarness::step_until_timeout` steps while it waits
+const WAIT_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Default number of steps `NetworkHarness::step_until` takes before giving up
+pub const DEFAULT_MAX_STEPS: usize = 1000;
+
+/// Which app in a `NetworkHarness`
+#[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
+pub enum Side {
+    Server,
+    Client,
+}
+
+/// How the apps in a `NetworkHarness` are connected
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HarnessTransport {
+    /// Connect through an in-memory loopback socket, see `networking::loopback`
+    Loopback,
+
+    /// Connect over QUIC on localhost, using the test certificates in the `certs` directory
+    Quic,
+}
+
+/// Configures the apps created by a `NetworkHarness`
+#[derive(Debug, Clone)]
+pub struct HarnessConfig {
+    pub transport: HarnessTransport,
+
+    /// Limits on the packets and streams the server accepts from the client
+    pub rate_limit: RateLimitConfig,
+
+    /// Sizes of the server's send queues
+    pub queue_config: QueueConfig,
+
+    /// Client certificates the server accepts, only used with `HarnessTransport::Quic`
+    pub client_auth: Option<ClientAuth>,
+
+    /// Certificate the client presents, only used with `HarnessTransport::Quic`
+    pub client_identity: Option<ClientIdentity>,
+
+    /// How the client reconnects when the connection is lost. By default it does not, so tests see a lost
+    /// connection rather than a new one.
+    pub reconnect: ReconnectConfig,
+}
+
+impl Default for HarnessConfig {
+    fn default() -> Self {
+        HarnessConfig {
+            transport: HarnessTransport::Loopback,
+            rate_limit: Default::default(),
+            queue_config: Default::default(),
+            client_auth: None,
+            client_identity: None,
+            reconnect: ReconnectConfig {
+                max_attempts: Some(0),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HarnessError {
+    #[error("Condition was not met after {steps} steps")]
+    Timeout {
+        steps: usize,
+    },
+
+    #[error("Condition was not met after {timeout:?}")]
+    Deadline {
+        timeout: Duration,
+    },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Certificate(#[from] CertificateError),
+}
+
+/// A headless server `App` and client `App` wired together, for testing the networking layer. The apps are stepped
+/// manually, one frame at a time. The connection tasks run on a single threaded runtime owned by the harness, which
+/// only runs while the harness is stepping: after each app update the runtime runs until the tasks have handled
+/// everything the update sent them. With the loopback transport a test therefore sees the same events on every run.
+///
+/// Timers and work on other threads (e.g. blocking tasks) still take real time, use
+/// `NetworkHarness::step_until_timeout` to wait for them.
+///
+/// Only the network plugins are added by default. Use `NetworkHarness::build` to add more plugins and systems to
+/// either app.
+pub struct NetworkHarness {
+    pub server: App,
+    pub client: App,
+
+    transport: HarnessTransport,
+    server_events: EventReader<ReceiveEvent>,
+    client_events: EventReader<ReceiveEvent>,
+
+    /// Must be dropped after the apps, which hold handles to it
+    runtime: Runtime,
+}
+
+impl NetworkHarness {
+    /// Create a server and client with only the network plugins
+    pub fn new(transport: HarnessTransport) -> Result<Self, HarnessError> {
+        Self::build(HarnessConfig { transport, ..Default::default() }, |_| {}, |_| {})
+    }
+
+    /// Create a server and client, calling `server` and `client` to add extra plugins and systems to each app after
+    /// the network plugins have been added
+    pub fn build(
+        config: HarnessConfig,
+        server: impl FnOnce(&mut AppBuilder),
+        client: impl FnOnce(&mut AppBuilder),
+    ) -> Result<Self, HarnessError> {
+        let HarnessConfig { transport, rate_limit, queue_config, client_auth, client_identity, reconnect } = config;
+        let runtime = Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+
+        let (server_transport, client_transport) = match transport {
+            HarnessTransport::Loopback => {
+                let (listener, connector) = loopback();
+                (ServerTransport::Loopback(listener), ClientTransport::Loopback(connector))
+            }
+            HarnessTransport::Quic => quic_transports(client_auth, client_identity)?,
+        };
+
+        // The network plugins spawn tasks and capture the runtime while they are built
+        let (server, client) = runtime.enter(|| {
+            let mut server_app = App::build();
+            server_app.add_plugin(ServerNetwork {
+                transport: server_transport,
+                queue_config,
+                codec: Default::default(),
+                rate_limit,
+                health_config: Default::default(),
+            });
+            server(&mut server_app);
+
+            let mut client_app = App::build();
+            client_app.add_plugin(ClientNetwork {
+                transport: client_transport,
+                queue_config: Default::default(),
+                codec: Default::default(),
+                health_config: Default::default(),
+                reconnect,
+            });
+            client(&mut client_app);
+
+            (startup(server_app), startup(client_app))
+        });
+
+        let mut harness = NetworkHarness {
+            server,
+            client,
+            transport,
+            server_events: Default::default(),
+            client_events: Default::default(),
+            runtime,
+        };
+
+        // Let the tasks spawned by the plugins start, e.g. the server starts listening
+        harness.settle();
+
+        Ok(harness)
+    }
+
+    pub fn app(&self, side: Side) -> &App {
+        match side {
+            Side::Server => &self.server,
+            Side::Client => &self.client,
+        }
+    }
+
+    pub fn app_mut(&mut self, side: Side) -> &mut App {
+        match side {
+            Side::Server => &mut self.server,
+            Side::Client => &mut self.client,
+        }
+    }
+
+    /// Run one frame of the server and then one frame of the client, running the connection tasks after each, and
+    /// pass every `ReceiveEvent` published during those frames to `observe`
+    pub fn step(&mut self, mut observe: impl FnMut(Side, &ReceiveEvent)) {
+        update(&self.runtime, &mut self.server, &mut self.server_events, |evt| observe(Side::Server, evt));
+        self.settle();
+        update(&self.runtime, &mut self.client, &mut self.client_events, |evt| observe(Side::Client, evt));
+        self.settle();
+    }
+
+    /// Run the connection tasks until they have handled everything they can without waiting
+    fn settle(&mut self) {
+        let transport = self.transport;
+        self.runtime.block_on(async move {
+            for _ in 0..SETTLE_YIELDS {
+                yield_now().await;
+            }
+
+            if transport == HarnessTransport::Quic {
+                delay_for(QUIC_STEP_WAIT).await;
+                for _ in 0..SETTLE_YIELDS {
+                    yield_now().await;
+                }
+            }
+        });
+    }
+
+    /// Run only the connection tasks (not the apps) for some real time, e.g. to let timers expire
+    pub fn wait(&mut self, duration: Duration) {
+        self.runtime.block_on(delay_for(duration));
+    }
+
+    /// Step both apps until `condition` returns true for one of the published `ReceiveEvent`s, giving up after
+    /// `DEFAULT_MAX_STEPS` steps
+    pub fn step_until(&mut self, condition: impl FnMut(Side, &ReceiveEvent) -> bool) -> Result<(), HarnessError> {
+        self.step_until_max(DEFAULT_MAX_STEPS, condition)
+    }
+
+    /// Step both apps until `condition` returns true for one of the published `ReceiveEvent`s, giving up after
+    /// `max_steps` steps
+    pub fn step_until_max(
+        &mut self,
+        max_steps: usize,
+        mut condition: impl FnMut(Side, &ReceiveEvent) -> bool,
+    ) -> Result<(), HarnessError> {
+        for _ in 0..max_steps {
+            let mut met = false;
+            self.step(|side, evt| met |= condition(side, evt));
+
+            if met {
+                return Ok(());
+            }
+        }
+
+        Err(HarnessError::Timeout { steps: max_steps })
+    }
+
+    /// Step both apps until `condition` returns true for one of the published `ReceiveEvent`s, giving up after
+    /// `timeout` has passed. Use this rather than `step_until` when the condition depends on timers or on work done on
+    /// other threads, which do not advance with the steps.
+    pub fn step_until_timeout(
+        &mut self,
+        timeout: Duration,
+        mut condition: impl FnMut(Side, &ReceiveEvent) -> bool,
+    ) -> Result<(), HarnessError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut met = false;
+            self.step(|side, evt| met |= condition(side, evt));
+
+            if met {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(HarnessError::Deadline { timeout });
+            }
+            self.wait(WAIT_INTERVAL);
+        }
+    }
+
+    /// Step both apps until the handshake has completed on both sides
+    pub fn connect(&mut self) -> Result<(), HarnessError> {
+        let (mut server, mut client) = (false, false);
+        self.step_until(|side, evt| {
+            if let ReceiveEvent::HandshakeCompleted { .. } = evt {
+                match side {
+                    Side::Server => server = true,
+                    Side::Client => client = true,
+                }
+            }
+            server && client
+        })
+    }
+
+    /// Publish a `SendEvent` in an app, it will be sent during the next step
+    pub fn send(&mut self, side: Side, event: SendEvent) {
+        self.app_mut(side)
+            .resources
+            .get_mut::<Events<SendEvent>>()
+            .expect("Network plugin was not added")
+            .send(event);
+    }
+
+    /// Get the open connections of an app
+    pub fn connections(&self, side: Side) -> Vec<ConnectionId> {
+        let mut connections = self.app(side)
+            .resources
+            .get::<NetworkConnections>()
+            .expect("Network plugin was not added")
+            .connections
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        connections.sort();
+        connections
+    }
+
+    /// Get the `Connection` components of every entity in an app
+    pub fn connection_entities(&self, side: Side) -> Vec<Connection> {
+        let mut connections = self.app(side)
+            .world
+            .query::<&Connection>()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        connections.sort();
+        connections
+    }
+
+    /// Get the single connection of an app, panics if there is not exactly one
+    pub fn connection(&self, side: Side) -> ConnectionId {
+        match self.connections(side).as_slice() {
+            [connection] => *connection,
+            connections => panic!("Expected exactly one {:?} connection, found {}", side, connections.len()),
+        }
+    }
+}
+
+/// Run one frame of an app and pass the `ReceiveEvent`s it published to `observe`
+fn update(runtime: &Runtime, app: &mut App, reader: &mut EventReader<ReceiveEvent>, mut observe: impl FnMut(&ReceiveEvent)) {
+    runtime.enter(|| app.update());
+
+    let events = app.resources.get::<Events<ReceiveEvent>>().expect("Network plugin was not added");
+    for evt in reader.iter(&events) {
+        observe(evt);
+    }
+}
+
+/// Take the app out of a builder and run its startup systems, in the same way as `App::run`
+fn startup(mut builder: AppBuilder) -> App {
+    let mut app = std::mem::take(&mut builder.app);
+    app.startup_schedule.initialize(&mut app.world, &mut app.resources);
+    app.startup_executor.run(&mut app.startup_schedule, &mut app.world, &mut app.resources);
+    app
+}
+
+/// Create QUIC transports for a server listening on a free localhost port
+fn quic_transports(client_auth: Option<ClientAuth>, identity: Option<ClientIdentity>) -> Result<(ServerTransport, ClientTransport), HarnessError> {
+    let certs = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../certs");
+    let private_key = load_private_key(&certs.join("key.pem"))?;
+    let certificate = load_certificate_chain(&certs.join("cert.pem"))?;
+
+    // Find a free port by binding to port 0 and releasing it again
+    let addr: SocketAddr = UdpSocket::bind("[::1]:0")?.local_addr()?;
+
+    let server = ServerTransport::Quic { private_key, certificate, addr, client_auth };
+    let client = ClientTransport::Quic {
+        addr,
+        url: Url::parse("quic://localhost").expect("Failed to parse test URL"),
+
+        // The test certificate is not issued for localhost
+        trust: ServerTrust {
+            accept_any: true,
+            ..Default::default()
+        },
+        identity,
+    };
+
+    Ok((server, client))
+}
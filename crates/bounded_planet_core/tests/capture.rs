@@ -0,0 +1,207 @@
+mod common;
+
+use std::{fs::{self, OpenOptions}, io::Write, sync::Arc, time::Duration};
+
+use bevy::prelude::*;
+use bounded_planet::networking::{
+    capture::{Capture, CaptureError, CaptureReader, CaptureWriter, CapturedEvent, REDACTED_PASSWORD, REDACTED_TOKEN, Replay},
+    events::{ReceiveEvent, SendEvent},
+    id::RequestId,
+    loopback::loopback,
+    packets::{AuthRequest, Packet, RpcPayload, Session, TextChat},
+    server::{
+        plugin::{Network, ServerTransport},
+        session::Sessions,
+    },
+    testing::{HarnessConfig, NetworkHarness, Side},
+};
+use tokio::runtime::Builder;
+
+use common::temp_path;
+
+fn is_chat(packet: &Packet) -> bool {
+    matches!(packet, Packet::TextChat(_))
+}
+
+#[test]
+fn capture_and_replay() {
+    let path = temp_path("capture.bpcap");
+
+    // Capture the server while a client connects and sends a chat message
+    {
+        let capture = path.clone();
+        let mut harness = NetworkHarness::build(
+            HarnessConfig::default(),
+            |app| {
+                app.add_plugin(Capture { path: capture });
+            },
+            |_| {},
+        )
+        .expect("Failed to create harness");
+        harness.connect().expect("Handshake did not complete");
+
+        harness.send(Side::Client, SendEvent::SendPacket {
+            connection: harness.connection(Side::Client),
+            data: Arc::new(Packet::TextChat(TextChat { index: 0, message: "hello".to_owned() })),
+        });
+        harness
+            .step_until(|side, evt| side == Side::Server && matches!(evt, ReceiveEvent::ReceivedPacket { data, .. } if is_chat(data)))
+            .expect("Chat did not arrive");
+    }
+
+    // Both directions are recorded
+    let records = CaptureReader::open(&path)
+        .expect("Failed to open capture")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read capture");
+    assert!(records.iter().any(|record| matches!(&record.event, CapturedEvent::Received { packet, .. } if is_chat(packet))));
+    assert!(records.iter().any(|record| matches!(&record.event, CapturedEvent::Send { packet, .. } if matches!(**packet, Packet::Handshake(_)))));
+
+    // Replay the capture into a server which no client is connected to
+    let runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+    let mut app = runtime.enter(|| {
+        let (listener, _) = loopback();
+        let mut builder = App::build();
+        builder.add_plugin(Network {
+            transport: ServerTransport::Loopback(listener),
+            queue_config: Default::default(),
+            codec: Default::default(),
+            rate_limit: Default::default(),
+            health_config: Default::default(),
+        });
+        std::mem::take(&mut builder.app)
+    });
+
+    let mut replay = Replay::load(&path).expect("Failed to load capture");
+    let mut reader = EventReader::<ReceiveEvent>::default();
+    let mut chats = 0;
+    while runtime.enter(|| replay.step(&mut app)) {
+        let events = app.resources.get::<Events<ReceiveEvent>>().expect("Network plugin was not added");
+        chats += reader
+            .iter(&events)
+            .filter(|evt| matches!(evt, ReceiveEvent::ReceivedPacket { data, .. } if is_chat(data)))
+            .count();
+    }
+    assert_eq!(chats, 1);
+
+    // The replayed server answered the replayed handshake
+    assert!(replay.sent.iter().any(|evt| matches!(evt.get_packet().map(|data| &**data), Some(Packet::Handshake(_)))));
+}
+
+/// Check if `needle` appears anywhere in `bytes`
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|window| window == needle)
+}
+
+#[test]
+fn secrets_are_not_captured() {
+    const PASSWORD: &str = "correct horse battery staple";
+    let path = temp_path("secrets.bpcap");
+
+    // Capture the server while a client gets a session token and sends its credentials, both as a packet and as a
+    // request
+    let token = {
+        let capture = path.clone();
+        let mut harness = NetworkHarness::build(
+            HarnessConfig::default(),
+            |app| {
+                app.add_plugin(Sessions { grace_period: Duration::from_secs(60) });
+                app.add_plugin(Capture { path: capture });
+            },
+            |_| {},
+        )
+        .expect("Failed to create harness");
+        harness.connect().expect("Handshake did not complete");
+
+        let mut token = None;
+        harness
+            .step_until(|side, evt| match evt {
+                ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client => match **data {
+                    Packet::Session(Session::Accepted { token: t, .. }) => {
+                        token = Some(t);
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            })
+            .expect("Session was not accepted");
+
+        let request = AuthRequest { username: "player".to_owned(), password: PASSWORD.to_owned() };
+        for packet in vec![
+            Packet::AuthRequest(request.clone()),
+            Packet::Request { id: RequestId(0), request: RpcPayload::AuthRequest(request) },
+        ] {
+            harness.send(Side::Client, SendEvent::SendPacket {
+                connection: harness.connection(Side::Client),
+                data: Arc::new(packet),
+            });
+        }
+
+        let mut received = 0;
+        harness
+            .step_until(|side, evt| {
+                if let ReceiveEvent::ReceivedPacket { data, .. } = evt {
+                    if side == Side::Server && matches!(**data, Packet::AuthRequest(_) | Packet::Request { .. }) {
+                        received += 1;
+                    }
+                }
+                received == 2
+            })
+            .expect("Credentials did not arrive");
+
+        token.expect("No session token")
+    };
+
+    // Neither the password nor the token appear anywhere in the file
+    let bytes = fs::read(&path).expect("Failed to read capture");
+    assert!(!contains(&bytes, PASSWORD.as_bytes()));
+    assert!(!contains(&bytes, &token.0));
+
+    // The rest of the packets are recorded, with placeholders for the secrets
+    let records = CaptureReader::open(&path)
+        .expect("Failed to open capture")
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Failed to read capture");
+    let packets = records
+        .iter()
+        .filter_map(|record| match &record.event {
+            CapturedEvent::Received { packet, .. } | CapturedEvent::Send { packet, .. } => Some(packet.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let credentials = packets
+        .iter()
+        .filter_map(|packet| match &**packet {
+            Packet::AuthRequest(request) | Packet::Request { request: RpcPayload::AuthRequest(request), .. } => Some(request.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(credentials.len(), 2);
+    for request in credentials {
+        assert_eq!(request.username, "player");
+        assert_eq!(request.password, REDACTED_PASSWORD);
+    }
+
+    assert!(packets.iter().any(|packet| matches!(**packet, Packet::Session(Session::Accepted { token, .. }) if token == REDACTED_TOKEN)));
+}
+
+#[test]
+fn oversized_records_are_refused() {
+    let path = temp_path("oversized.bpcap");
+    CaptureWriter::create(&path).expect("Failed to create capture");
+
+    // A length prefix which claims the record is 4GiB
+    let mut file = OpenOptions::new().append(true).open(&path).expect("Failed to open capture");
+    file.write_all(&u32::MAX.to_be_bytes()).expect("Failed to write capture");
+    drop(file);
+
+    let mut reader = CaptureReader::open(&path).expect("Failed to open capture");
+    let result = reader.read();
+    assert!(matches!(result, Err(CaptureError::RecordTooLarge { size, .. }) if size == u32::MAX as usize), "{:?}", result);
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use bincode::Options;
+use bounded_planet::networking::{
+    codec::{CodecConfig, Compression, Format},
+    packets::{Handshake, Packet, RequestId, RpcPayload, StreamType, TextChat, WorldInfoRequest},
+    serialization::{RecvError, SharedPacket},
+};
+use serde::{Serialize, Serializer, ser::SerializeStructVariant};
+
+fn chat(message: &str) -> Packet {
+    Packet::TextChat(TextChat {
+        index: 7,
+        message: message.to_owned(),
+    })
+}
+
+fn message(packet: Packet) -> String {
+    match packet {
+        Packet::TextChat(TextChat { message, .. }) => message,
+        packet => panic!("Unexpected packet: {:?}", packet),
+    }
+}
+
+#[test]
+fn round_trip_every_format() {
+    for format in [Format::MessagePack, Format::Bincode].iter() {
+        for compression in [Compression::None, Compression::Zlib(6)].iter() {
+            let mut codec = CodecConfig { format: *format, ..Default::default() };
+            codec.compression.insert(StreamType::TextChat, *compression);
+
+            let frame = codec.encode(&chat("hello"), StreamType::TextChat).expect("Failed to encode");
+
+            // Frames are decoded according to their header, not the configuration of the receiver
+            let decoded = CodecConfig::default().decode(&frame, StreamType::TextChat).expect("Failed to decode");
+            assert_eq!(message(decoded), "hello");
+        }
+    }
+}
+
+#[test]
+fn handshakes_are_not_framed() {
+    let handshake = Packet::Handshake(Handshake { protocol_version: 1, capabilities: vec![] });
+    let mut codec = CodecConfig { format: Format::Bincode, ..Default::default() };
+    codec.compression.insert(StreamType::Handshake, Compression::Zlib(6));
+
+    // Handshakes are plain MessagePack whatever the codec is configured to do, as every protocol version sends them
+    let frame = codec.encode(&handshake, StreamType::Handshake).expect("Failed to encode");
+    assert_eq!(frame, rmp_serde::to_vec(&handshake).expect("Failed to encode"));
+
+    let decoded = CodecConfig::default().decode(&frame, StreamType::Handshake).expect("Failed to decode");
+    assert!(matches!(decoded, Packet::Handshake(Handshake { protocol_version: 1, .. })));
+}
+
+#[test]
+fn compression_shrinks_repetitive_packets() {
+    let mut codec = CodecConfig::default();
+    let packet = chat(&"a".repeat(4096));
+
+    let plain = codec.encode(&packet, StreamType::TextChat).expect("Failed to encode");
+    codec.compression.insert(StreamType::TextChat, Compression::Zlib(6));
+    let compressed = codec.encode(&packet, StreamType::TextChat).expect("Failed to encode");
+
+    assert!(compressed.len() < plain.len() / 10);
+}
+
+#[test]
+fn oversize_frames_are_refused() {
+    let mut codec = CodecConfig::default();
+    codec.compression.insert(StreamType::TextChat, Compression::Zlib(9));
+    let frame = codec.encode(&chat(&"a".repeat(4096)), StreamType::TextChat).expect("Failed to encode");
+
+    // The compressed frame is small, but it decompresses to more than the limit
+    let small = CodecConfig { max_frame_size: 1024, ..Default::default() };
+    assert!(frame.len() < small.max_frame_size);
+    assert!(matches!(small.decode(&frame, StreamType::TextChat), Err(RecvError::FrameTooLarge { max: 1024, .. })));
+
+    // The encoder refuses to create frames the receiver would refuse
+    assert!(small.encode(&chat(&"a".repeat(4096)), StreamType::PingPong).is_err());
+}
+
+#[test]
+fn shared_packets_are_encoded_once() {
+    let codec = CodecConfig::default();
+    let packet = SharedPacket::new(Arc::new(chat("hello")));
+    let clone = packet.clone();
+
+    // Every clone gets the frame encoded for the first one
+    let frame = packet.frame(&codec).expect("Failed to encode");
+    assert!(Arc::ptr_eq(&frame, &clone.frame(&codec).expect("Failed to encode")));
+    assert_eq!(message(codec.decode(&frame, StreamType::TextChat).expect("Failed to decode")), "hello");
+
+    // Datagrams reuse the frame too
+    let datagram = clone.to_datagram(&codec).expect("Failed to encode datagram");
+    let (stream, decoded) = Packet::from_datagram(&datagram, &codec).expect("Failed to decode datagram");
+    assert_eq!(stream, StreamType::TextChat);
+    assert_eq!(message(decoded), "hello");
+}
+
+/// Encodes a `Packet::Request` with `depth` more requests nested inside it, which `Packet` can not represent. With no
+/// nested requests it encodes a valid `Packet::Request`.
+struct NestedRequest(usize);
+
+impl Serialize for NestedRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Index of `Packet::Request`
+        let mut request = serializer.serialize_struct_variant("Packet", 12, "Request", 2)?;
+        request.serialize_field("id", &RequestId(0))?;
+        match self.0 {
+            0 => request.serialize_field("request", &RpcPayload::WorldInfoRequest(WorldInfoRequest))?,
+            depth => request.serialize_field("request", &NestedRequest(depth - 1))?,
+        }
+        request.end()
+    }
+}
+
+fn encode_nested(format: Format, depth: usize) -> Vec<u8> {
+    let mut frame = vec![format as u8];
+    match format {
+        Format::MessagePack => rmp_serde::encode::write(&mut frame, &NestedRequest(depth)).expect("Failed to encode"),
+        Format::Bincode => bincode::DefaultOptions::new().serialize_into(&mut frame, &NestedRequest(depth)).expect("Failed to encode"),
+    }
+    frame
+}
+
+#[test]
+fn nested_requests_are_refused() {
+    // Without nesting the frame is a valid request, so `NestedRequest` matches the layout of `Packet`
+    for format in [Format::MessagePack, Format::Bincode].iter() {
+        let decoded = CodecConfig::default().decode(&encode_nested(*format, 0), StreamType::Rpc);
+        assert!(matches!(decoded, Ok(Packet::Request { id: RequestId(0), request: RpcPayload::WorldInfoRequest(_) })), "{:?}", decoded);
+    }
+
+    let msgpack = encode_nested(Format::MessagePack, 64);
+    assert!(matches!(CodecConfig::default().decode(&msgpack, StreamType::Rpc), Err(RecvError::DecodeError(_))));
+
+    let bincode = encode_nested(Format::Bincode, 64);
+    assert!(matches!(CodecConfig::default().decode(&bincode, StreamType::Rpc), Err(RecvError::BincodeDecodeError(_))));
+}
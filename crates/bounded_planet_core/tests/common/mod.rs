@@ -0,0 +1,9 @@
+use std::{fs, path::PathBuf};
+
+/// Get a path in the temp directory which no other test uses, removing any file or directory an earlier run left there
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bounded_planet_{}_{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&path);
+    path
+}
@@ -0,0 +1,54 @@
+mod common;
+
+use std::{fs, path::PathBuf};
+
+use bounded_planet::networking::crypto::{
+    KnownHosts,
+    SelfSigned,
+    fingerprint,
+    load_certificates,
+    load_private_key,
+};
+
+use common::temp_path;
+
+#[test]
+fn loads_pem_and_der_certificates() {
+    let pem_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../certs/cert.pem");
+    let pem = load_certificates(&pem_path).expect("Failed to load PEM certificate");
+    assert_eq!(pem.len(), 1);
+
+    // DER files are loaded too
+    let generated = SelfSigned::generate(vec!["localhost".to_owned()]).expect("Failed to generate certificate");
+    let der_path = temp_path("cert.der");
+    fs::write(&der_path, &generated.cert).expect("Failed to write certificate");
+    assert_eq!(load_certificates(&der_path).expect("Failed to load DER certificate").len(), 1);
+}
+
+#[test]
+fn generated_certificates_round_trip() {
+    let (key_path, cert_path) = (temp_path("key.pem"), temp_path("cert.pem"));
+
+    let generated = SelfSigned::generate(vec!["localhost".to_owned()]).expect("Failed to generate certificate");
+    generated.save(&key_path, &cert_path).expect("Failed to save certificate");
+
+    load_private_key(&key_path).expect("Failed to load generated key");
+    let certs = load_certificates(&cert_path).expect("Failed to load generated certificate");
+    assert_eq!(certs.len(), 1);
+}
+
+#[test]
+fn known_hosts_are_pinned() {
+    let path = temp_path("known_hosts.txt");
+    let print = fingerprint(b"not really a certificate");
+    assert_eq!(print.len(), 64);
+
+    let known_hosts = KnownHosts::load(&path).expect("Failed to load missing known hosts");
+    assert_eq!(known_hosts.get("localhost:4433"), None);
+    known_hosts.pin("localhost:4433", &print).expect("Failed to pin host");
+
+    // Pins are saved, and only apply to the exact host and port
+    let known_hosts = KnownHosts::load(&path).expect("Failed to load known hosts");
+    assert_eq!(known_hosts.get("localhost:4433"), Some(print));
+    assert_eq!(known_hosts.get("localhost:4434"), None);
+}
@@ -0,0 +1,120 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+use bounded_planet::networking::{
+    client::interpolation::{ServerClock, Snapshot, sample},
+    packets::timestamp_now,
+    replication::NetworkTransform,
+};
+
+/// Allowed difference (in milliseconds) between an estimated offset and the expected offset, for the time which
+/// passes while the test runs
+const TOLERANCE: f64 = 50.0;
+
+fn assert_offset(clock: &ServerClock, expected: f64) {
+    let offset = clock.offset().expect("Clock is not synchronised");
+    assert!((offset - expected).abs() < TOLERANCE, "offset {} is not close to {}", offset, expected);
+}
+
+#[test]
+fn clock_starts_unsynchronised() {
+    let clock = ServerClock::default();
+    assert!(clock.offset().is_none());
+    assert!(clock.now().is_none());
+}
+
+#[test]
+fn first_sample_sets_the_offset() {
+    // The server clock is 10s ahead of the local clock
+    let mut clock = ServerClock::default();
+    clock.add_sample(timestamp_now() + 10_000, None);
+    assert_offset(&clock, 10_000.0);
+
+    let now = clock.now().expect("Clock is not synchronised");
+    assert!((now - (timestamp_now() as f64 + 10_000.0)).abs() < TOLERANCE);
+}
+
+#[test]
+fn samples_are_corrected_for_latency() {
+    // The timestamp was sent half of the round trip time ago, so the server clock is that much further ahead
+    let mut clock = ServerClock::default();
+    clock.add_sample(timestamp_now() + 10_000, Some(Duration::from_millis(400)));
+    assert_offset(&clock, 10_200.0);
+}
+
+#[test]
+fn later_samples_are_smoothed() {
+    let mut clock = ServerClock::default();
+    clock.add_sample(timestamp_now() + 10_000, None);
+
+    // A single sample far from the estimate only moves it a little of the way
+    clock.add_sample(timestamp_now() + 11_000, None);
+    let offset = clock.offset().expect("Clock is not synchronised");
+    assert!(offset > 10_000.0 + TOLERANCE && offset < 10_500.0, "offset {} was not smoothed", offset);
+
+    // Many samples converge on the new offset
+    for _ in 0..200 {
+        clock.add_sample(timestamp_now() + 11_000, None);
+    }
+    assert_offset(&clock, 11_000.0);
+}
+
+/// A snapshot of an entity at `x` along the x axis, rotated by `angle` radians around the y axis
+fn snapshot(server_time: u64, x: f32, angle: f32) -> Snapshot {
+    let rotation: Vec4 = Quat::from_rotation_y(angle).into();
+    Snapshot {
+        server_time,
+        transform: NetworkTransform {
+            translation: [x, 0.0, 0.0],
+            rotation: rotation.into(),
+            scale: [1.0, 1.0, 1.0],
+        },
+    }
+}
+
+fn snapshots() -> VecDeque<Snapshot> {
+    vec![snapshot(1000, 0.0, 0.0), snapshot(1100, 10.0, 1.0), snapshot(1200, 30.0, 1.0)].into()
+}
+
+fn assert_x(transform: &Transform, expected: f32) {
+    let x = transform.translation().x();
+    assert!((x - expected).abs() < 0.001, "x {} is not {}", x, expected);
+}
+
+#[test]
+fn samples_between_snapshots() {
+    let buffer = snapshots();
+    assert_x(&sample(&buffer, 1050.0, 0.0), 5.0);
+    assert_x(&sample(&buffer, 1100.0, 0.0), 10.0);
+    assert_x(&sample(&buffer, 1175.0, 0.0), 25.0);
+}
+
+#[test]
+fn samples_before_the_first_snapshot() {
+    assert_x(&sample(&snapshots(), 500.0, 100.0), 0.0);
+
+    // A single snapshot is used at any time
+    let buffer: VecDeque<_> = vec![snapshot(1000, 7.0, 0.0)].into();
+    assert_x(&sample(&buffer, 500.0, 100.0), 7.0);
+    assert_x(&sample(&buffer, 1500.0, 100.0), 7.0);
+}
+
+#[test]
+fn extrapolates_for_a_limited_time() {
+    // Continues along the last two snapshots while within the limit
+    let buffer = snapshots();
+    assert_x(&sample(&buffer, 1250.0, 100.0), 40.0);
+
+    // Then stops at the latest snapshot
+    assert_x(&sample(&buffer, 1350.0, 100.0), 30.0);
+    assert_x(&sample(&buffer, 1250.0, 0.0), 30.0);
+}
+
+#[test]
+fn rotations_are_not_extrapolated() {
+    let buffer: VecDeque<_> = vec![snapshot(1000, 0.0, 0.0), snapshot(1100, 10.0, 1.0)].into();
+    let transform = sample(&buffer, 1150.0, 100.0);
+
+    assert_x(&transform, 15.0);
+    assert!(transform.rotation().dot(Quat::from_rotation_y(1.0)).abs() > 0.999);
+}
@@ -0,0 +1,861 @@
+mod common;
+
+use std::{
+    fs,
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bounded_planet::{
+    land::{
+        MeshData,
+        MeshFile,
+        WorldManifest,
+        store::{WorldStore, WorldStoreConfig},
+        systems::{WorldTileDataState, handle_world_tile_data_requests},
+        world::save_tile,
+    },
+    networking::{
+        client::{connection::ReconnectConfig, replication::Replication as ClientReplication},
+        components::{Connection, PeerIdentity},
+        crypto::{ClientAuth, ClientIdentity, SelfSigned, fingerprint},
+        events::{DisconnectCode, DisconnectReason, NetworkError, ReceiveEvent, SendEvent, SendTarget},
+        groups::Groups,
+        handshake::{HandshakeRejection, PROTOCOL_VERSION},
+        id::NetworkEntityId,
+        packets::{AuthRequest, AuthResponse, Handshake, Packet, PacketKind, Ping, RpcError, Session, StreamType, TextChat, WorldInfoRequest, WorldTileData, WorldTileDataRequest},
+        queue::{OverflowPolicy, QueueConfig},
+        rate_limit::{Rate, RateLimitConfig, Violation, ViolationPolicy},
+        replication::Replicated,
+        rpc::{Request, Requests, ResponseEvent, ResponseHandle},
+        server::{
+            auth::{Auth, Authenticated, CredentialStore},
+            replication::Replication as ServerReplication,
+            session::Sessions,
+        },
+        stats::{ConnectionStats, NetworkStats},
+        systems::NetworkConnections,
+        testing::{HarnessConfig, HarnessTransport, NetworkHarness, Side},
+    },
+};
+
+use common::temp_path;
+
+/// Check that a connection opens and completes the handshake, and that both sides track it
+fn connects(transport: HarnessTransport) {
+    let mut harness = NetworkHarness::new(transport).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    for side in [Side::Server, Side::Client].iter() {
+        let connection = harness.connection(*side);
+        assert_eq!(harness.connection_entities(*side), vec![Connection { id: connection }]);
+    }
+}
+
+/// Check that a ping sent by the client is answered by the server
+fn ping_pong(transport: HarnessTransport) {
+    let mut harness = NetworkHarness::new(transport).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    let ping = Ping { timestamp: 42 };
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(Packet::Ping(ping)),
+    });
+
+    harness
+        .step_until(|side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } => side == Side::Client && matches!(**data, Packet::Pong(ref pong) if pong.timestamp == 42),
+            _ => false,
+        })
+        .expect("Pong was not received");
+}
+
+#[test]
+fn loopback_connects() {
+    connects(HarnessTransport::Loopback);
+}
+
+#[test]
+fn quic_connects() {
+    connects(HarnessTransport::Quic);
+}
+
+#[test]
+fn loopback_ping_pong() {
+    ping_pong(HarnessTransport::Loopback);
+}
+
+#[test]
+fn quic_ping_pong() {
+    ping_pong(HarnessTransport::Quic);
+}
+
+/// Create a harness whose server answers world tile requests with a single triangle. The world has 2x1 tiles at 2
+/// levels of detail, but only the files for the first level of detail exist.
+fn tile_server(transport: HarnessTransport) -> NetworkHarness {
+    static WORLDS: AtomicUsize = AtomicUsize::new(0);
+    let dir = temp_path(&format!("tiles_{}", WORLDS.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&dir).expect("Failed to create world directory");
+
+    let mesh_data = MeshData {
+        vertices: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+        indices: vec![0, 1, 2],
+        normals: vec![[0.0, 1.0, 0.0]; 3],
+        uvs: vec![[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]],
+    };
+    let manifest = WorldManifest::new(200, 100, 128, 2).expect("Invalid manifest");
+    manifest.save(&dir).expect("Failed to save manifest");
+    for x in 0..2 {
+        let tile = MeshFile { header: manifest.tile_header(x, 0, 0), mesh_data: mesh_data.clone() };
+        save_tile(&WorldManifest::tile_path(&dir, x, 0, 0), &tile).expect("Failed to save tile");
+    }
+
+    let mut harness = NetworkHarness::build(
+        HarnessConfig { transport, ..Default::default() },
+        |server| {
+            server.add_resource(WorldStore::new(&WorldStoreConfig { dir, ..Default::default() }));
+            server.init_resource::<WorldTileDataState>();
+            server.add_system(handle_world_tile_data_requests.system());
+        },
+        |_| {},
+    )
+    .expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+    harness
+}
+
+/// Send a request from the client to the server
+fn send_request<R: Request>(harness: &mut NetworkHarness, request: R, timeout: Duration) -> ResponseHandle<R> {
+    let connection = harness.connection(Side::Client);
+    let app = harness.app_mut(Side::Client);
+    let mut requests = app.resources.get_mut::<Requests>().expect("Network plugin was not added");
+    let mut sender = app.resources.get_mut::<Events<SendEvent>>().expect("Network plugin was not added");
+    requests.send(&mut sender, connection, request, timeout)
+}
+
+/// Step until the client gets the responses to some requests, returned in the same order as the requests. Requests
+/// may take real time to answer (e.g. loading a tile on a blocking task, or timing out).
+fn wait_for_responses<R>(harness: &mut NetworkHarness, handles: &[ResponseHandle<R>]) -> Vec<Result<R::Response, RpcError>>
+where
+    R: Request,
+    R::Response: Clone,
+{
+    let mut reader = EventReader::<ResponseEvent>::default();
+    let mut results = vec![None; handles.len()];
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        harness.step(|_, _| {});
+
+        let responses = harness.app(Side::Client).resources.get::<Events<ResponseEvent>>().expect("Network plugin was not added");
+        for evt in reader.iter(&responses) {
+            for (handle, result) in handles.iter().zip(results.iter_mut()) {
+                if let Some(response) = handle.get(evt) {
+                    *result = Some(response.map(<R::Response>::clone));
+                }
+            }
+        }
+
+        if results.iter().all(Option::is_some) {
+            return results.into_iter().flatten().collect();
+        }
+        harness.wait(Duration::from_millis(1));
+    }
+    panic!("Requests {:?} were never answered", handles);
+}
+
+#[test]
+fn world_tile_request() {
+    let mut harness = tile_server(HarnessTransport::Quic);
+
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(Packet::WorldTileDataRequest(WorldTileDataRequest { x: 0, y: 0, lod: 0 })),
+    });
+
+    // The tile is loaded on a blocking task
+    let mut indices = None;
+    harness
+        .step_until_timeout(Duration::from_secs(10), |side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client => match &**data {
+                Packet::WorldTileData(WorldTileData { mesh_data }) => {
+                    indices = Some(mesh_data.indices.clone());
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        })
+        .expect("World tile was not received");
+
+    assert_eq!(indices, Some(vec![0, 1, 2]));
+}
+
+#[test]
+fn world_tile_rpc() {
+    let mut harness = tile_server(HarnessTransport::Loopback);
+
+    // Several requests are in flight at once, and each is answered separately
+    let first = send_request(&mut harness, WorldTileDataRequest { x: 0, y: 0, lod: 0 }, Duration::from_secs(10));
+    let second = send_request(&mut harness, WorldTileDataRequest { x: 1, y: 0, lod: 0 }, Duration::from_secs(10));
+    assert_ne!(first.id, second.id);
+
+    for result in wait_for_responses(&mut harness, &[first, second]) {
+        let tile = result.expect("Request failed");
+        assert_eq!(tile.mesh_data.indices, vec![0, 1, 2]);
+    }
+
+    let requests = harness.app(Side::Client).resources.get::<Requests>().expect("Network plugin was not added");
+    assert_eq!(requests.pending(), 0);
+}
+
+#[test]
+fn world_info_rpc() {
+    let mut harness = tile_server(HarnessTransport::Loopback);
+
+    let handle = send_request(&mut harness, WorldInfoRequest, Duration::from_secs(10));
+    let results = wait_for_responses(&mut harness, &[handle]);
+    let info = results[0].as_ref().expect("Request failed");
+    assert_eq!((info.manifest.tiles_x, info.manifest.tiles_y), (2, 1));
+}
+
+#[test]
+fn missing_world_tile() {
+    let mut harness = tile_server(HarnessTransport::Loopback);
+
+    // Outside the world, and in the world but without a tile file
+    let outside = send_request(&mut harness, WorldTileDataRequest { x: 5, y: 5, lod: 0 }, Duration::from_secs(10));
+    let no_file = send_request(&mut harness, WorldTileDataRequest { x: 0, y: 0, lod: 1 }, Duration::from_secs(10));
+    for result in wait_for_responses(&mut harness, &[outside, no_file]) {
+        assert_eq!(result.as_ref().map(|_| ()), Err(&RpcError::NotFound));
+    }
+}
+
+#[test]
+fn unanswered_request_times_out() {
+    // Nothing on this server answers tile requests
+    let mut harness = NetworkHarness::new(HarnessTransport::Loopback).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    let handle = send_request(&mut harness, WorldTileDataRequest { x: 0, y: 0, lod: 0 }, Duration::from_millis(100));
+    let results = wait_for_responses(&mut harness, &[handle]);
+    assert_eq!(results[0].as_ref().map(|_| ()), Err(&RpcError::TimedOut));
+}
+
+#[test]
+fn broadcast_targets() {
+    let mut harness = NetworkHarness::new(HarnessTransport::Loopback).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+    let connection = harness.connection(Side::Server);
+
+    // Put the only client in a group
+    let server = harness.app_mut(Side::Server);
+    let entity = server.world
+        .query::<(Entity, &Connection)>()
+        .iter()
+        .map(|(entity, _)| entity)
+        .next()
+        .expect("Connection entity was not spawned");
+    server.world.insert_one(entity, Groups::new(vec!["red"])).expect("Failed to add groups");
+
+    let targets = vec![
+        (SendTarget::AllExcept(connection), "except"),
+        (SendTarget::Group("blue".to_owned()), "blue"),
+        (SendTarget::Group("red".to_owned()), "red"),
+        (SendTarget::All, "all"),
+    ];
+    for (target, message) in targets {
+        harness.send(Side::Server, SendEvent::Broadcast {
+            target,
+            data: Arc::new(Packet::TextChat(TextChat { index: 0, message: message.to_owned() })),
+        });
+    }
+
+    let mut received = Vec::new();
+    harness
+        .step_until(|side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client => match &**data {
+                Packet::TextChat(TextChat { message, .. }) => {
+                    received.push(message.clone());
+                    message == "all"
+                }
+                _ => false,
+            },
+            _ => false,
+        })
+        .expect("Broadcast was not received");
+    assert_eq!(received, vec!["red", "all"]);
+}
+
+#[test]
+fn server_disconnect() {
+    let mut harness = NetworkHarness::new(HarnessTransport::Loopback).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    let reason = DisconnectReason::new(DisconnectCode::Other(7), "Test over");
+    harness.send(Side::Server, SendEvent::Disconnect {
+        connection: harness.connection(Side::Server),
+        reason: reason.clone(),
+    });
+
+    let (mut server, mut client) = (None, None);
+    harness
+        .step_until(|side, evt| {
+            if let ReceiveEvent::Disconnected { reason, by_peer, .. } = evt {
+                match side {
+                    Side::Server => server = Some((reason.clone(), *by_peer)),
+                    Side::Client => client = Some((reason.clone(), *by_peer)),
+                }
+            }
+            server.is_some() && client.is_some()
+        })
+        .expect("Connection was not closed");
+    assert_eq!(server, Some((reason.clone(), false)));
+    assert_eq!(client, Some((reason, true)));
+
+    // Both sides forget the connection once it has closed
+    for side in [Side::Server, Side::Client].iter() {
+        assert!(harness.connections(*side).is_empty());
+        assert!(harness.connection_entities(*side).is_empty());
+    }
+}
+
+#[test]
+fn disconnect_is_not_evicted() {
+    let config = HarnessConfig {
+        queue_config: QueueConfig {
+            connection_capacity: 4,
+            policy: OverflowPolicy::DropOldest,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut harness = NetworkHarness::build(config, |_| {}, |_| {}).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    // Keep sending after the disconnect, in the same frame, until the queue would have overflowed several times
+    let connection = harness.connection(Side::Server);
+    let reason = DisconnectReason::new(DisconnectCode::Other(7), "Test over");
+    harness.send(Side::Server, SendEvent::Disconnect { connection, reason: reason.clone() });
+    for index in 0..16 {
+        harness.send(Side::Server, SendEvent::SendPacket {
+            connection,
+            data: Arc::new(Packet::TextChat(TextChat { index, message: "too late".to_owned() })),
+        });
+    }
+
+    let (mut closed, mut chats) = (None, 0);
+    harness
+        .step_until(|side, evt| {
+            match evt {
+                ReceiveEvent::Disconnected { reason, .. } if side == Side::Client => closed = Some(reason.clone()),
+                ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client && matches!(**data, Packet::TextChat(_)) => chats += 1,
+                _ => {}
+            }
+            closed.is_some()
+        })
+        .expect("Connection was not closed");
+    assert_eq!(closed, Some(reason));
+    assert_eq!(chats, 0);
+}
+
+#[test]
+fn protocol_version_mismatch() {
+    let mut harness = NetworkHarness::new(HarnessTransport::Loopback).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    // Handshake again, as a client built with a newer version of the protocol
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(Packet::Handshake(Handshake {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+        })),
+    });
+
+    let (mut rejection, mut closed) = (None, None);
+    harness
+        .step_until(|side, evt| {
+            match evt {
+                ReceiveEvent::HandshakeRejected { reason, .. } if side == Side::Server => rejection = Some(reason.clone()),
+                ReceiveEvent::Disconnected { reason, by_peer, .. } if side == Side::Client => closed = Some((reason.code, *by_peer)),
+                _ => {}
+            }
+            rejection.is_some() && closed.is_some()
+        })
+        .expect("Handshake was not rejected");
+
+    let HandshakeRejection::VersionMismatch { local, remote } = rejection.expect("No rejection");
+    assert_eq!((local, remote), (PROTOCOL_VERSION, PROTOCOL_VERSION + 1));
+
+    // The server closes the connection
+    assert_eq!(closed, Some((DisconnectCode::ProtocolMismatch, true)));
+    assert!(harness.connections(Side::Client).is_empty());
+}
+
+/// Get the entities replicated to the client, with their translations
+fn replicas(harness: &NetworkHarness) -> Vec<(NetworkEntityId, [f32; 3])> {
+    let mut replicas = harness.client.world
+        .query::<(&NetworkEntityId, &Transform)>()
+        .iter()
+        .map(|(id, transform)| (*id, transform.translation().into()))
+        .collect::<Vec<_>>();
+    replicas.sort_by_key(|(id, _)| *id);
+    replicas
+}
+
+/// Step until the entities replicated to the client match `expected`. Replicas take a few frames to get a `Transform`.
+fn wait_for_replicas(harness: &mut NetworkHarness, expected: &[(NetworkEntityId, [f32; 3])]) {
+    for _ in 0..100 {
+        harness.step(|_, _| {});
+        if replicas(harness) == expected {
+            return;
+        }
+    }
+    panic!("Client has replicas {:?}, expected {:?}", replicas(harness), expected);
+}
+
+#[test]
+fn entities_are_replicated() {
+    let mut harness = NetworkHarness::build(
+        HarnessConfig::default(),
+        |server| {
+            server.add_plugin(ServerReplication);
+        },
+        |client| {
+            client.add_plugin(ClientReplication);
+        },
+    )
+    .expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    let a = harness.server.world.spawn((Replicated, Transform::from_translation(Vec3::new(1.0, 2.0, 3.0))));
+    let b = harness.server.world.spawn((Replicated, Transform::from_translation(Vec3::new(4.0, 5.0, 6.0))));
+    harness.step(|_, _| {});
+    let id = |harness: &NetworkHarness, entity| *harness.server.world.get::<NetworkEntityId>(entity).expect("Entity has no network ID");
+    let (a_id, b_id) = (id(&harness, a), id(&harness, b));
+    assert_ne!(a_id, b_id);
+
+    // New entities are spawned on the client
+    wait_for_replicas(&mut harness, &[(a_id, [1.0, 2.0, 3.0]), (b_id, [4.0, 5.0, 6.0])]);
+
+    // Changes are sent to the client
+    *harness.server.world.get_mut::<Transform>(a).expect("Entity has no transform") = Transform::from_translation(Vec3::new(7.0, 8.0, 9.0));
+    wait_for_replicas(&mut harness, &[(a_id, [7.0, 8.0, 9.0]), (b_id, [4.0, 5.0, 6.0])]);
+
+    // Despawned entities are despawned on the client
+    harness.server.world.despawn(b).expect("Entity was already despawned");
+    wait_for_replicas(&mut harness, &[(a_id, [7.0, 8.0, 9.0])]);
+}
+
+#[test]
+fn rate_limited_packets_are_dropped() {
+    let mut rate_limit = RateLimitConfig {
+        policy: ViolationPolicy::Drop,
+        ..Default::default()
+    };
+    rate_limit.packets.insert(PacketKind::TextChat, Rate { burst: 2, per_second: 0.0 });
+
+    let config = HarnessConfig { rate_limit, ..Default::default() };
+    let mut harness = NetworkHarness::build(config, |_| {}, |_| {}).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    for index in 0..3 {
+        harness.send(Side::Client, SendEvent::SendPacket {
+            connection: harness.connection(Side::Client),
+            data: Arc::new(Packet::TextChat(TextChat { index, message: "spam".to_owned() })),
+        });
+    }
+
+    let (mut received, mut exceeded) = (0, false);
+    harness
+        .step_until(|side, evt| {
+            match evt {
+                ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Server && matches!(**data, Packet::TextChat(_)) => received += 1,
+                ReceiveEvent::LimitExceeded { violation: Violation::Packet(PacketKind::TextChat), .. } => exceeded = true,
+                _ => {}
+            }
+            exceeded && received == 2
+        })
+        .expect("Limit was not enforced");
+
+    // The connection stays open when packets are dropped
+    assert_eq!(harness.connections(Side::Server).len(), 1);
+}
+
+/// Get the traffic stats of the single connection of an app
+fn connection_stats(harness: &NetworkHarness, side: Side) -> ConnectionStats {
+    let stats = harness.app(side)
+        .world
+        .query::<&ConnectionStats>()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(stats.len(), 1);
+    stats[0].clone()
+}
+
+#[test]
+fn traffic_is_counted() {
+    let mut harness = NetworkHarness::new(HarnessTransport::Loopback).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    for index in 0..3 {
+        harness.send(Side::Client, SendEvent::SendPacket {
+            connection: harness.connection(Side::Client),
+            data: Arc::new(Packet::TextChat(TextChat { index, message: "hello".to_owned() })),
+        });
+    }
+
+    let mut received = 0;
+    harness
+        .step_until(|side, evt| {
+            if let ReceiveEvent::ReceivedPacket { data, .. } = evt {
+                if side == Side::Server && matches!(**data, Packet::TextChat(_)) {
+                    received += 1;
+                }
+            }
+            received == 3
+        })
+        .expect("Chat did not arrive");
+
+    // Stats are copied into the ECS at the start of every frame
+    harness.step(|_, _| {});
+
+    let sent = connection_stats(&harness, Side::Client).streams[&StreamType::TextChat].sent;
+    let arrived = connection_stats(&harness, Side::Server).streams[&StreamType::TextChat].received;
+    assert_eq!(sent.packets, 3);
+    assert_eq!(sent, arrived);
+    assert!(sent.bytes > 0);
+
+    // The totals include every stream of every connection
+    let totals = harness.server.resources.get::<NetworkStats>().expect("Network plugin was not added");
+    assert_eq!(totals.connections, 1);
+    assert!(totals.total.total().received.packets > 3);
+    assert_eq!(totals.total.errors, 0);
+}
+
+#[test]
+fn allowed_client_is_identified() {
+    let generated = SelfSigned::generate(vec!["player".to_owned()]).expect("Failed to generate certificate");
+    let mut client_auth = ClientAuth::default();
+    client_auth.allowed.insert(fingerprint(&generated.cert));
+
+    let config = HarnessConfig {
+        transport: HarnessTransport::Quic,
+        client_auth: Some(client_auth),
+        client_identity: Some(ClientIdentity::from(&generated)),
+        ..Default::default()
+    };
+    let mut harness = NetworkHarness::build(config, |_| {}, |_| {}).expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    // The identity is attached to the entity of the client's connection
+    let identities = harness.server.world
+        .query::<(&Connection, &PeerIdentity)>()
+        .iter()
+        .map(|(connection, identity)| (*connection, identity.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].0, Connection { id: harness.connection(Side::Server) });
+    assert_eq!(identities[0].1.fingerprint, fingerprint(&generated.cert));
+    assert_eq!(identities[0].1.certificate, generated.cert);
+}
+
+#[test]
+fn unknown_client_is_refused() {
+    let allowed = SelfSigned::generate(vec!["player".to_owned()]).expect("Failed to generate certificate");
+    let unknown = SelfSigned::generate(vec!["intruder".to_owned()]).expect("Failed to generate certificate");
+    let mut client_auth = ClientAuth::default();
+    client_auth.allowed.insert(fingerprint(&allowed.cert));
+
+    let config = HarnessConfig {
+        transport: HarnessTransport::Quic,
+        client_auth: Some(client_auth),
+        client_identity: Some(ClientIdentity::from(&unknown)),
+        ..Default::default()
+    };
+    let mut harness = NetworkHarness::build(config, |_| {}, |_| {}).expect("Failed to create harness");
+
+    // The client may finish its side of the TLS handshake before the server refuses its certificate
+    harness
+        .step_until(|side, evt| {
+            assert!(!matches!(evt, ReceiveEvent::HandshakeCompleted { .. }), "Unknown client completed the handshake");
+            side == Side::Client && matches!(evt, ReceiveEvent::Disconnected { .. } | ReceiveEvent::NetworkError(NetworkError::ConnectionError(_)))
+        })
+        .expect("Connection was not refused");
+
+    assert!(harness.connections(Side::Server).is_empty());
+}
+
+/// Accepts a single user, `player` with the password `secret`
+struct TestCredentials;
+
+impl CredentialStore for TestCredentials {
+    fn verify(&self, username: &str, password: &str) -> AuthResponse {
+        match (username, password) {
+            ("player", "secret") => AuthResponse::Ok,
+            ("player", _) => AuthResponse::IncorrectPassword,
+            _ => AuthResponse::IncorrectUsername,
+        }
+    }
+}
+
+/// Create a harness whose server requires clients to authenticate
+fn auth_server(max_attempts: u32, timeout: Duration) -> NetworkHarness {
+    NetworkHarness::build(
+        HarnessConfig::default(),
+        |server| {
+            server.add_plugin(Auth {
+                store: Arc::new(TestCredentials),
+                max_attempts,
+                timeout,
+            });
+        },
+        |_| {},
+    )
+    .expect("Failed to create harness")
+}
+
+/// Send credentials from the client to the server
+fn send_auth(harness: &mut NetworkHarness, username: &str, password: &str) {
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(Packet::AuthRequest(AuthRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })),
+    });
+}
+
+/// Step until the client receives an `AuthResponse`. Credentials are verified on a blocking task.
+fn wait_for_auth_response(harness: &mut NetworkHarness) -> AuthResponse {
+    let mut response = None;
+    harness
+        .step_until_timeout(Duration::from_secs(10), |side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client => match **data {
+                Packet::AuthResponse(r) => {
+                    response = Some(r);
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        })
+        .expect("No authentication response was received");
+    response.expect("Condition was met without a response")
+}
+
+/// Step until the client receives an `AuthResponse` and its connection is closed
+fn wait_for_rejection(harness: &mut NetworkHarness) -> (AuthResponse, DisconnectReason) {
+    let (mut response, mut closed) = (None, None);
+    harness
+        .step_until_timeout(Duration::from_secs(10), |side, evt| {
+            match evt {
+                ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Client => {
+                    if let Packet::AuthResponse(r) = **data {
+                        response = Some(r);
+                    }
+                }
+                ReceiveEvent::Disconnected { reason, .. } if side == Side::Client => closed = Some(reason.clone()),
+                _ => {}
+            }
+            response.is_some() && closed.is_some()
+        })
+        .expect("Connection was not rejected");
+
+    // The response is sent before the connection is closed
+    (response.expect("No response"), closed.expect("No disconnect"))
+}
+
+#[test]
+fn wrong_credentials_are_rejected() {
+    let mut harness = auth_server(5, Duration::from_secs(60));
+    harness.connect().expect("Handshake did not complete");
+
+    send_auth(&mut harness, "player", "guess");
+    assert_eq!(wait_for_auth_response(&mut harness), AuthResponse::IncorrectPassword);
+    send_auth(&mut harness, "someone", "secret");
+    assert_eq!(wait_for_auth_response(&mut harness), AuthResponse::IncorrectUsername);
+
+    // Failed attempts do not close the connection, and the client may try again
+    send_auth(&mut harness, "player", "secret");
+    assert_eq!(wait_for_auth_response(&mut harness), AuthResponse::Ok);
+
+    assert_eq!(authenticated_users(&harness), vec!["player".to_owned()]);
+}
+
+#[test]
+fn auth_rpc() {
+    let mut harness = auth_server(5, Duration::from_secs(60));
+    harness.connect().expect("Handshake did not complete");
+
+    // Credentials may also be sent as a request, which is answered with a response
+    let request = AuthRequest { username: "player".to_owned(), password: "secret".to_owned() };
+    let handle = send_request(&mut harness, request, Duration::from_secs(10));
+    assert_eq!(wait_for_responses(&mut harness, &[handle]), vec![Ok(AuthResponse::Ok)]);
+}
+
+#[test]
+fn too_many_failed_attempts() {
+    let mut harness = auth_server(2, Duration::from_secs(60));
+    harness.connect().expect("Handshake did not complete");
+
+    send_auth(&mut harness, "player", "guess");
+    assert_eq!(wait_for_auth_response(&mut harness), AuthResponse::IncorrectPassword);
+
+    send_auth(&mut harness, "player", "guess again");
+    let (response, reason) = wait_for_rejection(&mut harness);
+    assert_eq!(response, AuthResponse::TooManyAttempts);
+    assert_eq!(reason.code, DisconnectCode::AuthenticationFailed);
+}
+
+#[test]
+fn auth_times_out() {
+    let mut harness = auth_server(5, Duration::from_millis(100));
+
+    // The client never sends credentials
+    let (response, reason) = wait_for_rejection(&mut harness);
+    assert_eq!(response, AuthResponse::TimedOut);
+    assert_eq!(reason.code, DisconnectCode::AuthenticationFailed);
+}
+
+#[test]
+fn packets_before_auth_are_dropped() {
+    let mut harness = auth_server(5, Duration::from_secs(60));
+    harness.connect().expect("Handshake did not complete");
+
+    let chat = |index| Packet::TextChat(TextChat { index, message: "hello".to_owned() });
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(chat(0)),
+    });
+    send_auth(&mut harness, "player", "secret");
+
+    let mut chats = Vec::new();
+    let mut authenticated = false;
+    harness
+        .step_until_timeout(Duration::from_secs(10), |side, evt| {
+            if let ReceiveEvent::ReceivedPacket { data, .. } = evt {
+                match **data {
+                    Packet::TextChat(TextChat { index, .. }) if side == Side::Server => chats.push(index),
+                    Packet::AuthResponse(AuthResponse::Ok) if side == Side::Client => authenticated = true,
+                    _ => {}
+                }
+            }
+            authenticated
+        })
+        .expect("Client did not authenticate");
+
+    // Packets sent once the connection has authenticated are published
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(chat(1)),
+    });
+    harness
+        .step_until(|side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { data, .. } if side == Side::Server => match **data {
+                Packet::TextChat(TextChat { index, .. }) => {
+                    chats.push(index);
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        })
+        .expect("Chat was not received");
+
+    assert_eq!(chats, vec![1]);
+}
+
+/// Get the usernames of the authenticated connections on the server
+fn authenticated_users(harness: &NetworkHarness) -> Vec<String> {
+    harness.server.world
+        .query::<&Authenticated>()
+        .iter()
+        .map(|authenticated| authenticated.username.clone())
+        .collect()
+}
+
+#[test]
+fn session_is_resumed() {
+    let config = HarnessConfig {
+        // Reconnect straight away, once
+        reconnect: ReconnectConfig {
+            initial_delay: Duration::from_secs(0),
+            max_attempts: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut harness = NetworkHarness::build(
+        config,
+        |server| {
+            server.add_plugin(Auth {
+                store: Arc::new(TestCredentials),
+                max_attempts: 5,
+                timeout: Duration::from_secs(60),
+            });
+            server.add_plugin(Sessions { grace_period: Duration::from_secs(60) });
+        },
+        |_| {},
+    )
+    .expect("Failed to create harness");
+    harness.connect().expect("Handshake did not complete");
+
+    // The client requests a session as soon as the handshake completes, it becomes resumable once authenticated
+    send_auth(&mut harness, "player", "secret");
+    assert_eq!(wait_for_auth_response(&mut harness), AuthResponse::Ok);
+    harness.step(|_, _| {});
+
+    let old = harness.connection(Side::Server);
+    let entity = harness.server.resources.get::<NetworkConnections>().expect("Network plugin was not added").connections[&old];
+
+    // Lose the connection, the client reconnects and resumes its session
+    harness.send(Side::Server, SendEvent::Disconnect {
+        connection: old,
+        reason: DisconnectReason::new(DisconnectCode::Other(7), "Connection dropped"),
+    });
+
+    let (mut resumed, mut accepted, mut auth_responses) = (None, false, 0);
+    harness
+        .step_until_timeout(Duration::from_secs(10), |side, evt| {
+            match (side, evt) {
+                (Side::Server, ReceiveEvent::SessionResumed { connection, replaced }) => resumed = Some((*connection, *replaced)),
+                (Side::Client, ReceiveEvent::ReceivedPacket { data, .. }) => match **data {
+                    Packet::Session(Session::Accepted { resumed: true, .. }) => accepted = true,
+                    Packet::AuthResponse(_) => auth_responses += 1,
+                    _ => {}
+                },
+                _ => {}
+            }
+            resumed.is_some() && accepted
+        })
+        .expect("Session was not resumed");
+
+    // The new connection replaces the old one, taking over its ID and entity
+    let (connection, replaced) = resumed.expect("No resumed session");
+    assert_eq!(connection, old);
+    assert_ne!(replaced, old);
+    assert_eq!(harness.connection(Side::Server), old);
+    assert_eq!(harness.connection_entities(Side::Server), vec![Connection { id: old }]);
+    assert_eq!(harness.server.resources.get::<NetworkConnections>().expect("Network plugin was not added").connections[&old], entity);
+
+    // The resumed connection is still authenticated, without sending its credentials again
+    assert_eq!(auth_responses, 0);
+    assert_eq!(authenticated_users(&harness), vec!["player".to_owned()]);
+
+    harness.send(Side::Client, SendEvent::SendPacket {
+        connection: harness.connection(Side::Client),
+        data: Arc::new(Packet::TextChat(TextChat { index: 0, message: "hello again".to_owned() })),
+    });
+    harness
+        .step_until(|side, evt| match evt {
+            ReceiveEvent::ReceivedPacket { connection, data, .. } if side == Side::Server => {
+                *connection == old && matches!(**data, Packet::TextChat(_))
+            }
+            _ => false,
+        })
+        .expect("Chat from the resumed connection was not received");
+}
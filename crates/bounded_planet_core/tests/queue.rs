@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use bounded_planet::networking::{
+    events::{DisconnectCode, DisconnectReason, SendEvent},
+    id::ConnectionId,
+    packets::{Packet, TextChat},
+    queue::{OverflowPolicy, QueueConfig, SendQueue},
+};
+
+fn chat(connection: ConnectionId, index: u64) -> SendEvent {
+    SendEvent::SendPacket {
+        connection,
+        data: Arc::new(Packet::TextChat(TextChat { index, message: "hello".to_owned() })),
+    }
+}
+
+#[test]
+fn packets_after_disconnect_are_discarded() {
+    let queue = SendQueue::new(QueueConfig {
+        policy: OverflowPolicy::DropOldest,
+        ..Default::default()
+    });
+    let connection = ConnectionId::new();
+
+    queue.send(chat(connection, 0)).expect("Queue is not full");
+    assert!(!queue.is_disconnecting());
+    queue.send(SendEvent::Disconnect {
+        connection,
+        reason: DisconnectReason::new(DisconnectCode::Normal, "Goodbye"),
+    })
+    .expect("Disconnect was refused");
+    assert!(queue.is_disconnecting());
+
+    // Nothing is queued after the disconnect, so it can never be pushed out of a full queue
+    for index in 1..10 {
+        queue.send(chat(connection, index)).expect("Packet after disconnect was reported as dropped");
+    }
+    assert_eq!(queue.depth().events, 2);
+}
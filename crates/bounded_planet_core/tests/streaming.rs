@@ -0,0 +1,59 @@
+use bevy::prelude::*;
+use bounded_planet::land::{
+    WorldManifest,
+    streaming::{TerrainStreamingConfig, TerrainTile, visible_tiles},
+};
+
+fn config() -> TerrainStreamingConfig {
+    TerrainStreamingConfig {
+        view_distance_scale: 2.0,
+        min_view_distance: 100.0,
+        max_view_distance: 1000.0,
+        lod_distance: 100.0,
+        ..Default::default()
+    }
+}
+
+fn manifest() -> WorldManifest {
+    // 8x8 tiles of 100 quads
+    WorldManifest::new(801, 801, 100, 4).expect("Invalid manifest")
+}
+
+#[test]
+fn tiles_around_the_focus() {
+    // Looking straight down from low down, only the nearby tiles are shown, at full detail
+    let camera = Vec3::new(450.0, 10.0, 450.0);
+    let tiles = visible_tiles(&manifest(), &config(), camera, Vec3::new(450.0, 0.0, 450.0));
+
+    assert_eq!(tiles[0], TerrainTile { x: 4, y: 4, lod: 0 });
+    assert_eq!(tiles.len(), 9);
+    assert!(tiles.iter().all(|tile| (3..=5).contains(&tile.x) && (3..=5).contains(&tile.y)));
+    assert!(tiles.iter().all(|tile| tile.lod == 0));
+}
+
+#[test]
+fn tiles_are_clipped_to_the_world() {
+    let camera = Vec3::new(-50.0, 10.0, -50.0);
+    let tiles = visible_tiles(&manifest(), &config(), camera, Vec3::new(-50.0, 0.0, -50.0));
+
+    assert_eq!(tiles, vec![TerrainTile { x: 0, y: 0, lod: 0 }]);
+
+    // Nothing is shown when looking far outside the world
+    let camera = Vec3::new(-5000.0, 10.0, -5000.0);
+    assert!(visible_tiles(&manifest(), &config(), camera, Vec3::new(-5000.0, 0.0, -5000.0)).is_empty());
+}
+
+#[test]
+fn zooming_out_shows_more_tiles_in_less_detail() {
+    let focus = Vec3::new(450.0, 0.0, 450.0);
+    let near = visible_tiles(&manifest(), &config(), Vec3::new(450.0, 10.0, 450.0), focus);
+    let far = visible_tiles(&manifest(), &config(), Vec3::new(450.0, 300.0, 450.0), focus);
+
+    assert!(far.len() > near.len());
+    assert!(far.iter().all(|tile| tile.lod >= 2));
+
+    // Levels of detail are limited to the ones the world was generated at
+    let very_far = visible_tiles(&manifest(), &config(), Vec3::new(450.0, 5000.0, 450.0), focus);
+    assert_eq!(very_far.len(), 64);
+    assert!(very_far.iter().all(|tile| tile.lod == 3));
+}
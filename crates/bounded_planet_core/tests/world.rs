@@ -0,0 +1,253 @@
+mod common;
+
+use std::{collections::BTreeMap, fs, io::Cursor, sync::Arc, time::Duration};
+
+use bounded_planet::land::{
+    MeshData,
+    bpmesh::{MESH_FILE_VERSION, MeshFile, MeshFileError, MeshFileHeader},
+    heightmap::{HeightmapData, SamplingError},
+    mesh::{SKIRT_DEPTH, texture_to_lod_mesh_data},
+    store::{TileKey, TileStatus, WorldStore, WorldStoreConfig},
+    world::{WorldError, WorldManifest, generate_world, load_tile, load_world, save_tile, tile_mesh_data},
+};
+
+use common::temp_path;
+
+/// A heightmap computed from its coordinates, with the one sample border `HeightmapData` allows
+struct TestHeightmap {
+    size: (u16, u16),
+}
+
+impl HeightmapData for TestHeightmap {
+    fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    fn sample(&self, x: i32, y: i32) -> Result<f32, SamplingError> {
+        if x < -1 || y < -1 || x > i32::from(self.size.0) || y > i32::from(self.size.1) {
+            return Err(SamplingError::ReadOutOfBounds());
+        }
+        Ok(((x * 7 + y * 13) % 256) as f32)
+    }
+}
+
+#[test]
+fn tiles_cover_the_world() {
+    // Larger than a single mesh could index
+    let heightmap = TestHeightmap { size: (300, 260) };
+    let manifest = WorldManifest::new(300, 260, 128, 1).expect("Invalid manifest");
+    assert_eq!((manifest.tiles_x, manifest.tiles_y), (3, 3));
+
+    assert_eq!(manifest.tile_bounds(0, 0).unwrap(), ((0, 0), (129, 129)));
+    assert_eq!(manifest.tile_bounds(2, 2).unwrap(), ((256, 256), (44, 4)));
+    assert!(matches!(manifest.tile_bounds(3, 0), Err(WorldError::NoSuchTile { x: 3, y: 0, .. })));
+    assert!(matches!(tile_mesh_data(&heightmap, &manifest, 0, 0, 1), Err(WorldError::NoSuchTile { lod: 1, .. })));
+
+    // Neighbouring tiles share the vertices along their common border
+    let left = tile_mesh_data(&heightmap, &manifest, 0, 0, 0).expect("Failed to generate tile");
+    let right = tile_mesh_data(&heightmap, &manifest, 1, 0, 0).expect("Failed to generate tile");
+    let border = |mesh: &MeshData, x: f32| {
+        let mut vertices: Vec<_> = mesh.vertices.iter().zip(&mesh.normals).filter(|(v, _)| v[0] == x && v[1] >= 0.0).map(|(v, n)| (*v, *n)).collect();
+        vertices.sort_by(|a, b| a.0[2].partial_cmp(&b.0[2]).unwrap());
+        vertices
+    };
+    let shared = border(&left, 128.0);
+    assert_eq!(shared.len(), 129);
+    assert_eq!(shared, border(&right, 128.0));
+}
+
+#[test]
+fn invalid_manifest() {
+    assert!(matches!(WorldManifest::new(100, 100, 0, 1), Err(WorldError::InvalidTileSize(0))));
+    assert!(matches!(WorldManifest::new(100, 100, 255, 1), Err(WorldError::InvalidTileSize(255))));
+    assert!(matches!(WorldManifest::new(100, 100, 64, 0), Err(WorldError::InvalidLodCount(0))));
+    assert!(matches!(WorldManifest::new(1, 100, 64, 1), Err(WorldError::HeightmapTooSmall { .. })));
+
+    // Manifests which were not made by `new` (e.g. received from a server) are checked in the same way
+    let manifest = WorldManifest::new(300, 260, 128, 2).expect("Invalid manifest");
+    assert!(manifest.validate().is_ok());
+    assert!(matches!(WorldManifest { width: 0, ..manifest }.validate(), Err(WorldError::HeightmapTooSmall { .. })));
+    assert!(matches!(WorldManifest { lods: 0, ..manifest }.validate(), Err(WorldError::InvalidLodCount(0))));
+    assert!(matches!(WorldManifest { tiles_x: 1000, ..manifest }.validate(), Err(WorldError::InconsistentManifest { .. })));
+}
+
+#[test]
+fn generate_and_load_world() {
+    let dir = temp_path("world");
+    let heightmap = TestHeightmap { size: (70, 40) };
+
+    let manifest = generate_world(&heightmap, &dir, 32, 2, &BTreeMap::new()).expect("Failed to generate world");
+    let (loaded, tiles) = load_world(&dir).expect("Failed to load world");
+    assert_eq!(loaded, manifest);
+    assert_eq!(tiles.len(), 3 * 2 * 2);
+
+    let expected = tile_mesh_data(&heightmap, &manifest, 2, 1, 1).expect("Failed to generate tile");
+    assert_eq!(tiles[&(2, 1, 1)].vertices, expected.vertices);
+    assert_eq!(tiles[&(2, 1, 1)].indices, expected.indices);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn lod_meshes() {
+    let heightmap = TestHeightmap { size: (10, 7) };
+
+    // Every other sample is kept, along with the last one so the edges of neighbouring tiles still meet
+    let lod = texture_to_lod_mesh_data(&heightmap, 1);
+    let surface: Vec<_> = lod.vertices.iter().filter(|v| v[1] >= 0.0).collect();
+    let skirts: Vec<_> = lod.vertices.iter().filter(|v| v[1] < 0.0).collect();
+    assert_eq!(surface.len(), 6 * 4);
+    assert!(surface.iter().any(|v| v[0] == 9.0 && v[2] == 6.0));
+    assert!(!surface.iter().any(|v| v[0] == 1.0));
+
+    // A skirt hangs below every edge vertex
+    assert_eq!(skirts.len(), 2 * (6 + 4));
+    for skirt in skirts {
+        assert!(surface.iter().any(|v| v[0] == skirt[0] && v[2] == skirt[2] && v[1] - SKIRT_DEPTH == skirt[1]));
+    }
+
+    assert_eq!(lod.normals.len(), lod.vertices.len());
+    assert_eq!(lod.uvs.len(), lod.vertices.len());
+    assert!(lod.indices.iter().all(|&idx| (idx as usize) < lod.vertices.len()));
+
+    // The edges of a decimated tile are a subset of the edges at full detail
+    let full = texture_to_lod_mesh_data(&heightmap, 0);
+    for v in surface {
+        assert!(full.vertices.contains(v));
+    }
+}
+
+/// Wait for tiles being loaded by a store
+fn poll_until_loaded(store: &mut WorldStore, count: usize) -> Vec<(TileKey, Result<Arc<MeshData>, WorldError>)> {
+    let mut loaded = Vec::new();
+    for _ in 0..500 {
+        loaded.extend(store.poll());
+        if loaded.len() >= count {
+            return loaded;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Tiles did not load");
+}
+
+#[test]
+fn world_store() {
+    let dir = temp_path("store");
+    let heightmap = TestHeightmap { size: (70, 40) };
+    generate_world(&heightmap, &dir, 32, 1, &BTreeMap::new()).expect("Failed to generate world");
+
+    let runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .expect("Failed to create runtime");
+
+    // Tiles are loaded in the background, then shared rather than copied
+    let mut store = runtime.enter(|| WorldStore::new(&WorldStoreConfig { dir: dir.clone(), memory_budget: usize::MAX }));
+    assert!(matches!(store.get((0, 0, 0)), TileStatus::Loading));
+    assert!(matches!(store.get((0, 0, 0)), TileStatus::Loading));
+    assert!(matches!(store.get((3, 0, 0)), TileStatus::Missing));
+    assert!(matches!(store.get((0, 0, 1)), TileStatus::Missing));
+
+    let loaded = poll_until_loaded(&mut store, 1);
+    assert_eq!(loaded.len(), 1);
+    let first = loaded[0].1.as_ref().expect("Failed to load tile").clone();
+    match store.get((0, 0, 0)) {
+        TileStatus::Ready(tile) => assert!(Arc::ptr_eq(&tile, &first)),
+        status => panic!("Tile was not kept in memory: {:?}", status),
+    }
+    assert_eq!(store.cached(), 1);
+    assert!(store.memory_used() > 0);
+
+    // With no memory budget, tiles are handed out but not kept
+    let mut store = runtime.enter(|| WorldStore::new(&WorldStoreConfig { dir: dir.clone(), memory_budget: 0 }));
+    store.get((1, 1, 0));
+    let loaded = poll_until_loaded(&mut store, 1);
+    assert!(loaded[0].1.is_ok());
+    assert_eq!(store.cached(), 0);
+    assert_eq!(store.memory_used(), 0);
+    assert!(matches!(store.get((1, 1, 0)), TileStatus::Loading));
+
+    // A world which does not exist has no tiles
+    let mut store = runtime.enter(|| WorldStore::new(&WorldStoreConfig { dir: temp_path("no_world"), ..Default::default() }));
+    assert!(store.manifest().is_none());
+    assert!(matches!(store.get((0, 0, 0)), TileStatus::Missing));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+fn mesh_file() -> MeshFile {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("source".to_owned(), "test.png".to_owned());
+
+    MeshFile {
+        header: MeshFileHeader {
+            x: 1,
+            y: 2,
+            lod: 3,
+            heightmap_width: 70,
+            heightmap_height: 40,
+            metadata,
+        },
+        mesh_data: texture_to_lod_mesh_data(&TestHeightmap { size: (10, 7) }, 0),
+    }
+}
+
+#[test]
+fn mesh_file_round_trip() {
+    let file = mesh_file();
+    let mut bytes = Vec::new();
+    file.write(&mut bytes).expect("Failed to write mesh file");
+    assert!(bytes.starts_with(b"BPMESH"));
+
+    let read = MeshFile::read(Cursor::new(&bytes)).expect("Failed to read mesh file");
+    assert_eq!(read.header, file.header);
+    assert_eq!(read.mesh_data.vertices, file.mesh_data.vertices);
+    assert_eq!(read.mesh_data.indices, file.mesh_data.indices);
+    assert_eq!(read.mesh_data.normals, file.mesh_data.normals);
+    assert_eq!(read.mesh_data.uvs, file.mesh_data.uvs);
+}
+
+#[test]
+fn invalid_mesh_files() {
+    let mut bytes = Vec::new();
+    mesh_file().write(&mut bytes).expect("Failed to write mesh file");
+
+    // Files from before the format was versioned are just compressed meshes
+    let read = |bytes: &[u8]| MeshFile::read(Cursor::new(bytes.to_vec()));
+    assert!(matches!(read(&[0x78, 0x9c, 1, 2, 3]), Err(MeshFileError::NotAMeshFile)));
+    assert!(matches!(read(b"BPM"), Err(MeshFileError::NotAMeshFile)));
+
+    let mut newer = bytes.clone();
+    newer[6..10].copy_from_slice(&(MESH_FILE_VERSION + 1).to_be_bytes());
+    assert!(matches!(read(&newer), Err(MeshFileError::UnsupportedVersion(v)) if v == MESH_FILE_VERSION + 1));
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    assert!(matches!(read(&corrupt), Err(MeshFileError::ChecksumMismatch { .. })));
+
+    let truncated = &bytes[..bytes.len() - 10];
+    assert!(matches!(read(truncated), Err(MeshFileError::ChecksumMismatch { .. })));
+}
+
+#[test]
+fn tiles_are_checked_against_the_world() {
+    let dir = temp_path("mismatch");
+    fs::create_dir_all(&dir).expect("Failed to create world directory");
+    let manifest = WorldManifest::new(70, 40, 32, 4).expect("Invalid manifest");
+    let path = WorldManifest::tile_path(&dir, 1, 2, 3);
+
+    // Tiles from another world, or in the wrong place, are rejected
+    save_tile(&path, &mesh_file()).expect("Failed to save tile");
+    assert!(load_tile(&path, &manifest, 1, 2, 3).is_ok());
+    assert!(matches!(load_tile(&path, &manifest, 2, 1, 3), Err(WorldError::TileMismatch { .. })));
+
+    let other = WorldManifest::new(71, 40, 32, 4).expect("Invalid manifest");
+    assert!(matches!(load_tile(&path, &other, 1, 2, 3), Err(WorldError::TileMismatch { .. })));
+
+    fs::write(&path, b"garbage").expect("Failed to write tile");
+    assert!(matches!(load_tile(&path, &manifest, 1, 2, 3), Err(WorldError::MeshFile { err: MeshFileError::NotAMeshFile, .. })));
+
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -1,2 +1,2 @@
-#!/bin/sh
-cargo run --bin client -- --cert "./certs/cert.pem" --url "quic://localhost:4433" --accept_any
\ No newline at end of file
+# Logs in as the user in dev_users.txt, see run_server.ps1
+cargo run --bin client -- --cert "./certs/cert.pem" --url "quic://localhost:4433" --accept_any --username "test" --password "test"
@@ -1 +1,9 @@
-cargo run --bin server -- --cert "./certs/cert.pem" --key "./certs/key.pem"
\ No newline at end of file
+# Generate the world the server loads by default (`--world`) from its heightmap, if it has not been generated yet.
+# Run gen_world.ps1 to generate it again after changing the heightmap.
+if (-not (Test-Path "./content/worlds/CoveWorldtest")) {
+    cargo run --bin gen_world -- --path "./content/worlds/CoveWorldtest.png"
+}
+
+# The development users file has a single user, `test` with password `test`, which run_client.ps1 logs in as.
+# Add users to it (or create your own users file) with `cargo run --bin server -- --users "./dev_users.txt" --add_user "name:password"`.
+cargo run --bin server -- --cert "./certs/cert.pem" --key "./certs/key.pem" --users "./dev_users.txt"